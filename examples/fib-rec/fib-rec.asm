@@ -1,6 +1,4 @@
-SYSFN_EXIT  = 0
-SYSFN_READ  = 1
-SYSFN_WRITE = 2
+    include "../sysfn.asm"
 
 CHAR_LF = 10
 
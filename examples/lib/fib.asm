@@ -0,0 +1,41 @@
+    include "../sysfn.asm"
+
+CHAR_LF = 10
+
+MEMORY_HI = 0x4000
+
+    mem     MEMORY_HI
+
+    extern  print_str, print_uint, scan_uint, printf
+
+    section "rodata", "r--"
+welcome_msg: d8 "Программа для вычисления числа Фибоначчи.", CHAR_LF, "Введите число: ", 0
+result_msg:  d8 "F(%d) = %d", CHAR_LF, 0
+
+    section "text", "r-x"
+entry:
+    li      %sp, MEMORY_HI
+    li      %a0, welcome_msg
+    call    print_str
+    call    scan_uint
+    mov     %s0, %a0
+    call    fib
+    mov     %a2, %a0
+    mov     %a1, %s0
+    li      %a0, result_msg
+    call    printf
+    sysfn   %zero, SYSFN_EXIT
+
+fib:
+    li      %a1, 0
+    li      %a2, 1
+    beq     %a0, %zero, .fib.exit
+.fib.loop:
+    add     %a3, %a1, %a2
+    mov     %a1, %a2
+    mov     %a2, %a3
+    addi    %a0, %a0, -1
+    bne     %a0, %zero, .fib.loop
+.fib.exit:
+    mov     %a0, %a1
+    ret
@@ -0,0 +1,15 @@
+    include "../sysfn.asm"
+
+    global  print_str
+
+    section "text", "r-x"
+print_str:
+    ld.u8   %a1, %a0, 0
+    beq     %a1, %zero, .print_str.exit
+.print_str.loop:
+    sysfn   %a1, SYSFN_WRITE
+    ld.u8   %a1, %a0, 1
+    addi    %a0, %a0, 1
+    bne     %a1, %zero, .print_str.loop
+.print_str.exit:
+    ret
@@ -0,0 +1,26 @@
+    global  print_uint
+    extern  print_str
+
+    section "text", "r-x"
+print_uint:
+    lui     %a4, 0xCCCCC
+    addi    %a4, %a4, 0xCCD
+    mov     %a2, %a0
+    addi    %a0, %sp, -1
+    st.u8   %zero, %a0, 0
+.print_uint.loop:
+    ; q = upper32(num * 0xCCCCCCCD) >> 3
+    ; r = num - q * 10
+    ; *--s = r + '0'
+    ; num = q
+    ; if num != 0 repeat
+    mulwu   %zero, %a3, %a2, %a4
+    lshri   %a3, %a3, 3
+    muli    %a1, %a3, 10
+    sub     %a1, %a2, %a1
+    addi    %a1, %a1, '0'
+    st.u8   %a1, %a0, -1
+    addi    %a0, %a0, -1
+    mov     %a2, %a3
+    bne     %a2, %zero, .print_uint.loop
+    jmp     print_str
@@ -0,0 +1,17 @@
+    include "../sysfn.asm"
+
+    global  scan_uint
+
+    section "text", "r-x"
+scan_uint:
+    li      %a0, 0
+    li      %a1, 9
+.scan_uint.loop:
+    sysfn   %a2, SYSFN_READ
+    addi    %a2, %a2, -'0'
+    bgtu    %a2, %a1, .scan_uint.exit
+    muli    %a0, %a0, 10
+    add     %a0, %a0, %a2
+    jmp     .scan_uint.loop
+.scan_uint.exit:
+    ret
@@ -1,11 +1,15 @@
-SYSFN_EXIT  = 0
-SYSFN_READ  = 1
-SYSFN_WRITE = 2
+    include "../sysfn.asm"
 
 CHAR_LF = 10
 
 MEMORY_HI = 0x4000
 
+; Stores `value` into the stack argument number `index` of a call.
+macro stack_arg index, value
+    li      %s0, value
+    st      %s0, %sp, index*4
+endm
+
     mem     MEMORY_HI
 
     seg     0x2000
@@ -36,28 +40,17 @@ entry:
     li      %a3, 2
     li      %a4, 3
     li      %a5, 4
-    li      %s0, 5
-    st      %s0, %sp, 0*4
-    li      %s0, 6
-    st      %s0, %sp, 1*4
-    li      %s0, 7
-    st      %s0, %sp, 2*4
-    li      %s0, 8
-    st      %s0, %sp, 3*4
-    li      %s0, 9
-    st      %s0, %sp, 4*4
-    li      %s0, 10
-    st      %s0, %sp, 5*4
-    li      %s0, 11
-    st      %s0, %sp, 6*4
-    li      %s0, 12
-    st      %s0, %sp, 7*4
-    li      %s0, 13
-    st      %s0, %sp, 8*4
-    li      %s0, 14
-    st      %s0, %sp, 9*4
-    li      %s0, 15
-    st      %s0, %sp, 10*4
+    stack_arg 0, 5
+    stack_arg 1, 6
+    stack_arg 2, 7
+    stack_arg 3, 8
+    stack_arg 4, 9
+    stack_arg 5, 10
+    stack_arg 6, 11
+    stack_arg 7, 12
+    stack_arg 8, 13
+    stack_arg 9, 14
+    stack_arg 10, 15
     call    printf
 
     sysfn   %zero, SYSFN_EXIT
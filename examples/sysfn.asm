@@ -0,0 +1,4 @@
+; System functions of the vm, by number.
+SYSFN_EXIT  = 0
+SYSFN_READ  = 1
+SYSFN_WRITE = 2
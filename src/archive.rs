@@ -0,0 +1,174 @@
+use crate::binfile::{Error, Result};
+use crate::object::{read_u32, records, slice, string, Object, Place};
+
+// An archive is a header followed by member and symbol index records. Names
+// and member data come after the records; their offsets are relative to the
+// start of the file.
+const FILE_HEADER_SIZE: usize = 4 * 4;
+const MEMBER_SIZE: usize = 4 * 4;
+const INDEX_ENTRY_SIZE: usize = 3 * 4;
+
+const FILE_MAGIC: [u8; 4] = [0x80, b'A', b'R', b'C'];
+
+#[derive(Clone, Default, Debug)]
+pub struct Archive {
+    pub members: Vec<Member>,
+    // Global symbols defined by the members.
+    pub index: Vec<IndexEntry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    pub object: Object,
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    pub name: String,
+    pub member: u32,
+}
+
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(&FILE_MAGIC)
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Default::default()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Archive> {
+        if data.len() < FILE_HEADER_SIZE || !is_archive(data) {
+            return Err(Error::InvalidFormat);
+        }
+        let version = read_u32(data, 1);
+        if version != 1 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let (members, end) = records(data, FILE_HEADER_SIZE, read_u32(data, 2), MEMBER_SIZE)?;
+        let (index, _) = records(data, end, read_u32(data, 3), INDEX_ENTRY_SIZE)?;
+
+        let mut archive = Archive::new();
+        for record in members.chunks_exact(MEMBER_SIZE) {
+            archive.members.push(Member {
+                name: string(data, read_u32(record, 0), read_u32(record, 1))?,
+                object: Object::from_bytes(slice(data, read_u32(record, 2), read_u32(record, 3))?)?,
+            });
+        }
+        for record in index.chunks_exact(INDEX_ENTRY_SIZE) {
+            let member = read_u32(record, 2);
+            if member as usize >= archive.members.len() {
+                return Err(Error::InvalidFormat);
+            }
+            let name = string(data, read_u32(record, 0), read_u32(record, 1))?;
+            archive.index.push(IndexEntry { name, member });
+        }
+        Ok(archive)
+    }
+
+    // Adds an object and its global symbols to the index.
+    pub fn add(&mut self, name: &str, object: Object) {
+        let member = self.members.len() as u32;
+        for symbol in &object.symbols {
+            if symbol.global && symbol.place != Place::Undefined {
+                self.index.push(IndexEntry { name: symbol.name.clone(), member });
+            }
+        }
+        self.members.push(Member { name: name.to_owned(), object });
+    }
+
+    // Returns the first member that defines `name`.
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.index.iter().find(|entry| entry.name == name).map(|entry| entry.member)
+    }
+
+    pub fn serialize(&self, buffer: &mut Vec<u8>) -> Result<()> {
+        macro_rules! try_u32 {
+            ($e:expr) => {
+                u32::try_from($e).map_err(|_| Error::FileTooLarge)
+            };
+        }
+
+        let records_size = self.members.len() * MEMBER_SIZE + self.index.len() * INDEX_ENTRY_SIZE;
+        let data_offset = FILE_HEADER_SIZE + records_size;
+        let mut records = Vec::with_capacity(records_size);
+        let mut data = Vec::new();
+
+        let mut push_data = |bytes: &[u8]| -> Result<[u32; 2]> {
+            let offset = try_u32!(data_offset + data.len())?;
+            let size = try_u32!(bytes.len())?;
+            data.extend_from_slice(bytes);
+            Ok([offset, size])
+        };
+
+        let mut push_record = |values: &[u32]| {
+            for value in values {
+                records.extend_from_slice(&u32::to_le_bytes(*value));
+            }
+        };
+
+        for member in &self.members {
+            let [name_offset, name_size] = push_data(member.name.as_bytes())?;
+            let [object_offset, object_size] = push_data(&member.object.to_bytes()?)?;
+            push_record(&[name_offset, name_size, object_offset, object_size]);
+        }
+
+        for entry in &self.index {
+            let [name_offset, name_size] = push_data(entry.name.as_bytes())?;
+            push_record(&[name_offset, name_size, entry.member]);
+        }
+
+        try_u32!(data_offset + data.len())?;
+
+        buffer.extend_from_slice(&FILE_MAGIC);
+        buffer.extend_from_slice(&u32::to_le_bytes(1));
+        buffer.extend_from_slice(&u32::to_le_bytes(try_u32!(self.members.len())?));
+        buffer.extend_from_slice(&u32::to_le_bytes(try_u32!(self.index.len())?));
+        buffer.extend_from_slice(&records);
+        buffer.extend_from_slice(&data);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.serialize(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn round_trip() {
+        let mut archive = Archive::new();
+        let object = asm::assemble_object("a.asm", "global f, g\n extern h\nf:\n call h\ng:\n").unwrap();
+        archive.add("a.o", object);
+        archive.add("b.o", asm::assemble_object("b.asm", "global h\nh:\n ret\n").unwrap());
+        assert_eq!(archive.find("g"), Some(0));
+        assert_eq!(archive.find("h"), Some(1));
+        // Undefined symbols are not indexed.
+        assert_eq!(archive.index.len(), 3);
+
+        let archive = Archive::from_bytes(&archive.to_bytes().unwrap()).unwrap();
+        let names: Vec<_> = archive.members.iter().map(|member| member.name.as_str()).collect();
+        assert_eq!(names, ["a.o", "b.o"]);
+        assert_eq!(archive.find("f"), Some(0));
+        assert_eq!(archive.find("h"), Some(1));
+        assert_eq!(archive.find("missing"), None);
+        assert_eq!(archive.members[0].object.relocs.len(), 1);
+    }
+
+    #[test]
+    fn invalid_archive() {
+        let mut data = Archive::new().to_bytes().unwrap();
+        assert!(Archive::from_bytes(&data).unwrap().members.is_empty());
+        data[4] = 2;
+        assert!(matches!(Archive::from_bytes(&data), Err(Error::UnsupportedVersion(2))));
+        assert!(matches!(Archive::from_bytes(&data[..8]), Err(Error::InvalidFormat)));
+    }
+}
@@ -0,0 +1,62 @@
+use smallvec::SmallVec;
+
+use super::id_table::{IdentTable, Symbol};
+
+// What the parser produces for a file and the files it includes.
+#[derive(Default)]
+pub struct Source {
+    pub id_table: IdentTable,
+    pub ast: Vec<Node>,
+    pub expansions: Vec<Expansion>,
+    pub files: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub args: SmallVec<[Arg; 3]>,
+    // Index into `Source::files`.
+    pub file: u32,
+    pub line: u32,
+    // Index into the expansion table for nodes produced by a macro.
+    pub expansion: Option<u32>,
+}
+
+// A macro call that nodes were expanded from.
+#[derive(Clone, Copy, Debug)]
+pub struct Expansion {
+    pub name: Symbol,
+    pub file: u32,
+    pub line: u32,
+    pub parent: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum NodeKind {
+    Label(Symbol),
+    Assign(Symbol),
+    Inst(Symbol),
+}
+
+#[derive(Clone, Debug)]
+pub enum Arg {
+    Reg(u32),
+    Str(SmallVec<[u8; 16]>),
+    Expr(SmallVec<[Expr; 2]>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Expr {
+    Int(u32),
+    Label(Symbol),
+
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Lshr,
+    Ashr,
+}
@@ -0,0 +1,691 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+
+use crate::binfile::SymbolKind;
+use crate::isa::{Instruction, Operands, Rc, Rrc, Rrr, Rrrr};
+use crate::vm::Perms;
+
+use super::compiler::{
+    check_imm_fits, eval_branch_offset, set_symbol_sizes, ErrorKind, Program, Segment, Symbol,
+};
+
+// Errors are reported at the address of the offending instruction or data.
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub addr: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+struct LabelInfo {
+    name: Option<String>,
+    addr: Option<u32>,
+    kind: SymbolKind,
+}
+
+#[derive(Clone, Copy)]
+enum FixupKind {
+    // The immediate of the instruction is an offset to the label.
+    Branch(Instruction),
+    // The immediate of the instruction is the address of the label.
+    Imm(Instruction),
+    Word,
+}
+
+struct Fixup {
+    segment: usize,
+    offset: usize,
+    label: Label,
+    kind: FixupKind,
+}
+
+// Emits machine code without going through assembler source. Methods take
+// operands in the same order as the assembler; the first error is kept and
+// returned by `finish`.
+pub struct CodeBuilder {
+    memory_size: u32,
+    entry: Option<Label>,
+    initial_sp: Option<u32>,
+    segments: Vec<Segment>,
+    labels: Vec<LabelInfo>,
+    // Labels bound at the current address; they mark code if an instruction
+    // follows.
+    pending: Vec<Label>,
+    fixups: Vec<Fixup>,
+    error: Option<Error>,
+}
+
+macro_rules! rc_methods {
+    ($($name:ident = $variant:ident;)*) => {
+        $(pub fn $name(&mut self, r1: u8, imm: i32) {
+            self.emit_imm(Instruction::$variant(Rc { r1, imm }), 20);
+        })*
+    };
+}
+
+macro_rules! rrc_methods {
+    ($($name:ident = $variant:ident;)*) => {
+        $(pub fn $name(&mut self, r1: u8, r2: u8, imm: i32) {
+            self.emit_imm(Instruction::$variant(Rrc { r1, r2, imm }), 16);
+        })*
+    };
+}
+
+macro_rules! branch_methods {
+    ($($name:ident = $variant:ident, $swap:literal;)*) => {
+        $(pub fn $name(&mut self, r1: u8, r2: u8, target: Label) {
+            let (r1, r2) = if $swap { (r2, r1) } else { (r1, r2) };
+            let inst = Instruction::$variant(Rrc { r1, r2, imm: 0 });
+            self.emit_fixup(inst, target, FixupKind::Branch(inst));
+        })*
+    };
+}
+
+macro_rules! rrr_methods {
+    ($($name:ident = $variant:ident;)*) => {
+        $(pub fn $name(&mut self, r1: u8, r2: u8, r3: u8) {
+            self.emit(Instruction::$variant(Rrr { r1, r2, r3 }));
+        })*
+    };
+}
+
+macro_rules! rrrr_methods {
+    ($($name:ident = $variant:ident;)*) => {
+        $(pub fn $name(&mut self, r1: u8, r2: u8, r3: u8, r4: u8) {
+            self.emit(Instruction::$variant(Rrrr { r1, r2, r3, r4 }));
+        })*
+    };
+}
+
+impl Default for CodeBuilder {
+    fn default() -> Self {
+        CodeBuilder::new()
+    }
+}
+
+impl CodeBuilder {
+    pub fn new() -> CodeBuilder {
+        CodeBuilder {
+            memory_size: 0,
+            entry: None,
+            initial_sp: None,
+            segments: vec![Segment::new()],
+            labels: Vec::new(),
+            pending: Vec::new(),
+            fixups: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn mem(&mut self, size: u32) {
+        self.memory_size = cmp::max(self.memory_size, size);
+    }
+
+    pub fn entry(&mut self, label: Label) {
+        self.entry = Some(label);
+    }
+
+    pub fn initial_sp(&mut self, sp: u32) {
+        self.initial_sp = Some(sp);
+    }
+
+    pub fn seg(&mut self, addr: u32) {
+        self.seg_with_perms(addr, Perms::ALL);
+    }
+
+    pub fn seg_with_perms(&mut self, addr: u32, perms: Perms) {
+        self.pending.clear();
+        self.segments.push(Segment { addr, perms, ..Segment::new() });
+    }
+
+    pub fn addr(&self) -> u32 {
+        let segment = self.segments.last().unwrap();
+        segment.addr.wrapping_add(segment.size())
+    }
+
+    // Reserves zero bytes that take no space in the file.
+    pub fn space(&mut self, size: u32) {
+        let segment = self.segments.last_mut().unwrap();
+        let end = u64::from(segment.size()) + u64::from(size);
+        if u64::from(segment.addr) + end > u64::from(u32::MAX) + 1 {
+            let addr = self.addr();
+            self.fail(ErrorKind::AddrOverflow, addr);
+            return;
+        }
+        segment.zero_size += size;
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(LabelInfo { name: None, addr: None, kind: SymbolKind::Data });
+        Label(self.labels.len() - 1)
+    }
+
+    // Named labels are listed in the symbols of the program.
+    pub fn named_label(&mut self, name: &str) -> Label {
+        self.labels.push(LabelInfo { name: Some(name.to_owned()), addr: None, kind: SymbolKind::Data });
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        let addr = self.addr();
+        let info = &mut self.labels[label.0];
+        if info.addr.is_some() {
+            self.fail(ErrorKind::RedefinedSymbol, addr);
+            return;
+        }
+        info.addr = Some(addr);
+        self.pending.push(label);
+    }
+
+    pub fn label_addr(&self, label: Label) -> Option<u32> {
+        self.labels[label.0].addr
+    }
+
+    pub fn d8(&mut self, value: u8) {
+        self.push_data(&[value]);
+    }
+
+    pub fn d16(&mut self, value: u16) {
+        self.push_data(&u16::to_le_bytes(value));
+    }
+
+    pub fn d32(&mut self, value: u32) {
+        self.push_data(&u32::to_le_bytes(value));
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.push_data(data);
+    }
+
+    // Emits the address of `label` as a 32-bit word.
+    pub fn d32_label(&mut self, label: Label) {
+        self.add_fixup(label, FixupKind::Word);
+        self.push_data(&[0; 4]);
+    }
+
+    rc_methods! {
+        li    = Li;
+        sysfn = Sysfn;
+    }
+
+    pub fn lui(&mut self, r1: u8, imm: u32) {
+        if imm >> 20 != 0 {
+            self.fail(ErrorKind::ConstantTooLarge, self.addr());
+        }
+        self.emit(Instruction::Lui(Rc { r1, imm: imm as i32 }));
+    }
+
+    // Loads any 32-bit constant: with `li` if it fits, otherwise with `lui`
+    // and `ori`.
+    pub fn li32(&mut self, r1: u8, value: u32) {
+        if check_imm_fits(value, 20).is_ok() {
+            self.li(r1, value as i32);
+        } else {
+            self.lui(r1, value >> 12);
+            if value & 0xFFF != 0 {
+                self.ori(r1, r1, (value & 0xFFF) as i32);
+            }
+        }
+    }
+
+    // Loads the address of `label`, which must fit in the immediate of `li`.
+    pub fn li_label(&mut self, r1: u8, label: Label) {
+        let inst = Instruction::Li(Rc { r1, imm: 0 });
+        self.emit_fixup(inst, label, FixupKind::Imm(inst));
+    }
+
+    rrc_methods! {
+        st_u8  = Stu8;
+        st_u16 = Stu16;
+        st     = St;
+
+        ld_s8  = Lds8;
+        ld_u8  = Ldu8;
+        ld_s16 = Lds16;
+        ld_u16 = Ldu16;
+        ld     = Ld;
+
+        jalr   = Jalr;
+
+        addi   = Addi;
+        rsubi  = Rsubi;
+        muli   = Muli;
+        andi   = Andi;
+        ori    = Ori;
+        xori   = Xori;
+        shli   = Shli;
+        lshri  = Lshri;
+        ashri  = Ashri;
+    }
+
+    pub fn jal(&mut self, r1: u8, target: Label) {
+        let inst = Instruction::Jal(Rc { r1, imm: 0 });
+        self.emit_fixup(inst, target, FixupKind::Branch(inst));
+    }
+
+    branch_methods! {
+        beq  = Beq,  false;
+        bne  = Bne,  false;
+        blt  = Blt,  false;
+        bge  = Bge,  false;
+        bltu = Bltu, false;
+        bgeu = Bgeu, false;
+
+        bgt  = Blt,  true;
+        ble  = Bge,  true;
+        bgtu = Bltu, true;
+        bleu = Bgeu, true;
+    }
+
+    rrr_methods! {
+        add  = Add;
+        sub  = Sub;
+        mul  = Mul;
+        and  = And;
+        or   = Or;
+        xor  = Xor;
+        shl  = Shl;
+        lshr = Lshr;
+        ashr = Ashr;
+    }
+
+    rrrr_methods! {
+        mulw  = Mulw;
+        mulwu = Mulwu;
+        div   = Div;
+        divu  = Divu;
+    }
+
+    pub fn jmp(&mut self, target: Label) {
+        self.jal(0, target);
+    }
+
+    pub fn call(&mut self, target: Label) {
+        self.jal(1, target);
+    }
+
+    pub fn ret(&mut self) {
+        self.jalr(0, 1, 0);
+    }
+
+    pub fn mov(&mut self, r1: u8, r2: u8) {
+        self.addi(r1, r2, 0);
+    }
+
+    // Resolves label references and returns the program; empty segments are
+    // dropped.
+    pub fn finish(mut self) -> Result<Program, Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        for fixup in &self.fixups {
+            let segment = &mut self.segments[fixup.segment];
+            let addr = segment.addr.wrapping_add(fixup.offset as u32);
+            let err = |kind| Error { kind, addr };
+            let target = self.labels[fixup.label.0].addr.ok_or(err(ErrorKind::UndefinedSymbol))?;
+
+            let word = match fixup.kind {
+                FixupKind::Branch(inst) => {
+                    let imm = eval_branch_offset(target, addr).map_err(err)?;
+                    let bits = if let Instruction::Jal(_) = inst { 20 } else { 16 };
+                    check_imm_fits(imm, bits).map_err(|_| err(ErrorKind::TargetTooFar))?;
+                    with_imm(inst, imm as i32).encode()
+                }
+                FixupKind::Imm(inst) => {
+                    check_imm_fits(target, 20).map_err(err)?;
+                    with_imm(inst, target as i32).encode()
+                }
+                FixupKind::Word => target,
+            };
+            segment.data[fixup.offset..fixup.offset + 4].copy_from_slice(&u32::to_le_bytes(word));
+        }
+
+        let mut program = Program::new();
+        program.memory_size = self.memory_size;
+        if let Some(label) = self.entry {
+            let entry = self.labels[label.0].addr;
+            program.entry = entry.ok_or(Error { kind: ErrorKind::UndefinedSymbol, addr: 0 })?;
+        }
+        program.initial_sp = self.initial_sp;
+        program.segments = self.segments;
+        program.segments.retain(|segment| !segment.is_empty());
+        for info in self.labels {
+            if let (Some(name), Some(value)) = (info.name, info.addr) {
+                program.symbols.push(Symbol { name, value, size: 0, kind: info.kind });
+            }
+        }
+        set_symbol_sizes(&mut program.symbols, &program.segments);
+        Ok(program)
+    }
+
+    fn fail(&mut self, kind: ErrorKind, addr: u32) {
+        if self.error.is_none() {
+            self.error = Some(Error { kind, addr });
+        }
+    }
+
+    // Bytes cannot follow reserved space within one segment.
+    fn end_reserved(&mut self) {
+        let segment = self.segments.last().unwrap();
+        if segment.zero_size != 0 {
+            let next = Segment { addr: self.addr(), perms: segment.perms, ..Segment::new() };
+            self.segments.push(next);
+        }
+    }
+
+    fn push_data(&mut self, data: &[u8]) {
+        self.end_reserved();
+        let segment = self.segments.last_mut().unwrap();
+        let end = (segment.data.len() as u64) + (data.len() as u64);
+        if u64::from(segment.addr) + end > u64::from(u32::MAX) + 1 {
+            let addr = self.addr();
+            self.fail(ErrorKind::AddrOverflow, addr);
+            return;
+        }
+        segment.data.extend_from_slice(data);
+        self.pending.clear();
+    }
+
+    fn emit(&mut self, inst: Instruction) {
+        let regs_valid = match inst.operands() {
+            Operands::Rc(ops) => ops.r1 < 16,
+            Operands::Rrc(ops) => ops.r1 < 16 && ops.r2 < 16,
+            Operands::Rrr(ops) => ops.r1 < 16 && ops.r2 < 16 && ops.r3 < 16,
+            Operands::Rrrr(ops) => ops.r1 < 16 && ops.r2 < 16 && ops.r3 < 16 && ops.r4 < 16,
+        };
+        if !regs_valid {
+            self.fail(ErrorKind::InvalidArgument, self.addr());
+        }
+        for label in &self.pending {
+            self.labels[label.0].kind = SymbolKind::Code;
+        }
+        self.push_data(&u32::to_le_bytes(inst.encode()));
+    }
+
+    fn emit_imm(&mut self, inst: Instruction, bits: u32) {
+        let imm = match inst.operands() {
+            Operands::Rc(Rc { imm, .. }) | Operands::Rrc(Rrc { imm, .. }) => imm,
+            _ => 0,
+        };
+        if check_imm_fits(imm as u32, bits).is_err() {
+            self.fail(ErrorKind::ConstantTooLarge, self.addr());
+        }
+        self.emit(inst);
+    }
+
+    fn emit_fixup(&mut self, inst: Instruction, label: Label, kind: FixupKind) {
+        self.add_fixup(label, kind);
+        self.emit(inst);
+    }
+
+    fn add_fixup(&mut self, label: Label, kind: FixupKind) {
+        self.end_reserved();
+        let segment = self.segments.len() - 1;
+        let offset = self.segments[segment].data.len();
+        self.fixups.push(Fixup { segment, offset, label, kind });
+    }
+}
+
+fn with_imm(inst: Instruction, imm: i32) -> Instruction {
+    let operands = match inst.operands() {
+        Operands::Rc(ops) => Operands::Rc(Rc { imm, ..ops }),
+        Operands::Rrc(ops) => Operands::Rrc(Rrc { imm, ..ops }),
+        operands => operands,
+    };
+    Instruction::from_parts(inst.opcode(), operands).unwrap()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at 0x{:X}", self.kind, self.addr)
+    }
+}
+
+impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::isa::{A0, A1, LR, SP, ZERO};
+    use crate::testing::NoDevice;
+    use crate::vm::StopReason;
+
+    fn build_error(build: impl FnOnce(&mut CodeBuilder)) -> Error {
+        let mut builder = CodeBuilder::new();
+        builder.seg(0x1000);
+        build(&mut builder);
+        match builder.finish() {
+            Ok(_) => panic!("built"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn forward_labels() {
+        let mut builder = CodeBuilder::new();
+        builder.seg(0x1000);
+        let (skip, data, end) = (builder.new_label(), builder.new_label(), builder.new_label());
+        builder.beq(A0, ZERO, skip);
+        builder.jmp(end);
+        builder.bind(skip);
+        builder.li_label(A1, data);
+        builder.bind(end);
+        builder.ret();
+        builder.bind(data);
+        builder.d32_label(end);
+        let program = builder.finish().unwrap();
+
+        let source = "
+    seg     0x1000
+    beq     %a0, %zero, skip
+    jmp     end
+skip:
+    li      %a1, data
+end:
+    ret
+data:
+    d32     end
+";
+        let expected = asm::assemble(source).unwrap();
+        assert_eq!(program.segments[0].data, expected.segments[0].data);
+    }
+
+    #[test]
+    fn branch_range() {
+        // The offset is in words from the next instruction and has 16 bits.
+        let err = build_error(|builder| {
+            let far = builder.new_label();
+            builder.addi(A0, A0, 1);
+            builder.bne(A0, ZERO, far);
+            builder.space(0x2_0000);
+            builder.bind(far);
+            builder.ret();
+        });
+        assert!(matches!(err.kind, ErrorKind::TargetTooFar));
+        assert_eq!(err.addr, 0x1004);
+
+        let mut builder = CodeBuilder::new();
+        builder.seg(0x1000);
+        let near = builder.new_label();
+        builder.bne(A0, ZERO, near);
+        builder.space(0x1_FFFC);
+        builder.bind(near);
+        builder.ret();
+        assert!(builder.finish().is_ok());
+
+        // `jal` has 20 bits.
+        let err = build_error(|builder| {
+            let back = builder.new_label();
+            builder.bind(back);
+            builder.space(0x20_0000);
+            builder.call(back);
+        });
+        assert!(matches!(err.kind, ErrorKind::TargetTooFar));
+        assert_eq!(err.addr, 0x20_1000);
+
+        let err = build_error(|builder| {
+            let missing = builder.new_label();
+            builder.ret();
+            builder.jmp(missing);
+        });
+        assert!(matches!(err.kind, ErrorKind::UndefinedSymbol));
+        assert_eq!(err.addr, 0x1004);
+
+        let err = build_error(|builder| {
+            builder.ret();
+            builder.addi(A0, A0, 0x8000);
+            builder.addi(A0, A0, 0x8000);
+        });
+        assert!(matches!(err.kind, ErrorKind::ConstantTooLarge));
+        assert_eq!(err.addr, 0x1004);
+    }
+
+    #[test]
+    fn li32() {
+        for value in [0, 1, 0x7_FFFF, 0xFFF8_0000, !0, 0x8_0000, 0x1234_5000, 0xDEAD_BEEF, 0x8000_0000] {
+            let mut builder = CodeBuilder::new();
+            builder.mem(0x2000);
+            builder.seg(0x1000);
+            builder.li32(A0, value);
+            builder.sysfn(A0, 0);
+            let program = builder.finish().unwrap();
+
+            // One instruction if `li` or `lui` alone is enough, otherwise `lui` and `ori`.
+            let fits = value <= 0x7_FFFF || value >= 0xFFF8_0000 || value & 0xFFF == 0;
+            let count = if fits { 1 } else { 2 };
+            assert_eq!(program.segments[0].data.len(), 4 * (count + 1), "0x{:X}", value);
+
+            let mut machine = program.to_machine().unwrap();
+            let reason = machine.run(&mut NoDevice);
+            assert!(matches!(reason, StopReason::Exited(status) if status == value), "0x{:X}", value);
+        }
+    }
+
+    #[test]
+    fn entry_and_symbols() {
+        let mut builder = CodeBuilder::new();
+        builder.mem(0x2000);
+        builder.initial_sp(0x1800);
+        builder.seg(0x1000);
+        let (table, main) = (builder.named_label("table"), builder.named_label("main"));
+        let empty = builder.named_label("empty");
+        builder.entry(main);
+        builder.bind(table);
+        builder.d32(1);
+        builder.d32(2);
+        builder.bind(main);
+        builder.mov(A0, SP);
+        builder.sysfn(A0, 0);
+        builder.seg(0x1800);
+        builder.bind(empty);
+        let program = builder.finish().unwrap();
+
+        assert_eq!((program.entry, program.initial_sp), (0x1008, Some(0x1800)));
+        let symbols: Vec<_> =
+            program.symbols.iter().map(|s| (s.name.as_str(), s.value, s.size, s.kind)).collect();
+        assert_eq!(
+            symbols,
+            [
+                ("table", 0x1000, 8, SymbolKind::Data),
+                ("main", 0x1008, 8, SymbolKind::Code),
+                ("empty", 0x1800, 0, SymbolKind::Data),
+            ]
+        );
+        let mut machine = program.to_machine().unwrap();
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(0x1800)));
+
+        let mut builder = CodeBuilder::new();
+        let main = builder.new_label();
+        builder.entry(main);
+        let err = builder.finish().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::UndefinedSymbol));
+
+        let err = build_error(|builder| {
+            let label = builder.new_label();
+            builder.bind(label);
+            builder.ret();
+            builder.bind(label);
+        });
+        assert!(matches!(err.kind, ErrorKind::RedefinedSymbol));
+        assert_eq!(err.addr, 0x1004);
+    }
+
+    #[test]
+    fn same_as_assembler() {
+        let source = "
+    mem     0x4000
+    entry   main, 0x3000
+    seg     0x1000
+main:
+    li      %a0, 10
+    call    count
+    lui     %a1, 0x12345
+    st      %a0, %a1, 0
+    sysfn   %a0, 0
+count:
+    addi    %a0, %a0, -1
+    bgt     %a0, %zero, count
+    ret
+    seg     0x2000
+table:
+    d32     main, count
+    d16     0x1234
+    d8      7, 8
+    space   6
+tail:
+    d8      9
+";
+        let expected = asm::assemble(source).unwrap();
+
+        let mut builder = CodeBuilder::new();
+        builder.mem(0x4000);
+        builder.initial_sp(0x3000);
+        builder.seg(0x1000);
+        let main = builder.named_label("main");
+        let count = builder.named_label("count");
+        builder.entry(main);
+        builder.bind(main);
+        builder.li(A0, 10);
+        builder.call(count);
+        builder.lui(A1, 0x12345);
+        builder.st(A0, A1, 0);
+        builder.sysfn(A0, 0);
+        builder.bind(count);
+        builder.addi(A0, A0, -1);
+        builder.bgt(A0, ZERO, count);
+        builder.jalr(ZERO, LR, 0);
+        builder.seg(0x2000);
+        let table = builder.named_label("table");
+        builder.bind(table);
+        builder.d32_label(main);
+        builder.d32_label(count);
+        builder.d16(0x1234);
+        builder.bytes(&[7, 8]);
+        builder.space(6);
+        let tail = builder.named_label("tail");
+        builder.bind(tail);
+        builder.d8(9);
+        let program = builder.finish().unwrap();
+
+        assert_eq!(program.memory_size, expected.memory_size);
+        assert_eq!((program.entry, program.initial_sp), (expected.entry, expected.initial_sp));
+        let segments = |program: &Program| -> Vec<_> {
+            let segments = program.segments.iter();
+            let mut segments: Vec<_> =
+                segments.map(|s| (s.addr, s.data.clone(), s.zero_size, s.perms)).collect();
+            segments.sort_by_key(|segment| segment.0);
+            segments
+        };
+        assert_eq!(segments(&program), segments(&expected));
+        let symbols = |program: &Program| -> Vec<_> {
+            let symbols = program.symbols.iter();
+            symbols.map(|s| (s.name.clone(), s.value, s.size, s.kind)).collect()
+        };
+        assert_eq!(symbols(&program), symbols(&expected));
+    }
+}
@@ -0,0 +1,1285 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::mem;
+
+use smallvec::SmallVec;
+
+use crate::isa::{self, ImmKind, InstInfo, Instruction, Operands, Rc, Rrc, Rrr, Rrrr};
+use crate::binfile::{self, SymbolKind};
+use crate::object::{self, Object, Place, RelocKind, Target};
+use crate::opcode;
+use crate::vm::{Machine, Perms};
+
+use super::ast::*;
+use super::id_table::{IdentTable, Symbol as SymbolId};
+use super::inst_syms::*;
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub file: u32,
+    pub line: u32,
+    pub expansion: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErrorKind {
+    UnknownInst,
+    InvalidArgument,
+    InvalidArgCount,
+
+    UndefinedSymbol,
+    RedefinedSymbol,
+    CircularDefinition(String),
+
+    AddrOverflow,
+    ConstantTooLarge,
+    TargetTooFar,
+    MisalignedOffset,
+    RedefinedEntry,
+    InvalidPerms,
+    NotRelocatable,
+    ObjectOnly,
+    NotInObject,
+    InvalidRange,
+}
+
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub memory_size: u32,
+    pub entry: u32,
+    pub initial_sp: Option<u32>,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+    // Zero bytes reserved after `data`; they take no space in the file.
+    pub zero_size: u32,
+    pub perms: Perms,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LineEntry {
+    pub addr: u32,
+    pub size: u32,
+    // Index into `Program::files`.
+    pub file: u32,
+    pub line: u32,
+}
+
+// Where a value is relative to. Only object files have values that are not
+// absolute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Base {
+    Abs,
+    Section(u32),
+    // An `extern` symbol, by its id.
+    Extern(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Value {
+    base: Base,
+    offset: u32,
+}
+
+#[derive(Clone, Debug)]
+struct SectionDef {
+    name: String,
+    perms: Perms,
+    // Offset reached so far while resolving symbols.
+    size: Result<u32, Blocked>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PendingReloc {
+    section: u32,
+    offset: u32,
+    kind: RelocKind,
+    value: Value,
+}
+
+// What `compile_tree` produces. For object files every segment holds a section
+// and addresses are offsets into sections.
+#[derive(Default)]
+struct Output {
+    program: Program,
+    entry: Option<Value>,
+    relocs: Vec<PendingReloc>,
+    // Section of each entry of `program.lines`.
+    line_sections: Vec<u32>,
+}
+
+impl Node {
+    fn error(&self, kind: ErrorKind) -> Error {
+        Error { kind, file: self.file, line: self.line, expansion: self.expansion }
+    }
+}
+
+impl Value {
+    pub fn abs(offset: u32) -> Value {
+        Value { base: Base::Abs, offset }
+    }
+}
+
+impl Output {
+    fn add_reloc(&mut self, base: Base, offset: u32, kind: RelocKind, value: Value) {
+        // Values other than absolute ones exist only in sections.
+        let Base::Section(section) = base else { unreachable!() };
+        self.relocs.push(PendingReloc { section, offset, kind, value });
+    }
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program {
+            memory_size: 0,
+            entry: binfile::DEFAULT_ENTRY,
+            initial_sp: None,
+            segments: Vec::new(),
+            symbols: Vec::new(),
+            files: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn to_binfile(&self) -> binfile::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let symbols: Vec<_> = self.symbols.iter().map(binfile::Symbol::from).collect();
+        let files: Vec<_> = self.files.iter().map(String::as_str).collect();
+        let lines: Vec<_> = self.lines.iter().map(binfile::Line::from).collect();
+        let header = binfile::Header {
+            memory_size: self.memory_size,
+            entry: self.entry,
+            initial_sp: self.initial_sp,
+            symbols: &symbols,
+            files: &files,
+            lines: &lines,
+        };
+        binfile::serialize_with(&header, &self.segments, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn to_memory(&self) -> binfile::Result<Vec<u8>> {
+        binfile::to_memory(&self.to_binfile()?)
+    }
+
+    pub fn to_machine(&self) -> binfile::Result<Machine> {
+        binfile::load(&self.to_binfile()?)
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Program::new()
+    }
+}
+
+impl Segment {
+    pub fn new() -> Segment {
+        Segment { addr: 0, data: Vec::new(), zero_size: 0, perms: Perms::ALL }
+    }
+
+    pub fn size(&self) -> u32 {
+        (self.data.len() as u32).wrapping_add(self.zero_size)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.zero_size == 0
+    }
+}
+
+impl Default for Segment {
+    fn default() -> Self {
+        Segment::new()
+    }
+}
+
+impl<'a> From<&'a Segment> for binfile::Segment<'a> {
+    fn from(segment: &'a Segment) -> Self {
+        binfile::Segment {
+            addr: segment.addr,
+            data: &segment.data,
+            zero_size: segment.zero_size,
+            perms: segment.perms,
+        }
+    }
+}
+
+impl<'a> From<&'a Symbol> for binfile::Symbol<'a> {
+    fn from(symbol: &'a Symbol) -> Self {
+        binfile::Symbol {
+            name: &symbol.name,
+            value: symbol.value,
+            size: symbol.size,
+            kind: symbol.kind,
+        }
+    }
+}
+
+impl From<&LineEntry> for binfile::Line {
+    fn from(entry: &LineEntry) -> Self {
+        binfile::Line {
+            addr: entry.addr,
+            size: entry.size,
+            file: entry.file,
+            line: entry.line,
+        }
+    }
+}
+
+pub fn compile(ast: &[Node], id_table: &IdentTable) -> Result<Program, Error> {
+    let (symtab, sections) = resolve_symbols(ast, id_table, false)?;
+    let Output { mut program, entry, .. } = compile_tree(ast, &symtab, &sections)?;
+    if let Some(entry) = entry {
+        program.entry = entry.offset;
+    }
+    program.lines.sort_by_key(|entry| entry.addr);
+    remove_empty_segments(&mut program.segments);
+
+    for (i, node) in ast.iter().enumerate() {
+        let (sym, kind) = match node.kind {
+            NodeKind::Label(sym) => (sym, label_kind(&ast[i + 1..])),
+            NodeKind::Assign(sym) => (sym, SymbolKind::Constant),
+            NodeKind::Inst(_) => continue,
+        };
+        let value = symtab[sym.id as usize].unwrap().offset;
+        program.symbols.push(Symbol { name: id_table.name(sym).to_owned(), value, size: 0, kind });
+    }
+    set_symbol_sizes(&mut program.symbols, &program.segments);
+
+    Ok(program)
+}
+
+pub fn compile_object(ast: &[Node], id_table: &IdentTable) -> Result<Object, Error> {
+    let (symtab, sections) = resolve_symbols(ast, id_table, true)?;
+    let out = compile_tree(ast, &symtab, &sections)?;
+
+    let mut object = Object::new();
+    object.memory_size = out.program.memory_size;
+    object.initial_sp = out.program.initial_sp;
+    for (def, segment) in sections.iter().zip(out.program.segments) {
+        object.sections.push(object::Section {
+            name: def.name.clone(),
+            data: segment.data,
+            zero_size: segment.zero_size,
+            perms: segment.perms,
+        });
+    }
+
+    // Index into `object.symbols` by symbol id.
+    let mut indices = vec![None; id_table.len()];
+    for (i, node) in ast.iter().enumerate() {
+        let (sym, kind) = match node.kind {
+            NodeKind::Label(sym) => (sym, label_kind(&ast[i + 1..])),
+            NodeKind::Assign(sym) => (sym, SymbolKind::Constant),
+            NodeKind::Inst(_) => continue,
+        };
+        let value = symtab[sym.id as usize].unwrap();
+        let place = match value.base {
+            Base::Abs => Place::Absolute,
+            Base::Section(index) => Place::Section(index),
+            Base::Extern(_) => unreachable!(),
+        };
+        indices[sym.id as usize] = Some(object.symbols.len() as u32);
+        object.symbols.push(object::Symbol {
+            name: id_table.name(sym).to_owned(),
+            place,
+            value: value.offset,
+            size: 0,
+            kind,
+            global: false,
+        });
+    }
+    set_section_symbol_sizes(&mut object.symbols, &object.sections);
+
+    for node in ast {
+        if let NodeKind::Inst(GLOBAL) = node.kind {
+            for arg in &node.args {
+                let sym = extract_label(arg).unwrap();
+                match indices[sym.id as usize].map(|index| &mut object.symbols[index as usize]) {
+                    Some(symbol) if symbol.place != Place::Undefined => symbol.global = true,
+                    _ => return Err(node.error(ErrorKind::UndefinedSymbol)),
+                }
+            }
+        }
+    }
+
+    // Only the external symbols that are referenced get into the object.
+    for value in out.relocs.iter().map(|reloc| reloc.value).chain(out.entry) {
+        let Base::Extern(id) = value.base else { continue };
+        if indices[id as usize].is_none() {
+            indices[id as usize] = Some(object.symbols.len() as u32);
+            object.symbols.push(object::Symbol {
+                name: id_table.name(SymbolId { id }).to_owned(),
+                place: Place::Undefined,
+                value: 0,
+                size: 0,
+                kind: SymbolKind::Code,
+                global: false,
+            });
+        }
+    }
+
+    let target = |value: Value| match value.base {
+        Base::Abs => Target::Absolute,
+        Base::Section(index) => Target::Section(index),
+        Base::Extern(id) => Target::Symbol(indices[id as usize].unwrap()),
+    };
+    object.entry = out.entry.map(|value| (target(value), value.offset));
+    for reloc in &out.relocs {
+        object.relocs.push(object::Reloc {
+            section: reloc.section,
+            offset: reloc.offset,
+            kind: reloc.kind,
+            target: target(reloc.value),
+            addend: reloc.value.offset,
+        });
+    }
+    for (entry, &section) in out.program.lines.iter().zip(&out.line_sections) {
+        object.lines.push(object::Line {
+            section,
+            offset: entry.addr,
+            size: entry.size,
+            file: entry.file,
+            line: entry.line,
+        });
+    }
+
+    Ok(object)
+}
+
+// A label marks code if the first thing after it is an instruction.
+fn label_kind(rest: &[Node]) -> SymbolKind {
+    let inst = rest.iter().find_map(|node| match node.kind {
+        NodeKind::Inst(inst) => Some(inst),
+        _ => None,
+    });
+    match inst {
+        Some(MEM | SEG | ENTRY | RES | SPACE | D8 | D16 | D32 | INCBIN | SECTION | GLOBAL | EXTERN)
+        | None => SymbolKind::Data,
+        Some(_) => SymbolKind::Code,
+    }
+}
+
+// A label extends up to the next label or the end of its segment.
+pub fn set_symbol_sizes(symbols: &mut [Symbol], segments: &[Segment]) {
+    let mut addrs: Vec<u32> = symbols
+        .iter()
+        .filter(|symbol| symbol.kind != SymbolKind::Constant)
+        .map(|symbol| symbol.value)
+        .collect();
+    addrs.sort_unstable();
+
+    for symbol in symbols.iter_mut().filter(|symbol| symbol.kind != SymbolKind::Constant) {
+        let value = symbol.value;
+        let segment = segments.iter().find(|segment| {
+            value >= segment.addr && value - segment.addr < segment.size()
+        });
+        let Some(segment) = segment else { continue };
+        let end = segment.addr.wrapping_add(segment.size());
+        let next = addrs[addrs.partition_point(|&addr| addr <= value)..].first().copied();
+        symbol.size = next.map_or(end, |next| next.min(end)).wrapping_sub(value);
+    }
+}
+
+// Same as `set_symbol_sizes` for labels of an object file.
+fn set_section_symbol_sizes(symbols: &mut [object::Symbol], sections: &[object::Section]) {
+    let mut places: Vec<(u32, u32)> = symbols
+        .iter()
+        .filter(|symbol| symbol.kind != SymbolKind::Constant)
+        .filter_map(|symbol| match symbol.place {
+            Place::Section(index) => Some((index, symbol.value)),
+            _ => None,
+        })
+        .collect();
+    places.sort_unstable();
+
+    for symbol in symbols.iter_mut().filter(|symbol| symbol.kind != SymbolKind::Constant) {
+        let Place::Section(index) = symbol.place else { continue };
+        let end = sections[index as usize].size();
+        if symbol.value >= end {
+            continue;
+        }
+        let place = (index, symbol.value);
+        let next = places[places.partition_point(|&other| other <= place)..]
+            .first()
+            .filter(|next| next.0 == index)
+            .map(|next| next.1);
+        symbol.size = next.map_or(end, |next| next.min(end)) - symbol.value;
+    }
+}
+
+fn eval_value(expr: &[Expr], symtab: &[Option<Value>]) -> Result<Value, ErrorKind> {
+    let mut stack: SmallVec<[Value; 16]> = SmallVec::new();
+
+    macro_rules! binop_impl {
+        ($op:expr) => {{
+            let y = stack.pop().unwrap();
+            let x = stack.pop().unwrap();
+            if x.base != Base::Abs || y.base != Base::Abs {
+                return Err(ErrorKind::NotRelocatable);
+            }
+            stack.push(Value::abs($op(x.offset, y.offset) as u32));
+        }};
+    }
+
+    for op in expr {
+        match op {
+            Expr::Int(x) => stack.push(Value::abs(*x)),
+            Expr::Label(sym) => {
+                stack.push(symtab[sym.id as usize].ok_or(ErrorKind::UndefinedSymbol)?);
+            }
+            Expr::Add => {
+                let y = stack.pop().unwrap();
+                let x = stack.pop().unwrap();
+                let base = match (x.base, y.base) {
+                    (base, Base::Abs) | (Base::Abs, base) => base,
+                    _ => return Err(ErrorKind::NotRelocatable),
+                };
+                stack.push(Value { base, offset: x.offset.wrapping_add(y.offset) });
+            }
+            Expr::Sub => {
+                let y = stack.pop().unwrap();
+                let x = stack.pop().unwrap();
+                let base = match (x.base, y.base) {
+                    (base, Base::Abs) => base,
+                    (x, y) if x == y => Base::Abs,
+                    _ => return Err(ErrorKind::NotRelocatable),
+                };
+                stack.push(Value { base, offset: x.offset.wrapping_sub(y.offset) });
+            }
+            Expr::Mul  => binop_impl!(u32::wrapping_mul),
+            Expr::And  => binop_impl!(|x, y| x & y),
+            Expr::Or   => binop_impl!(|x, y| x | y),
+            Expr::Xor  => binop_impl!(|x, y| x ^ y),
+            Expr::Shl  => binop_impl!(|x, y| x << (y & 0x1F)),
+            Expr::Lshr => binop_impl!(|x, y| x >> (y & 0x1F)),
+            Expr::Ashr => binop_impl!(|x, y| (x as i32) >> (y & 0x1F)),
+        }
+    }
+
+    Ok(stack.pop().unwrap())
+}
+
+pub fn eval_expr(expr: &[Expr], symtab: &[Option<Value>]) -> Result<u32, ErrorKind> {
+    match eval_value(expr, symtab)? {
+        Value { base: Base::Abs, offset } => Ok(offset),
+        _ => Err(ErrorKind::NotRelocatable),
+    }
+}
+
+// Why an address or a symbol is not known yet: the first undefined symbol it
+// depends on and the index of the node that refers to it.
+#[derive(Clone, Copy, Debug)]
+struct Blocked {
+    on: SymbolId,
+    node: usize,
+}
+
+// Symbols may refer to symbols defined further down, and sizes of `res`,
+// `space` and `incbin` and addresses of `seg` may depend on such symbols. The
+// layout is repeated until every symbol is known; a symbol is only given a
+// value once everything it depends on is known, so values never change between
+// passes.
+fn resolve_symbols(
+    ast: &[Node],
+    id_table: &IdentTable,
+    object: bool,
+) -> Result<(Vec<Option<Value>>, Vec<SectionDef>), Error> {
+    check_definitions(ast, id_table.len())?;
+
+    let mut symtab: Vec<Option<Value>> = vec![None; id_table.len()];
+    let mut defined = 0;
+    loop {
+        let mut blocked = HashMap::new();
+        let sections = layout(ast, &mut symtab, &mut blocked, object)?;
+        if blocked.is_empty() {
+            return Ok((symtab, sections));
+        }
+
+        let count = symtab.iter().filter(|value| value.is_some()).count();
+        if count == defined {
+            return Err(unresolved_error(ast, id_table, &blocked));
+        }
+        defined = count;
+    }
+}
+
+fn check_definitions(ast: &[Node], table_size: usize) -> Result<(), Error> {
+    let mut defined = vec![false; table_size];
+    let mut has_entry = false;
+    for node in ast {
+        let syms: SmallVec<[SymbolId; 1]> = match node.kind {
+            NodeKind::Label(sym) | NodeKind::Assign(sym) => SmallVec::from_elem(sym, 1),
+            NodeKind::Inst(EXTERN) => node.args.iter().filter_map(|arg| extract_label(arg).ok()).collect(),
+            NodeKind::Inst(ENTRY) => {
+                if has_entry {
+                    return Err(node.error(ErrorKind::RedefinedEntry));
+                }
+                has_entry = true;
+                continue;
+            }
+            NodeKind::Inst(_) => continue,
+        };
+        for sym in syms {
+            if mem::replace(&mut defined[sym.id as usize], true) {
+                return Err(node.error(ErrorKind::RedefinedSymbol));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Lays out the code once, giving values to the symbols that can be resolved
+// and recording in `blocked` what the others wait for.
+fn layout(
+    ast: &[Node],
+    symtab: &mut [Option<Value>],
+    blocked: &mut HashMap<SymbolId, Blocked>,
+    object: bool,
+) -> Result<Vec<SectionDef>, Error> {
+    let mut sections: Vec<SectionDef> = Vec::new();
+    let mut section = None;
+    let mut addr: Result<u32, Blocked> = Ok(0);
+
+    for (i, node) in ast.iter().enumerate() {
+        macro_rules! err {
+            ($e:expr) => {
+                Err(node.error($e))
+            };
+        }
+
+        macro_rules! eval {
+            ($e:expr) => {
+                match $e {
+                    Ok(value) => Ok(value),
+                    Err(ErrorKind::UndefinedSymbol) => {
+                        Err(Blocked { on: first_undefined(&node.args, symtab).unwrap(), node: i })
+                    }
+                    Err(err) => return err!(err),
+                }
+            };
+        }
+
+        // Moves past something of the given size, or `None` if the size
+        // overflows.
+        macro_rules! advance {
+            ($size:expr) => {
+                addr = match (addr, $size) {
+                    (Ok(addr), Ok(size)) => match size.and_then(|size| addr.checked_add(size)) {
+                        Some(value) => Ok(value),
+                        None => return err!(ErrorKind::AddrOverflow),
+                    },
+                    (Err(reason), _) | (_, Err(reason)) => Err(reason),
+                }
+            };
+        }
+
+        // Code and data before the first `section` go to "text".
+        if object && section.is_none() && needs_section(node) {
+            sections.push(SectionDef { name: "text".to_owned(), perms: Perms::ALL, size: Ok(0) });
+            section = Some(0);
+        }
+        let base = section.map_or(Base::Abs, |index| Base::Section(index as u32));
+
+        match node.kind {
+            NodeKind::Label(sym) => match addr {
+                Ok(offset) => symtab[sym.id as usize] = Some(Value { base, offset }),
+                Err(reason) => {
+                    blocked.insert(sym, reason);
+                }
+            },
+            NodeKind::Assign(sym) => {
+                if node.args.len() != 1 {
+                    return err!(ErrorKind::InvalidArgCount);
+                }
+                if symtab[sym.id as usize].is_some() {
+                    continue;
+                }
+
+                match eval!(extract_and_eval_value(&node.args[0], symtab)) {
+                    Ok(Value { base: Base::Extern(_), .. }) => return err!(ErrorKind::NotRelocatable),
+                    Ok(value) => symtab[sym.id as usize] = Some(value),
+                    Err(reason) => {
+                        blocked.insert(sym, reason);
+                    }
+                }
+            }
+            NodeKind::Inst(MEM | ENTRY) => {}
+            NodeKind::Inst(SEG) => {
+                if object {
+                    return err!(ErrorKind::NotInObject);
+                }
+                if node.args.is_empty() || node.args.len() > 2 {
+                    return err!(ErrorKind::InvalidArgCount);
+                }
+
+                addr = eval!(extract_and_eval_expr(&node.args[0], symtab));
+            }
+            NodeKind::Inst(SECTION | GLOBAL | EXTERN) if !object => return err!(ErrorKind::ObjectOnly),
+            NodeKind::Inst(SECTION) => {
+                let (name, perms) = match parse_section(&node.args) {
+                    Ok(value) => value,
+                    Err(err) => return err!(err),
+                };
+                if let Some(index) = section {
+                    sections[index].size = addr;
+                }
+                let index = match sections.iter().position(|def| def.name == name) {
+                    Some(index) if perms.is_some_and(|perms| perms != sections[index].perms) => {
+                        return err!(ErrorKind::InvalidPerms);
+                    }
+                    Some(index) => index,
+                    None => {
+                        sections.push(SectionDef { name, perms: perms.unwrap_or(Perms::ALL), size: Ok(0) });
+                        sections.len() - 1
+                    }
+                };
+                addr = sections[index].size;
+                section = Some(index);
+            }
+            NodeKind::Inst(t @ (GLOBAL | EXTERN)) => {
+                if node.args.is_empty() {
+                    return err!(ErrorKind::InvalidArgCount);
+                }
+
+                for arg in &node.args {
+                    let sym = match extract_label(arg) {
+                        Ok(sym) => sym,
+                        Err(err) => return err!(err),
+                    };
+                    if t == EXTERN {
+                        symtab[sym.id as usize] = Some(Value { base: Base::Extern(sym.id), offset: 0 });
+                    }
+                }
+            }
+            NodeKind::Inst(t @ (RES | SPACE)) => {
+                if node.args.len() != 1 {
+                    return err!(ErrorKind::InvalidArgCount);
+                }
+
+                let size = eval!(extract_and_eval_expr(&node.args[0], symtab));
+                advance!(size.map(|value| if t == RES { value.checked_mul(4) } else { Some(value) }));
+            }
+            NodeKind::Inst(t @ (D8 | D16 | D32)) => {
+                if node.args.is_empty() {
+                    return err!(ErrorKind::InvalidArgCount);
+                }
+
+                let size = match t {
+                    D8 => 1,
+                    D16 => 2,
+                    D32 => 4,
+                    _ => unreachable!(),
+                };
+
+                for arg in &node.args {
+                    let offset = match arg {
+                        Arg::Expr(_) => Some(size),
+                        Arg::Str(s) => u32::try_from(s.len()).ok(),
+                        _ => return err!(ErrorKind::InvalidArgument),
+                    };
+                    advance!(Ok(offset));
+                }
+            }
+            NodeKind::Inst(INCBIN) => {
+                let data = eval!(incbin_data(&node.args, symtab));
+                advance!(data.map(|data| u32::try_from(data.len()).ok()));
+            }
+            _ => advance!(Ok(Some(4))),
+        }
+    }
+
+    Ok(sections)
+}
+
+fn first_undefined(args: &[Arg], symtab: &[Option<Value>]) -> Option<SymbolId> {
+    args.iter().find_map(|arg| match arg {
+        Arg::Expr(expr) => expr.iter().find_map(|op| match op {
+            Expr::Label(sym) if symtab[sym.id as usize].is_none() => Some(*sym),
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+// Follows what the unresolved symbols wait for until it reaches a symbol that
+// is not defined anywhere or comes back to a symbol seen before.
+fn unresolved_error(ast: &[Node], id_table: &IdentTable, blocked: &HashMap<SymbolId, Blocked>) -> Error {
+    let (&first, &reason) = blocked.iter().min_by_key(|(sym, reason)| (reason.node, sym.id)).unwrap();
+    let mut chain = vec![first];
+    let mut reason = reason;
+    loop {
+        if let Some(start) = chain.iter().position(|&sym| sym == reason.on) {
+            let names: Vec<&str> = chain[start..]
+                .iter()
+                .chain([&reason.on])
+                .map(|&sym| id_table.name(sym))
+                .collect();
+            let node = &ast[blocked[&chain[start]].node];
+            return node.error(ErrorKind::CircularDefinition(names.join(" -> ")));
+        }
+        match blocked.get(&reason.on) {
+            Some(&next) => {
+                chain.push(reason.on);
+                reason = next;
+            }
+            None => return ast[reason.node].error(ErrorKind::UndefinedSymbol),
+        }
+    }
+}
+
+// `incbin "file" [, offset [, size]]`; the parser has put the contents of the
+// file in place of its name.
+fn incbin_data<'a>(args: &'a [Arg], symtab: &[Option<Value>]) -> Result<&'a [u8], ErrorKind> {
+    if args.is_empty() || args.len() > 3 {
+        return Err(ErrorKind::InvalidArgCount);
+    }
+    let Arg::Str(data) = &args[0] else {
+        return Err(ErrorKind::InvalidArgument);
+    };
+    let offset = match args.get(1) {
+        Some(arg) => extract_and_eval_expr(arg, symtab)? as usize,
+        None => 0,
+    };
+    let rest = data.get(offset..).ok_or(ErrorKind::InvalidRange)?;
+    match args.get(2) {
+        Some(arg) => {
+            let size = extract_and_eval_expr(arg, symtab)? as usize;
+            rest.get(..size).ok_or(ErrorKind::InvalidRange)
+        }
+        None => Ok(rest),
+    }
+}
+
+// `section "name" [, "perms"]`; the permissions are only needed the first time.
+fn parse_section(args: &[Arg]) -> Result<(String, Option<Perms>), ErrorKind> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(ErrorKind::InvalidArgCount);
+    }
+    let name = match &args[0] {
+        Arg::Str(s) if !s.is_empty() => {
+            String::from_utf8(s.to_vec()).map_err(|_| ErrorKind::InvalidArgument)?
+        }
+        _ => return Err(ErrorKind::InvalidArgument),
+    };
+    let perms = match args.get(1) {
+        Some(Arg::Str(s)) => Some(Perms::parse(s).ok_or(ErrorKind::InvalidPerms)?),
+        Some(_) => return Err(ErrorKind::InvalidPerms),
+        None => None,
+    };
+    Ok((name, perms))
+}
+
+fn compile_tree(ast: &[Node], symtab: &[Option<Value>], sections: &[SectionDef]) -> Result<Output, Error> {
+    let mut out = Output::default();
+    // Object files have a segment at offset 0 for every section.
+    out.program.segments = sections
+        .iter()
+        .map(|def| Segment { perms: def.perms, ..Segment::new() })
+        .collect();
+    let mut section = sections.iter().position(|def| def.name == "text");
+    let mut segment = match section {
+        Some(index) => mem::take(&mut out.program.segments[index]),
+        None => Segment::new(),
+    };
+
+    for node in ast {
+        if let NodeKind::Inst(SECTION) = node.kind {
+            let (name, _) = parse_section(&node.args).unwrap();
+            let next = sections.iter().position(|def| def.name == name).unwrap();
+            if let Some(index) = section {
+                out.program.segments[index] = mem::take(&mut segment);
+            }
+            segment = mem::take(&mut out.program.segments[next]);
+            section = Some(next);
+            continue;
+        }
+
+        if segment.zero_size != 0 && emits_data(node) {
+            if section.is_some() {
+                // Sections are contiguous, so the reserved space is filled in.
+                segment.data.resize(segment.size() as usize, 0);
+                segment.zero_size = 0;
+            } else {
+                // Data after reserved space starts a new segment at the same place.
+                let next = Segment {
+                    addr: segment.addr.wrapping_add(segment.size()),
+                    perms: segment.perms,
+                    ..Segment::new()
+                };
+                out.program.segments.push(mem::replace(&mut segment, next));
+            }
+        }
+
+        let base = section.map_or(Base::Abs, |index| Base::Section(index as u32));
+        let size = segment.data.len();
+        match compile_node(node, symtab, base, &mut out, &mut segment) {
+            Ok(()) => {}
+            Err(err) => return Err(node.error(err)),
+        }
+        if segment.data.len() > size {
+            let addr = segment.addr.wrapping_add(size as u32);
+            let size = (segment.data.len() - size) as u32;
+            out.program.lines.push(LineEntry { addr, size, file: node.file, line: node.line });
+            out.line_sections.push(section.unwrap_or(0) as u32);
+        }
+    }
+
+    match section {
+        Some(index) => out.program.segments[index] = segment,
+        None => out.program.segments.push(segment),
+    }
+    Ok(out)
+}
+
+fn emits_data(node: &Node) -> bool {
+    match node.kind {
+        NodeKind::Inst(inst) => !matches!(inst, MEM | SEG | ENTRY | RES | SPACE | SECTION | GLOBAL | EXTERN),
+        NodeKind::Label(_) | NodeKind::Assign(_) => false,
+    }
+}
+
+fn needs_section(node: &Node) -> bool {
+    match node.kind {
+        NodeKind::Label(_) | NodeKind::Inst(RES | SPACE) => true,
+        _ => emits_data(node),
+    }
+}
+
+fn compile_node(
+    node: &Node,
+    symtab: &[Option<Value>],
+    base: Base,
+    out: &mut Output,
+    segment: &mut Segment,
+) -> Result<(), ErrorKind> {
+    // `layout` has already rejected addresses that overflow.
+    let addr = segment.addr.wrapping_add(segment.data.len() as u32);
+
+    let inst = match node.kind {
+        NodeKind::Inst(inst) => inst,
+        _ => return Ok(()),
+    };
+
+    match inst {
+        MEM => {
+            check_arg_count(node.args.len(), 1)?;
+            let new_memory_size = extract_and_eval_expr(&node.args[0], symtab)?;
+            out.program.memory_size = cmp::max(out.program.memory_size, new_memory_size);
+        }
+        ENTRY => {
+            if node.args.is_empty() || node.args.len() > 2 {
+                return Err(ErrorKind::InvalidArgCount);
+            }
+            out.entry = Some(extract_and_eval_value(&node.args[0], symtab)?);
+            if let Some(arg) = node.args.get(1) {
+                out.program.initial_sp = Some(extract_and_eval_expr(arg, symtab)?);
+            }
+        }
+        GLOBAL | EXTERN => {}
+        SEG => {
+            let perms = match node.args.get(1) {
+                Some(Arg::Str(s)) => Perms::parse(s).ok_or(ErrorKind::InvalidPerms)?,
+                Some(_) => return Err(ErrorKind::InvalidPerms),
+                None => Perms::ALL,
+            };
+            out.program.segments.push(mem::replace(
+                segment,
+                Segment {
+                    addr: extract_and_eval_expr(&node.args[0], symtab)?,
+                    perms,
+                    ..Segment::new()
+                },
+            ));
+        }
+        RES | SPACE => {
+            let size = extract_and_eval_expr(&node.args[0], symtab)?;
+            let size = if inst == RES { size.wrapping_mul(4) } else { size };
+            segment.zero_size = segment.zero_size.wrapping_add(size);
+        }
+        D8 => {
+            for arg in &node.args {
+                match arg {
+                    Arg::Expr(expr) => {
+                        let value = match i8::try_from(eval_expr(expr, symtab)? as i32) {
+                            Ok(value) => value,
+                            Err(_) => return Err(ErrorKind::ConstantTooLarge),
+                        };
+                        segment.data.push(value as u8);
+                    }
+                    Arg::Str(s) => {
+                        segment.data.extend_from_slice(s);
+                    }
+                    _ => return Err(ErrorKind::InvalidArgument),
+                }
+            }
+        }
+        INCBIN => {
+            segment.data.extend_from_slice(incbin_data(&node.args, symtab)?);
+        }
+        D16 => {
+            for arg in &node.args {
+                let value = match i16::try_from(extract_and_eval_expr(arg, symtab)? as i32) {
+                    Ok(value) => value,
+                    Err(_) => return Err(ErrorKind::ConstantTooLarge),
+                };
+                segment.data.extend_from_slice(&i16::to_le_bytes(value));
+            }
+        }
+        D32 => {
+            for arg in &node.args {
+                let value = extract_and_eval_value(arg, symtab)?;
+                if value.base == Base::Abs {
+                    segment.data.extend_from_slice(&u32::to_le_bytes(value.offset));
+                } else {
+                    let offset = segment.data.len() as u32;
+                    out.add_reloc(base, offset, RelocKind::Abs32, value);
+                    segment.data.extend_from_slice(&[0; 4]);
+                }
+            }
+        }
+        STS8 | STS16 => {
+            let op = if inst == STS8 { opcode::STU8 } else { opcode::STU16 };
+            let info = isa::info_by_opcode(op).unwrap();
+            let operands = compile_operands(info, &node.args, symtab, base, addr, out)?;
+            emit(segment, op, operands);
+        }
+        BGT | BLE | BGTU | BLEU => {
+            let op = match inst {
+                BGT  => opcode::BLT,
+                BLE  => opcode::BGE,
+                BGTU => opcode::BLTU,
+                _    => opcode::BGEU,
+            };
+            let info = isa::info_by_opcode(op).unwrap();
+            let mut operands = compile_operands(info, &node.args, symtab, base, addr, out)?;
+            if let Operands::Rrc(ops) = &mut operands {
+                mem::swap(&mut ops.r1, &mut ops.r2);
+            }
+            emit(segment, op, operands);
+        }
+        JMP | CALL => {
+            check_arg_count(node.args.len(), 1)?;
+
+            let r1 = if inst == JMP { 0 } else { 1 };
+            let target = extract_and_eval_value(&node.args[0], symtab)?;
+            let imm = if target.base == base {
+                let imm = eval_branch_offset(target.offset, addr)?;
+                check_imm_fits(imm, 20).map_err(|_| ErrorKind::TargetTooFar)?;
+                imm
+            } else {
+                out.add_reloc(base, addr, RelocKind::Jal, target);
+                0
+            };
+
+            emit(segment, opcode::JAL, Operands::Rc(Rc { r1, imm: imm as i32 }));
+        }
+        RET => {
+            check_arg_count(node.args.len(), 0)?;
+
+            emit(segment, opcode::JALR, Operands::Rrc(Rrc { r1: 0, r2: 1, imm: 0 }));
+        }
+        MOV => {
+            check_arg_count(node.args.len(), 2)?;
+
+            let r1 = extract_reg(&node.args[0])?;
+            let r2 = extract_reg(&node.args[1])?;
+
+            emit(segment, opcode::ADDI, Operands::Rrc(Rrc { r1, r2, imm: 0 }));
+        }
+        _ => match inst_info(inst) {
+            Some(info) => {
+                let operands = compile_operands(info, &node.args, symtab, base, addr, out)?;
+                emit(segment, info.opcode, operands);
+            }
+            None => return Err(ErrorKind::UnknownInst),
+        },
+    }
+
+    Ok(())
+}
+
+fn compile_operands(
+    info: &InstInfo,
+    args: &[Arg],
+    symtab: &[Option<Value>],
+    base: Base,
+    addr: u32,
+    out: &mut Output,
+) -> Result<Operands, ErrorKind> {
+    let mut eval_imm = |arg: &Arg, bits: u32| {
+        let expr = match arg {
+            Arg::Expr(expr) => expr,
+            _ => return Err(ErrorKind::InvalidArgument),
+        };
+        match info.imm {
+            ImmKind::Upper => {
+                // `lui %r, label >> 12` loads the upper bits of an address.
+                if let [rest @ .., Expr::Int(12), Expr::Lshr | Expr::Ashr] = &expr[..] {
+                    let value = eval_value(rest, symtab)?;
+                    if value.base != Base::Abs {
+                        out.add_reloc(base, addr, RelocKind::Lui, value);
+                        return Ok(0);
+                    }
+                }
+                let value = eval_expr(expr, symtab)?;
+                if (value >> 20) != 0 {
+                    return Err(ErrorKind::ConstantTooLarge);
+                }
+                Ok(value as i32)
+            }
+            ImmKind::Offset => {
+                let target = eval_value(expr, symtab)?;
+                if target.base != base {
+                    let kind = if bits == 16 { RelocKind::Branch } else { RelocKind::Jal };
+                    out.add_reloc(base, addr, kind, target);
+                    return Ok(0);
+                }
+                let imm = eval_branch_offset(target.offset, addr)?;
+                check_imm_fits(imm, bits).map_err(|_| ErrorKind::TargetTooFar)?;
+                Ok(imm as i32)
+            }
+            _ => {
+                // `addi %r, %r, label & 0xFFF` adds the lower bits after `lui`.
+                if let [rest @ .., Expr::Int(0xFFF), Expr::And] = &expr[..] {
+                    let value = eval_value(rest, symtab)?;
+                    if value.base != Base::Abs && bits == 16 {
+                        out.add_reloc(base, addr, RelocKind::Low, value);
+                        return Ok(0);
+                    }
+                }
+                let value = eval_value(expr, symtab)?;
+                if value.base != Base::Abs && info.opcode == opcode::LI {
+                    out.add_reloc(base, addr, RelocKind::Li, value);
+                    return Ok(0);
+                }
+                let value = eval_expr(expr, symtab)?;
+                check_imm_fits(value, bits)?;
+                Ok(value as i32)
+            }
+        }
+    };
+
+    let operands = match info.format {
+        isa::Format::Rc => {
+            check_arg_count(args.len(), 2)?;
+            let r1 = extract_reg(&args[0])?;
+            Operands::Rc(Rc { r1, imm: eval_imm(&args[1], 20)? })
+        }
+        isa::Format::Rrc => {
+            check_arg_count(args.len(), 3)?;
+            let r1 = extract_reg(&args[0])?;
+            let r2 = extract_reg(&args[1])?;
+            Operands::Rrc(Rrc { r1, r2, imm: eval_imm(&args[2], 16)? })
+        }
+        isa::Format::Rrr => {
+            check_arg_count(args.len(), 3)?;
+            let r1 = extract_reg(&args[0])?;
+            let r2 = extract_reg(&args[1])?;
+            let r3 = extract_reg(&args[2])?;
+            Operands::Rrr(Rrr { r1, r2, r3 })
+        }
+        isa::Format::Rrrr => {
+            check_arg_count(args.len(), 4)?;
+            let r1 = extract_reg(&args[0])?;
+            let r2 = extract_reg(&args[1])?;
+            let r3 = extract_reg(&args[2])?;
+            let r4 = extract_reg(&args[3])?;
+            Operands::Rrrr(Rrrr { r1, r2, r3, r4 })
+        }
+    };
+    Ok(operands)
+}
+
+fn emit(segment: &mut Segment, opcode: u32, operands: Operands) {
+    let inst = Instruction::from_parts(opcode, operands).unwrap();
+    segment.data.extend_from_slice(&u32::to_le_bytes(inst.encode()));
+}
+
+pub fn eval_branch_offset(target: u32, addr: u32) -> Result<u32, ErrorKind> {
+    let offset = target.wrapping_sub(addr).wrapping_sub(4);
+    if offset & 3 == 0 {
+        Ok(((offset as i32) >> 2) as u32)
+    } else {
+        Err(ErrorKind::MisalignedOffset)
+    }
+}
+
+fn remove_empty_segments(segments: &mut Vec<Segment>) {
+    let mut i = 0;
+    while i < segments.len() {
+        if segments[i].is_empty() {
+            segments.swap_remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+pub fn check_imm_fits(imm: u32, bits: u32) -> Result<(), ErrorKind> {
+    let imm = imm as i32;
+    let shift = 32 - bits;
+    if (imm << shift) >> shift != imm {
+        Err(ErrorKind::ConstantTooLarge)
+    } else {
+        Ok(())
+    }
+}
+
+fn check_arg_count(got: usize, expected: usize) -> Result<(), ErrorKind> {
+    if got != expected {
+        Err(ErrorKind::InvalidArgCount)
+    } else {
+        Ok(())
+    }
+}
+
+fn extract_reg(arg: &Arg) -> Result<u8, ErrorKind> {
+    if let Arg::Reg(reg) = arg {
+        Ok(*reg as u8)
+    } else {
+        Err(ErrorKind::InvalidArgument)
+    }
+}
+
+fn extract_label(arg: &Arg) -> Result<SymbolId, ErrorKind> {
+    match arg {
+        Arg::Expr(expr) => match expr[..] {
+            [Expr::Label(sym)] => Ok(sym),
+            _ => Err(ErrorKind::InvalidArgument),
+        },
+        _ => Err(ErrorKind::InvalidArgument),
+    }
+}
+
+fn extract_and_eval_value(arg: &Arg, symtab: &[Option<Value>]) -> Result<Value, ErrorKind> {
+    if let Arg::Expr(expr) = arg {
+        eval_value(expr, symtab)
+    } else {
+        Err(ErrorKind::InvalidArgument)
+    }
+}
+
+fn extract_and_eval_expr(arg: &Arg, symtab: &[Option<Value>]) -> Result<u32, ErrorKind> {
+    if let Arg::Expr(expr) = arg {
+        eval_expr(expr, symtab)
+    } else {
+        Err(ErrorKind::InvalidArgument)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ErrorKind::*;
+        let msg = match self {
+            CircularDefinition(chain) => return write!(f, "circular definition: {}", chain),
+            UnknownInst      => "unknown instruction name",
+            InvalidArgument  => "invalid argument type",
+            InvalidArgCount  => "invalid argument count",
+            UndefinedSymbol  => "undefined symbol",
+            RedefinedSymbol  => "symbol redefined",
+            AddrOverflow     => "address overflow",
+            ConstantTooLarge => "constant is too large",
+            TargetTooFar     => "branch target is too far",
+            MisalignedOffset => "misaligned branch offset",
+            RedefinedEntry   => "entry point redefined",
+            InvalidPerms     => "invalid segment permissions",
+            NotRelocatable   => "expression is not relocatable",
+            ObjectOnly       => "directive is only allowed in object files",
+            NotInObject      => "directive is not allowed in object files",
+            InvalidRange     => "incbin range is outside the file",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for ErrorKind {}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorKind;
+    use crate::asm::{self, ErrorKind as AsmErrorKind, Program};
+
+    fn value(program: &Program, name: &str) -> u32 {
+        program.symbols.iter().find(|symbol| symbol.name == name).unwrap().value
+    }
+
+    fn compile_error(source: &str) -> ErrorKind {
+        match asm::assemble(source) {
+            Ok(_) => panic!("assembled"),
+            Err(asm::Error { kind: AsmErrorKind::Compile(kind), .. }) => kind,
+            Err(err) => panic!("parse error: {}", err.kind),
+        }
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = "
+TABLE_END = table + TABLE_SIZE * 4
+
+    seg     DATA
+table:
+    res     TABLE_SIZE
+after:
+    space   GAP
+    d32     TABLE_END
+end:
+
+DATA       = 0x2000
+TABLE_SIZE = GAP >> 1
+GAP        = 8
+";
+        let program = asm::assemble(source).unwrap();
+        assert_eq!(value(&program, "table"), 0x2000);
+        assert_eq!(value(&program, "TABLE_END"), 0x2010);
+        assert_eq!(value(&program, "after"), 0x2010);
+        assert_eq!(value(&program, "end"), 0x201C);
+        let segment = program.segments.iter().find(|segment| !segment.data.is_empty()).unwrap();
+        assert_eq!((segment.addr, &segment.data[..]), (0x2018, &0x2010_u32.to_le_bytes()[..]));
+
+        // Instructions may refer to labels below them.
+        let source = "seg 0x1000\n li %a0, end\n jmp end\n d32 end\nend:\n";
+        let program = asm::assemble(source).unwrap();
+        assert_eq!(program.segments[0].data[8..], 0x100C_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn circular_definitions() {
+        let chain = |source| match compile_error(source) {
+            ErrorKind::CircularDefinition(chain) => chain,
+            kind => panic!("unexpected error: {}", kind),
+        };
+        assert_eq!(chain("A = A + 1\n"), "A -> A");
+        assert_eq!(chain("A = B + 1\nB = C\nC = A * 2\n"), "A -> B -> C -> A");
+        assert_eq!(chain("X = 1\nA = B\nB = X + A\n"), "A -> B -> A");
+        assert_eq!(chain("seg 0x1000\n space SIZE\nend:\nSIZE = end - 0x1000\n"), "end -> SIZE -> end");
+
+        assert!(matches!(compile_error("A = B\n"), ErrorKind::UndefinedSymbol));
+    }
+}
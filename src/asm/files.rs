@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+// Reads the files named by `include` and `incbin`.
+pub trait FileSystem {
+    // Finds `name` referred to from the file `from`; returns the name to report
+    // in errors and the line table, and the contents.
+    fn read(&self, name: &str, from: &str) -> io::Result<(String, Vec<u8>)>;
+}
+
+// Looks for files next to the file that refers to them, then in `dirs`.
+#[derive(Clone, Debug, Default)]
+pub struct SearchPaths {
+    pub dirs: Vec<PathBuf>,
+}
+
+impl FileSystem for SearchPaths {
+    fn read(&self, name: &str, from: &str) -> io::Result<(String, Vec<u8>)> {
+        let dir = Path::new(from).parent().unwrap_or(Path::new(""));
+        let mut first_err = None;
+        for dir in iter::once(dir).chain(self.dirs.iter().map(PathBuf::as_path)) {
+            let path: PathBuf = dir.join(name).components().collect();
+            match fs::read(&path) {
+                Ok(data) => return Ok((path.display().to_string(), data)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    first_err.get_or_insert(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(first_err.unwrap())
+    }
+}
+
+// Files kept in memory, by name.
+impl FileSystem for HashMap<String, Vec<u8>> {
+    fn read(&self, name: &str, _from: &str) -> io::Result<(String, Vec<u8>)> {
+        match self.get(name) {
+            Some(data) => Ok((name.to_owned(), data.clone())),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
@@ -8,6 +8,7 @@ pub struct Symbol {
 #[derive(Default)]
 pub struct IdentTable {
     str_to_id: HashMap<Box<str>, u32>,
+    id_to_str: Vec<Box<str>>,
 }
 
 impl IdentTable {
@@ -22,10 +23,15 @@ impl IdentTable {
 
         let id = u32::try_from(self.str_to_id.len()).unwrap();
         self.str_to_id.insert(Box::from(key), id);
+        self.id_to_str.push(Box::from(key));
 
         Symbol { id }
     }
 
+    pub fn name(&self, sym: Symbol) -> &str {
+        &self.id_to_str[sym.id as usize]
+    }
+
     pub fn len(&self) -> usize {
         self.str_to_id.len()
     }
@@ -0,0 +1,99 @@
+use crate::isa::{self, InstInfo};
+
+use super::id_table::{IdentTable, Symbol};
+
+pub const MEM:   Symbol = Symbol { id: 0 };
+pub const SEG:   Symbol = Symbol { id: 1 };
+
+pub const D8:    Symbol = Symbol { id: 2 };
+pub const D16:   Symbol = Symbol { id: 3 };
+pub const D32:   Symbol = Symbol { id: 4 };
+
+pub const ENTRY: Symbol = Symbol { id: 5 };
+
+pub const RES:   Symbol = Symbol { id: 6 };
+pub const SPACE: Symbol = Symbol { id: 7 };
+
+pub const SECTION: Symbol = Symbol { id: 8 };
+pub const GLOBAL:  Symbol = Symbol { id: 9 };
+pub const EXTERN:  Symbol = Symbol { id: 10 };
+
+pub const MACRO:   Symbol = Symbol { id: 11 };
+pub const ENDM:    Symbol = Symbol { id: 12 };
+
+pub const INCLUDE: Symbol = Symbol { id: 13 };
+pub const INCBIN:  Symbol = Symbol { id: 14 };
+
+pub const IF:      Symbol = Symbol { id: 15 };
+pub const ELIF:    Symbol = Symbol { id: 16 };
+pub const ELSE:    Symbol = Symbol { id: 17 };
+pub const ENDIF:   Symbol = Symbol { id: 18 };
+pub const IFDEF:   Symbol = Symbol { id: 19 };
+pub const IFNDEF:  Symbol = Symbol { id: 20 };
+
+// Machine instructions follow in the order of `isa::INSTRUCTIONS`.
+const FIRST_INST:   u32 = 21;
+const FIRST_PSEUDO: u32 = FIRST_INST + isa::INSTRUCTIONS.len() as u32;
+
+pub const STS8:  Symbol = Symbol { id: FIRST_PSEUDO };
+pub const STS16: Symbol = Symbol { id: FIRST_PSEUDO + 1 };
+pub const BGT:   Symbol = Symbol { id: FIRST_PSEUDO + 2 };
+pub const BLE:   Symbol = Symbol { id: FIRST_PSEUDO + 3 };
+pub const BGTU:  Symbol = Symbol { id: FIRST_PSEUDO + 4 };
+pub const BLEU:  Symbol = Symbol { id: FIRST_PSEUDO + 5 };
+pub const JMP:   Symbol = Symbol { id: FIRST_PSEUDO + 6 };
+pub const CALL:  Symbol = Symbol { id: FIRST_PSEUDO + 7 };
+pub const RET:   Symbol = Symbol { id: FIRST_PSEUDO + 8 };
+pub const MOV:   Symbol = Symbol { id: FIRST_PSEUDO + 9 };
+
+pub fn inst_info(sym: Symbol) -> Option<&'static InstInfo> {
+    let index = sym.id.checked_sub(FIRST_INST)?;
+    isa::INSTRUCTIONS.get(index as usize)
+}
+
+pub fn is_builtin(sym: Symbol) -> bool {
+    sym.id <= MOV.id
+}
+
+pub fn make_proper_id_table() -> IdentTable {
+    let mut id_table = IdentTable::new();
+
+    id_table.insert("mem");
+    id_table.insert("seg");
+    id_table.insert("d8");
+    id_table.insert("d16");
+    id_table.insert("d32");
+    id_table.insert("entry");
+    id_table.insert("res");
+    id_table.insert("space");
+    id_table.insert("section");
+    id_table.insert("global");
+    id_table.insert("extern");
+    id_table.insert("macro");
+    id_table.insert("endm");
+    id_table.insert("include");
+    id_table.insert("incbin");
+    id_table.insert("if");
+    id_table.insert("elif");
+    id_table.insert("else");
+    id_table.insert("endif");
+    id_table.insert("ifdef");
+    id_table.insert("ifndef");
+
+    for info in isa::INSTRUCTIONS {
+        id_table.insert(info.mnemonic);
+    }
+
+    id_table.insert("st.s8");
+    id_table.insert("st.s16");
+    id_table.insert("bgt");
+    id_table.insert("ble");
+    id_table.insert("bgtu");
+    id_table.insert("bleu");
+    id_table.insert("jmp");
+    id_table.insert("call");
+    id_table.insert("ret");
+    id_table.insert("mov");
+
+    id_table
+}
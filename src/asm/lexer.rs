@@ -198,6 +198,7 @@ unsafe fn scan_token<'a>(mut start: *const u8, end: *const u8) -> (Token<'a>, *c
     }
 }
 
+#[allow(clippy::match_overlapping_arm)]
 unsafe fn decode_char(ptr: *const u8) -> (char, *const u8) {
     let byte = *ptr;
     match byte {
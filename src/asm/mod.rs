@@ -0,0 +1,179 @@
+mod ast;
+mod builder;
+mod compiler;
+mod files;
+mod id_table;
+mod inst_syms;
+mod lexer;
+mod parser;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::sync::LazyLock;
+
+use crate::object::Object;
+
+use self::ast::Source;
+use self::compiler::{compile, compile_object};
+use self::inst_syms::{is_builtin, make_proper_id_table};
+use self::lexer::{Lexer, Token};
+use self::parser::parse;
+
+pub use crate::binfile::SymbolKind;
+
+pub use self::builder::{CodeBuilder, Error as BuildError, Label};
+pub use self::compiler::{ErrorKind as CompileError, LineEntry, Program, Segment, Symbol};
+pub use self::files::{FileSystem, SearchPaths};
+pub use self::lexer::Error as LexerError;
+pub use self::parser::ErrorKind as ParseError;
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub file: String,
+    pub line: u32,
+    // Macro calls the line was expanded from, innermost first.
+    pub calls: Vec<MacroCall>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MacroCall {
+    pub name: String,
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErrorKind {
+    Parse(ParseError),
+    Compile(CompileError),
+}
+
+// Settings of `assemble_with` and `assemble_object_with`.
+#[derive(Clone, Copy)]
+pub struct Options<'a> {
+    // Where `include` and `incbin` read files from.
+    pub file_system: &'a dyn FileSystem,
+    // Constants defined before the first line, as if by `NAME = value`.
+    pub defines: &'a [(String, u32)],
+}
+
+static NO_FILES: LazyLock<HashMap<String, Vec<u8>>> = LazyLock::new(HashMap::new);
+
+static NO_SEARCH_PATHS: SearchPaths = SearchPaths { dirs: Vec::new() };
+
+// No files can be included by default.
+impl Default for Options<'_> {
+    fn default() -> Self {
+        Options { file_system: &*NO_FILES, defines: &[] }
+    }
+}
+
+impl Source {
+    fn parse(file_name: &str, text: &str, options: &Options) -> Result<Source, Error> {
+        let mut source = Source { id_table: make_proper_id_table(), ..Default::default() };
+        match parse(file_name, text, options.file_system, options.defines, &mut source) {
+            Ok(()) => Ok(source),
+            Err(err) => Err(source.error(ErrorKind::Parse(err.kind), err.file, err.line, err.expansion)),
+        }
+    }
+
+    fn error(&self, kind: ErrorKind, file: u32, line: u32, mut expansion: Option<u32>) -> Error {
+        let mut calls = Vec::new();
+        while let Some(index) = expansion {
+            let call = &self.expansions[index as usize];
+            calls.push(MacroCall {
+                name: self.id_table.name(call.name).to_owned(),
+                file: self.files[call.file as usize].clone(),
+                line: call.line,
+            });
+            expansion = call.parent;
+        }
+        Error { kind, file: self.files[file as usize].clone(), line, calls }
+    }
+
+    fn compile_error(&self, err: compiler::Error) -> Error {
+        self.error(ErrorKind::Compile(err.kind), err.file, err.line, err.expansion)
+    }
+}
+
+// Checks that `name` can be defined by a program: it is an identifier and not
+// a register or a builtin name.
+pub fn is_symbol_name(name: &str) -> bool {
+    let mut lexer = Lexer::new(name);
+    match (lexer.next(), lexer.next()) {
+        (Token::Ident(s), Token::Eof) if s == name => !is_builtin(make_proper_id_table().insert(name)),
+        _ => false,
+    }
+}
+
+// Assembles a program from source text; stops at the first error. Files
+// cannot be included.
+pub fn assemble(source: &str) -> Result<Program, Error> {
+    assemble_with("<source>", source, &Options::default())
+}
+
+// Same as `assemble`, but the line table refers to `file_name` and included
+// files are read from the disk next to it.
+pub fn assemble_file(file_name: &str, source: &str) -> Result<Program, Error> {
+    let options = Options { file_system: &NO_SEARCH_PATHS, ..Default::default() };
+    assemble_with(file_name, source, &options)
+}
+
+// Same as `assemble_file` with the given options.
+pub fn assemble_with(file_name: &str, source: &str, options: &Options) -> Result<Program, Error> {
+    let source = Source::parse(file_name, source, options)?;
+    let mut program = compile(&source.ast, &source.id_table).map_err(|err| source.compile_error(err))?;
+    program.files = source.files;
+    Ok(program)
+}
+
+// Assembles a relocatable object file to be combined with others by `link`;
+// included files are read from the disk next to `file_name`.
+pub fn assemble_object(file_name: &str, source: &str) -> Result<Object, Error> {
+    let options = Options { file_system: &NO_SEARCH_PATHS, ..Default::default() };
+    assemble_object_with(file_name, source, &options)
+}
+
+// Same as `assemble_object` with the given options.
+pub fn assemble_object_with(file_name: &str, source: &str, options: &Options) -> Result<Object, Error> {
+    let source = Source::parse(file_name, source, options)?;
+    let mut object = compile_object(&source.ast, &source.id_table).map_err(|err| source.compile_error(err))?;
+    object.files = source.files;
+    Ok(object)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Parse(err) => err.fmt(f),
+            ErrorKind::Compile(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ErrorKind {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_names() {
+        for name in ["DEBUG", "_x", ".local", "a.b_1", "r1", "x0"] {
+            assert!(is_symbol_name(name), "{}", name);
+        }
+        for name in ["", "1x", "=5", "a b", "a:", "%sp", "addi", "mem", "ifdef", "\"s\"", "a@1"] {
+            assert!(!is_symbol_name(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,1182 @@
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::mem;
+
+use smallvec::{Array, SmallVec};
+
+use super::ast::*;
+use super::compiler::{eval_expr, Value};
+use super::files::FileSystem;
+use super::id_table::{IdentTable, Symbol};
+use super::inst_syms::*;
+use super::lexer::{self, Lexer, Token};
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub file: u32,
+    pub line: u32,
+    pub expansion: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErrorKind {
+    JunkInLine,
+    MissingRegName,
+    InvalidRegName,
+    InvalidIntLiteral,
+    MissingClosingParen,
+    ExpectedExpr,
+    LexerError(lexer::Error),
+
+    InvalidMacroName,
+    InvalidMacroParam,
+    RedefinedMacro,
+    NestedMacro,
+    UnterminatedMacro,
+    UnexpectedEndm,
+    InvalidMacroArgCount,
+    InvalidMacroArg,
+    MacroTooDeep,
+
+    ExpectedFileName,
+    ReadError(String, String),
+    IncludeCycle,
+    IncludeTooDeep,
+    IncludeInMacro,
+
+    InvalidCondition,
+    NotConstant,
+    UnexpectedCond,
+    CondAfterElse,
+    UnterminatedIf,
+}
+
+const MAX_MACRO_DEPTH: u32 = 64;
+
+struct Macro {
+    params: Vec<Symbol>,
+    body: Vec<Node>,
+}
+
+// A macro being defined.
+struct Definition {
+    name: Symbol,
+    def: Macro,
+    // Line of the `macro` directive.
+    line: u32,
+    // Definitions in skipped conditional blocks are dropped.
+    active: bool,
+}
+
+// An `if` block being assembled.
+struct Cond {
+    // Lines of the current branch are assembled.
+    active: bool,
+    // No later branch is taken: one was taken already or the block is skipped.
+    done: bool,
+    has_else: bool,
+    file: u32,
+    line: u32,
+    expansion: Option<u32>,
+}
+
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    source: &'a mut Source,
+    file_system: &'a dyn FileSystem,
+    macros: HashMap<u32, Macro>,
+    // Files being parsed, the innermost last.
+    includes: Vec<u32>,
+    conds: Vec<Cond>,
+    // Blocks of the current file or macro expansion start at this index of
+    // `conds`; they must end there too.
+    cond_base: usize,
+    // Symbols defined so far and the values of constants among them; used
+    // in conditions.
+    defined: HashSet<u32>,
+    constants: Vec<Option<Value>>,
+    // Symbols defined by `defines`; assignments to them in the source are
+    // dropped.
+    overrides: HashSet<u32>,
+}
+
+// Parses `text` of the file `file_name` and the files it includes. `defines`
+// are assigned before the first line and replace assignments in the source.
+pub fn parse(
+    file_name: &str,
+    text: &str,
+    file_system: &dyn FileSystem,
+    defines: &[(String, u32)],
+    source: &mut Source,
+) -> Result<(), Error> {
+    let file = source.files.len() as u32;
+    source.files.push(file_name.to_owned());
+    let mut parser = Parser {
+        source,
+        file_system,
+        macros: HashMap::new(),
+        includes: vec![file],
+        conds: Vec::new(),
+        cond_base: 0,
+        defined: HashSet::new(),
+        constants: Vec::new(),
+        overrides: HashSet::new(),
+    };
+
+    for (i, (name, value)) in defines.iter().enumerate() {
+        // The last definition of a name wins.
+        if defines[i + 1..].iter().any(|(other, _)| other == name) {
+            continue;
+        }
+        let sym = parser.source.id_table.insert(name);
+        let mut args = SmallVec::new();
+        args.push(Arg::Expr(SmallVec::from_slice(&[Expr::Int(*value)])));
+        parser.push(Node { kind: NodeKind::Assign(sym), args, file, line: 0, expansion: None });
+        parser.overrides.insert(sym.id);
+    }
+    parser.parse_file(text, file)
+}
+
+// Parses `name [param [, param]*]` after `macro`.
+fn parse_macro_header(
+    lexer: &mut Lexer,
+    id_table: &mut IdentTable,
+) -> Result<(Symbol, Vec<Symbol>), ErrorKind> {
+    let name = match lexer.next() {
+        Token::Ident(s) => id_table.insert(s),
+        _ => return Err(ErrorKind::InvalidMacroName),
+    };
+    if is_builtin(name) {
+        return Err(ErrorKind::InvalidMacroName);
+    }
+
+    let mut params = Vec::new();
+    if matches!(lexer.peek(), Token::Eof | Token::Eol) {
+        return Ok((name, params));
+    }
+    loop {
+        let param = match lexer.next() {
+            Token::Ident(s) => id_table.insert(s),
+            _ => return Err(ErrorKind::InvalidMacroParam),
+        };
+        if is_builtin(param) || params.contains(&param) {
+            return Err(ErrorKind::InvalidMacroParam);
+        }
+        params.push(param);
+
+        if lexer.peek() != Token::Comma {
+            return Ok((name, params));
+        }
+        lexer.next();
+    }
+}
+
+impl Parser<'_> {
+    fn parse_file(&mut self, text: &str, file: u32) -> Result<(), Error> {
+        let mut lexer = Lexer::new(text);
+        let mut line = 1;
+        let mut definition: Option<Definition> = None;
+        let cond_base = mem::replace(&mut self.cond_base, self.conds.len());
+
+        macro_rules! err {
+            ($e:expr) => {
+                Err(Error { kind: $e, file, line, expansion: None })
+            };
+        }
+
+        loop {
+            let mut nodes: SmallVec<[Node; 2]> = SmallVec::new();
+
+            if let Token::Label(s) = lexer.peek() {
+                lexer.next();
+
+                nodes.push(Node {
+                    kind: NodeKind::Label(self.source.id_table.insert(s)),
+                    args: SmallVec::new(),
+                    file,
+                    line,
+                    expansion: None,
+                });
+            }
+
+            if let Token::Ident(s) = lexer.peek() {
+                lexer.next();
+
+                let sym = self.source.id_table.insert(s);
+
+                if sym == MACRO {
+                    if definition.is_some() {
+                        return err!(ErrorKind::NestedMacro);
+                    }
+                    match parse_macro_header(&mut lexer, &mut self.source.id_table) {
+                        // A definition in a skipped block may repeat an earlier one.
+                        Ok((name, _)) if self.active() && self.macros.contains_key(&name.id) => {
+                            return err!(ErrorKind::RedefinedMacro);
+                        }
+                        Ok((name, params)) => {
+                            let def = Macro { params, body: Vec::new() };
+                            definition = Some(Definition { name, def, line, active: self.active() });
+                        }
+                        Err(err) => return err!(err),
+                    }
+                } else if sym == INCLUDE {
+                    if definition.is_some() {
+                        return err!(ErrorKind::IncludeInMacro);
+                    }
+                    let name = match lexer.next() {
+                        Token::Str(name) => name,
+                        _ => return err!(ErrorKind::ExpectedFileName),
+                    };
+                    // Nodes before `include` on the same line go first.
+                    for node in nodes.drain(..) {
+                        self.emit(node, 0)?;
+                    }
+                    if !self.active() {
+                        continue;
+                    }
+                    match self.include(name, file) {
+                        Ok(()) => {}
+                        Err(IncludeError::Nested(err)) => return Err(err),
+                        Err(IncludeError::Kind(err)) => return err!(err),
+                    }
+                } else {
+                    let kind = if lexer.peek() == Token::Equal {
+                        lexer.next();
+                        NodeKind::Assign(sym)
+                    } else {
+                        NodeKind::Inst(sym)
+                    };
+
+                    let mut args = SmallVec::new();
+                    match parse_args(&mut lexer, &mut self.source.id_table, &mut args) {
+                        Ok(()) => {}
+                        Err(err) => return err!(err),
+                    }
+                    let active = definition.as_ref().map_or(self.active(), |definition| definition.active);
+                    if sym == INCBIN && active {
+                        match self.read_incbin(&mut args, file) {
+                            Ok(()) => {}
+                            Err(err) => return err!(err),
+                        }
+                    }
+                    nodes.push(Node { kind, args, file, line, expansion: None });
+                }
+            }
+
+            for node in nodes {
+                if let NodeKind::Inst(ENDM) = node.kind {
+                    match definition.take() {
+                        Some(definition) if definition.active => {
+                            self.macros.insert(definition.name.id, definition.def);
+                        }
+                        Some(_) => {}
+                        None => return err!(ErrorKind::UnexpectedEndm),
+                    }
+                } else if let Some(definition) = &mut definition {
+                    definition.def.body.push(node);
+                } else {
+                    self.emit(node, 0)?;
+                }
+            }
+
+            match lexer.next() {
+                Token::Eol => {}
+                Token::Eof => break,
+                Token::Err(err) => return err!(ErrorKind::LexerError(err)),
+                _ => return err!(ErrorKind::JunkInLine),
+            }
+            line += 1;
+        }
+
+        if let Some(Definition { line, .. }) = definition {
+            return Err(Error { kind: ErrorKind::UnterminatedMacro, file, line, expansion: None });
+        }
+        self.end_conds()?;
+        self.cond_base = cond_base;
+        Ok(())
+    }
+
+    fn read(&self, name: &str, from: u32) -> Result<(String, Vec<u8>), ErrorKind> {
+        match self.file_system.read(name, &self.source.files[from as usize]) {
+            Ok(result) => Ok(result),
+            Err(err) => Err(ErrorKind::ReadError(name.to_owned(), err.to_string())),
+        }
+    }
+
+    fn include(&mut self, name: &str, from: u32) -> Result<(), IncludeError> {
+        let (path, data) = self.read(name, from)?;
+        let text = match String::from_utf8(data) {
+            Ok(text) => text,
+            Err(err) => return Err(ErrorKind::ReadError(name.to_owned(), err.to_string()).into()),
+        };
+
+        let file = match self.source.files.iter().position(|file| *file == path) {
+            Some(index) => index as u32,
+            None => {
+                self.source.files.push(path);
+                (self.source.files.len() - 1) as u32
+            }
+        };
+        if self.includes.contains(&file) {
+            return Err(ErrorKind::IncludeCycle.into());
+        }
+        if self.includes.len() == MAX_INCLUDE_DEPTH {
+            return Err(ErrorKind::IncludeTooDeep.into());
+        }
+
+        self.includes.push(file);
+        let result = self.parse_file(&text, file);
+        self.includes.pop();
+        result.map_err(IncludeError::Nested)
+    }
+
+    // Replaces the file name of `incbin "file" [, offset [, size]]` with the
+    // contents of the file.
+    fn read_incbin(&self, args: &mut SmallVec<[Arg; 3]>, from: u32) -> Result<(), ErrorKind> {
+        let Some(Arg::Str(name)) = args.first() else {
+            return Err(ErrorKind::ExpectedFileName);
+        };
+        let name = String::from_utf8_lossy(name).into_owned();
+        let (_, data) = self.read(&name, from)?;
+        args[0] = Arg::Str(SmallVec::from_vec(data));
+        Ok(())
+    }
+
+    // Appends `node` to the AST unless it is in a skipped conditional block;
+    // macro calls are replaced with the macro body.
+    fn emit(&mut self, node: Node, depth: u32) -> Result<(), Error> {
+        if let NodeKind::Inst(IF | ELIF | ELSE | ENDIF | IFDEF | IFNDEF) = node.kind {
+            return self.emit_cond(&node);
+        }
+        if !self.active() {
+            return Ok(());
+        }
+
+        let NodeKind::Inst(name) = node.kind else {
+            self.push(node);
+            return Ok(());
+        };
+        let Some(def) = self.macros.get(&name.id) else {
+            self.push(node);
+            return Ok(());
+        };
+
+        let call_err = |kind| {
+            Err(Error { kind, file: node.file, line: node.line, expansion: node.expansion })
+        };
+        if depth == MAX_MACRO_DEPTH {
+            return call_err(ErrorKind::MacroTooDeep);
+        }
+        if node.args.len() != def.params.len() {
+            return call_err(ErrorKind::InvalidMacroArgCount);
+        }
+
+        let expansions = &mut self.source.expansions;
+        let expansion = expansions.len() as u32;
+        expansions.push(Expansion { name, file: node.file, line: node.line, parent: node.expansion });
+
+        // Symbols defined in the body get a new name in every expansion.
+        let id_table = &mut self.source.id_table;
+        let mut locals = HashMap::new();
+        for body_node in &def.body {
+            let (NodeKind::Label(sym) | NodeKind::Assign(sym)) = body_node.kind else { continue };
+            if !def.params.contains(&sym) && !locals.contains_key(&sym.id) {
+                let local = format!("{}@{}", id_table.name(sym), expansion);
+                locals.insert(sym.id, id_table.insert(&local));
+            }
+        }
+
+        let mut nodes = Vec::with_capacity(def.body.len());
+        for body_node in &def.body {
+            let (file, line) = (body_node.file, body_node.line);
+            match expand_node(body_node, def, &node, &locals) {
+                Ok((kind, args)) => nodes.push(Node { kind, args, file, line, expansion: Some(expansion) }),
+                Err(kind) => return Err(Error { kind, file, line, expansion: Some(expansion) }),
+            }
+        }
+
+        let cond_base = mem::replace(&mut self.cond_base, self.conds.len());
+        for node in nodes {
+            self.emit(node, depth + 1)?;
+        }
+        self.end_conds()?;
+        self.cond_base = cond_base;
+        Ok(())
+    }
+
+    fn push(&mut self, node: Node) {
+        match node.kind {
+            NodeKind::Label(sym) => {
+                self.defined.insert(sym.id);
+            }
+            NodeKind::Assign(sym) => {
+                if self.overrides.contains(&sym.id) {
+                    return;
+                }
+                self.defined.insert(sym.id);
+                if let [Arg::Expr(expr)] = &node.args[..] {
+                    self.constants.resize(self.source.id_table.len(), None);
+                    if let Ok(value) = eval_expr(expr, &self.constants) {
+                        self.constants[sym.id as usize].get_or_insert(Value::abs(value));
+                    }
+                }
+            }
+            NodeKind::Inst(EXTERN) => {
+                for arg in &node.args {
+                    if let Arg::Expr(expr) = arg {
+                        if let [Expr::Label(sym)] = expr[..] {
+                            self.defined.insert(sym.id);
+                        }
+                    }
+                }
+            }
+            NodeKind::Inst(_) => {}
+        }
+        self.source.ast.push(node);
+    }
+
+    fn active(&self) -> bool {
+        self.conds.last().is_none_or(|cond| cond.active)
+    }
+
+    fn emit_cond(&mut self, node: &Node) -> Result<(), Error> {
+        let err = |kind| Err(Error { kind, file: node.file, line: node.line, expansion: node.expansion });
+        let NodeKind::Inst(inst) = node.kind else { unreachable!() };
+
+        if let IF | IFDEF | IFNDEF = inst {
+            // Nothing is evaluated inside a skipped block.
+            let taken = if self.active() {
+                match self.eval_cond(inst, &node.args) {
+                    Ok(taken) => taken,
+                    Err(kind) => return err(kind),
+                }
+            } else {
+                false
+            };
+            self.conds.push(Cond {
+                active: taken,
+                done: taken || !self.active(),
+                has_else: false,
+                file: node.file,
+                line: node.line,
+                expansion: node.expansion,
+            });
+            return Ok(());
+        }
+
+        if self.conds.len() == self.cond_base {
+            return err(ErrorKind::UnexpectedCond);
+        }
+        let cond = self.conds.last().unwrap();
+        if inst != ENDIF && cond.has_else {
+            return err(ErrorKind::CondAfterElse);
+        }
+        if inst != ELIF && !node.args.is_empty() {
+            return err(ErrorKind::InvalidCondition);
+        }
+
+        let done = cond.done;
+        let taken = match inst {
+            ELIF if !done => match self.eval_cond(inst, &node.args) {
+                Ok(taken) => taken,
+                Err(kind) => return err(kind),
+            },
+            ELSE => !done,
+            _ => false,
+        };
+        if inst == ENDIF {
+            self.conds.pop();
+        } else {
+            let cond = self.conds.last_mut().unwrap();
+            cond.active = taken;
+            cond.done = done || taken;
+            cond.has_else = inst == ELSE;
+        }
+        Ok(())
+    }
+
+    // `ifdef name`, `ifndef name`, `if expr` or `elif expr`; expressions may
+    // only use constants defined above.
+    fn eval_cond(&mut self, inst: Symbol, args: &[Arg]) -> Result<bool, ErrorKind> {
+        let [Arg::Expr(expr)] = args else {
+            return Err(ErrorKind::InvalidCondition);
+        };
+        match inst {
+            IFDEF | IFNDEF => match expr[..] {
+                [Expr::Label(sym)] => Ok(self.defined.contains(&sym.id) == (inst == IFDEF)),
+                _ => Err(ErrorKind::InvalidCondition),
+            },
+            _ => {
+                self.constants.resize(self.source.id_table.len(), None);
+                match eval_expr(expr, &self.constants) {
+                    Ok(value) => Ok(value != 0),
+                    Err(_) => Err(ErrorKind::NotConstant),
+                }
+            }
+        }
+    }
+
+    // Reports the first `if` of the current file or macro expansion without
+    // `endif`.
+    fn end_conds(&self) -> Result<(), Error> {
+        match self.conds.get(self.cond_base) {
+            Some(cond) => Err(Error {
+                kind: ErrorKind::UnterminatedIf,
+                file: cond.file,
+                line: cond.line,
+                expansion: cond.expansion,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Errors of an included file are reported where they are.
+enum IncludeError {
+    Nested(Error),
+    Kind(ErrorKind),
+}
+
+impl From<ErrorKind> for IncludeError {
+    fn from(err: ErrorKind) -> Self {
+        IncludeError::Kind(err)
+    }
+}
+
+fn expand_node(
+    body_node: &Node,
+    def: &Macro,
+    call: &Node,
+    locals: &HashMap<u32, Symbol>,
+) -> Result<(NodeKind, SmallVec<[Arg; 3]>), ErrorKind> {
+    let kind = match body_node.kind {
+        NodeKind::Label(sym) => NodeKind::Label(substitute_name(sym, def, call, locals)?),
+        NodeKind::Assign(sym) => NodeKind::Assign(substitute_name(sym, def, call, locals)?),
+        NodeKind::Inst(sym) => NodeKind::Inst(substitute_name(sym, def, call, locals)?),
+    };
+    let mut args = SmallVec::new();
+    for arg in &body_node.args {
+        args.push(substitute_arg(arg, def, call, locals)?);
+    }
+    Ok((kind, args))
+}
+
+// A parameter used as a name must be given a plain identifier.
+fn substitute_name(
+    sym: Symbol,
+    def: &Macro,
+    call: &Node,
+    locals: &HashMap<u32, Symbol>,
+) -> Result<Symbol, ErrorKind> {
+    match def.params.iter().position(|&param| param == sym) {
+        Some(index) => match &call.args[index] {
+            Arg::Expr(expr) => match expr[..] {
+                [Expr::Label(name)] => Ok(name),
+                _ => Err(ErrorKind::InvalidMacroArg),
+            },
+            _ => Err(ErrorKind::InvalidMacroArg),
+        },
+        None => Ok(locals.get(&sym.id).copied().unwrap_or(sym)),
+    }
+}
+
+// A parameter that makes up a whole argument may be given anything; inside
+// an expression it must be given an expression.
+fn substitute_arg(
+    arg: &Arg,
+    def: &Macro,
+    call: &Node,
+    locals: &HashMap<u32, Symbol>,
+) -> Result<Arg, ErrorKind> {
+    let Arg::Expr(expr) = arg else {
+        return Ok(arg.clone());
+    };
+    if let [Expr::Label(sym)] = expr[..] {
+        if let Some(index) = def.params.iter().position(|&param| param == sym) {
+            return Ok(call.args[index].clone());
+        }
+    }
+
+    let mut new_expr = SmallVec::with_capacity(expr.len());
+    for &item in expr {
+        let Expr::Label(sym) = item else {
+            new_expr.push(item);
+            continue;
+        };
+        match def.params.iter().position(|&param| param == sym) {
+            Some(index) => match &call.args[index] {
+                Arg::Expr(arg_expr) => new_expr.extend_from_slice(arg_expr),
+                _ => return Err(ErrorKind::InvalidMacroArg),
+            },
+            None => new_expr.push(Expr::Label(locals.get(&sym.id).copied().unwrap_or(sym))),
+        }
+    }
+    Ok(Arg::Expr(new_expr))
+}
+
+fn parse_args<A>(
+    lexer: &mut Lexer,
+    id_table: &mut IdentTable,
+    args: &mut SmallVec<A>,
+) -> Result<(), ErrorKind>
+where
+    A: Array<Item = Arg>,
+{
+    if matches!(lexer.peek(), Token::Eof | Token::Eol) {
+        return Ok(());
+    }
+
+    loop {
+        args.push(match lexer.peek() {
+            Token::Reg(s) => {
+                lexer.next();
+                Arg::Reg(parse_reg(s)?)
+            }
+            Token::Str(s) => {
+                lexer.next();
+                Arg::Str(SmallVec::from_slice(s.as_bytes()))
+            }
+            _ => {
+                let mut expr = SmallVec::new();
+                parse_expr(lexer, id_table, &mut expr)?;
+                Arg::Expr(expr)
+            }
+        });
+
+        if lexer.peek() != Token::Comma {
+            return Ok(());
+        }
+        lexer.next();
+    }
+}
+
+fn parse_expr<A>(
+    lexer: &mut Lexer,
+    id_table: &mut IdentTable,
+    expr: &mut SmallVec<A>,
+) -> Result<(), ErrorKind>
+where
+    A: Array<Item = Expr>,
+{
+    parse_primary_expr(lexer, id_table, expr)?;
+    if precedence(lexer.peek()) > 0 {
+        parse_expr_helper(lexer, id_table, expr, 0)?;
+    }
+    Ok(())
+}
+
+fn parse_expr_helper<A>(
+    lexer: &mut Lexer,
+    id_table: &mut IdentTable,
+    expr: &mut SmallVec<A>,
+    min_prec: u32,
+) -> Result<(), ErrorKind>
+where
+    A: Array<Item = Expr>,
+{
+    let mut lookahead = lexer.peek();
+    loop {
+        let op = lookahead;
+        let max_prec = precedence(op);
+
+        lexer.next();
+        parse_primary_expr(lexer, id_table, expr)?;
+        lookahead = lexer.peek();
+
+        while precedence(lookahead) > max_prec {
+            parse_expr_helper(lexer, id_table, expr, max_prec)?;
+            lookahead = lexer.peek();
+        }
+
+        expr.push(token_to_binop(op));
+
+        if precedence(lookahead) <= min_prec {
+            return Ok(());
+        }
+    }
+}
+
+fn parse_primary_expr<A>(
+    lexer: &mut Lexer,
+    id_table: &mut IdentTable,
+    expr: &mut SmallVec<A>,
+) -> Result<(), ErrorKind>
+where
+    A: Array<Item = Expr>,
+{
+    match lexer.peek() {
+        Token::Lparen => {
+            lexer.next();
+            parse_expr(lexer, id_table, expr)?;
+            if lexer.next() != Token::Rparen {
+                return Err(ErrorKind::MissingClosingParen);
+            }
+            Ok(())
+        }
+        t @ (Token::Add | Token::Sub | Token::Xor) => {
+            lexer.next();
+
+            match t {
+                Token::Add => {}
+                Token::Sub => expr.push(Expr::Int(0)),
+                Token::Xor => expr.push(Expr::Int(!0)),
+                _ => unreachable!(),
+            }
+
+            parse_primary_expr(lexer, id_table, expr)?;
+
+            match t {
+                Token::Add => {}
+                Token::Sub => expr.push(Expr::Sub),
+                Token::Xor => expr.push(Expr::Xor),
+                _ => unreachable!(),
+            }
+
+            Ok(())
+        }
+        Token::Char(ch) => {
+            lexer.next();
+            expr.push(Expr::Int(ch as u32));
+            Ok(())
+        }
+        Token::Int(s) => {
+            lexer.next();
+            if let Ok(value) = parse_int(s) {
+                expr.push(Expr::Int(value));
+            } else {
+                return Err(ErrorKind::InvalidIntLiteral);
+            }
+            Ok(())
+        }
+        Token::Ident(s) => {
+            lexer.next();
+            expr.push(Expr::Label(id_table.insert(s)));
+            Ok(())
+        }
+        Token::Err(err) => Err(ErrorKind::LexerError(err)),
+        _ => Err(ErrorKind::ExpectedExpr),
+    }
+}
+
+fn precedence(token: Token) -> u32 {
+    match token {
+        Token::Shl | Token::Lshr | Token::Ashr => 3,
+        Token::And | Token::Mul => 2,
+        Token::Add | Token::Sub | Token::Or | Token::Xor => 1,
+        _ => 0,
+    }
+}
+
+fn token_to_binop(token: Token) -> Expr {
+    match token {
+        Token::Add  => Expr::Add,
+        Token::Sub  => Expr::Sub,
+        Token::And  => Expr::And,
+        Token::Mul  => Expr::Mul,
+        Token::Or   => Expr::Or,
+        Token::Xor  => Expr::Xor,
+        Token::Shl  => Expr::Shl,
+        Token::Lshr => Expr::Lshr,
+        Token::Ashr => Expr::Ashr,
+        _ => unreachable!(),
+    }
+}
+
+fn parse_reg(s: &str) -> Result<u32, ErrorKind> {
+    match s {
+        "x0"  | "zero" => Ok(0),
+        "x1"  | "lr"   => Ok(1),
+        "x2"  | "sp"   => Ok(2),
+        "x3"  | "a0"   => Ok(3),
+        "x4"  | "a1"   => Ok(4),
+        "x5"  | "a2"   => Ok(5),
+        "x6"  | "a3"   => Ok(6),
+        "x7"  | "a4"   => Ok(7),
+        "x8"  | "a5"   => Ok(8),
+        "x9"  | "s0"   => Ok(9),
+        "x10" | "s1"   => Ok(10),
+        "x11" | "s2"   => Ok(11),
+        "x12" | "s3"   => Ok(12),
+        "x13" | "s4"   => Ok(13),
+        "x14" | "s5"   => Ok(14),
+        "x15" | "s6"   => Ok(15),
+
+        "" => Err(ErrorKind::MissingRegName),
+        _  => Err(ErrorKind::InvalidRegName),
+    }
+}
+
+#[allow(clippy::from_str_radix_10)]
+fn parse_int(s: &str) -> Result<u32, ErrorKind> {
+    macro_rules! parse_int {
+        ($s:expr, $base:expr) => {
+            if let Ok(value) = u32::from_str_radix($s, $base) {
+                Ok(value)
+            } else {
+                return Err(ErrorKind::InvalidIntLiteral);
+            }
+        };
+    }
+
+    let bytes = s.as_bytes();
+    match s.len() {
+        0 => unreachable!(),
+        1 => Ok((bytes[0] - b'0') as u32),
+        2 => {
+            if bytes[1].is_ascii_digit() {
+                Ok(((bytes[0] - b'0') * 10 + (bytes[1] - b'0')) as u32)
+            } else {
+                Err(ErrorKind::InvalidIntLiteral)
+            }
+        }
+        _ => {
+            if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+                parse_int!(hex, 16)
+            } else {
+                parse_int!(s, 10)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ErrorKind::*;
+        let msg = match self {
+            JunkInLine           => "junk at the end of a line",
+            MissingRegName       => "missing register name",
+            InvalidRegName       => "invalid register name",
+            InvalidIntLiteral    => "invalid integer literal",
+            MissingClosingParen  => "missing closing ')'",
+            ExpectedExpr         => "expected expression",
+            LexerError(err)      => return err.fmt(f),
+
+            InvalidMacroName     => "invalid macro name",
+            InvalidMacroParam    => "invalid macro parameter",
+            RedefinedMacro       => "macro redefined",
+            NestedMacro          => "nested macro definition",
+            UnterminatedMacro    => "macro without endm",
+            UnexpectedEndm       => "endm without macro",
+            InvalidMacroArgCount => "wrong number of macro arguments",
+            InvalidMacroArg      => "invalid macro argument",
+            MacroTooDeep         => "macro expansion too deep",
+
+            ExpectedFileName     => "expected file name",
+            ReadError(name, err) => return write!(f, "failed to read file {}: {}", name, err),
+            IncludeCycle         => "circular include",
+            IncludeTooDeep       => "includes nested too deep",
+            IncludeInMacro       => "include in a macro definition",
+
+            InvalidCondition     => "invalid condition",
+            NotConstant          => "condition is not a constant",
+            UnexpectedCond       => "conditional directive without if",
+            CondAfterElse        => "conditional directive after else",
+            UnterminatedIf       => "if without endif",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for ErrorKind {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{ErrorKind, MAX_INCLUDE_DEPTH, MAX_MACRO_DEPTH};
+    use crate::asm::{self, ErrorKind as AsmErrorKind, Options, Program};
+
+    fn data(source: &str) -> Vec<u8> {
+        asm::assemble(source).unwrap().segments.remove(0).data
+    }
+
+    fn error(result: Result<Program, asm::Error>) -> asm::Error {
+        match result {
+            Ok(_) => panic!("assembled"),
+            Err(err) => err,
+        }
+    }
+
+    fn parse_error(source: &str) -> ErrorKind {
+        match error(asm::assemble(source)).kind {
+            AsmErrorKind::Parse(kind) => kind,
+            AsmErrorKind::Compile(kind) => panic!("compile error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn macro_expansion() {
+        let source = "
+macro put a, b
+    d8      a, b * 2
+endm
+    seg     0x1000
+    put     1, 2 + 1
+    put     'x', 0
+";
+        assert_eq!(data(source), [1, 6, b'x', 0]);
+
+        let source = "
+macro push reg
+    addi    %sp, %sp, -4
+    st      reg, %sp, 0
+endm
+    seg     0x1000
+    push    %a0
+";
+        let expected = "seg 0x1000\n addi %sp, %sp, -4\n st %a0, %sp, 0\n";
+        assert_eq!(data(source), data(expected));
+    }
+
+    #[test]
+    fn macro_locals() {
+        let source = "
+macro wait n
+    addi    %a0, %zero, n
+loop:
+    addi    %a0, %a0, -1
+    bne     %a0, %zero, loop
+endm
+    seg     0x1000
+    wait    2
+    wait    3
+loop:
+    jmp     loop
+";
+        let program = asm::assemble(source).unwrap();
+        let mut names: Vec<_> = program.symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["loop", "loop@0", "loop@1"]);
+
+        // Names of locals cannot be written in the source.
+        let source = "macro m\nl:\nendm\n seg 0x1000\n m\n jmp l@0\n";
+        assert!(matches!(parse_error(source), ErrorKind::LexerError(_)));
+    }
+
+    #[test]
+    fn macro_errors() {
+        let put = "macro put a, b\n d8 a, b\nendm\n";
+        assert!(matches!(parse_error(&format!("{}put 1\n", put)), ErrorKind::InvalidMacroArgCount));
+        assert!(matches!(parse_error(&format!("{}{}", put, put)), ErrorKind::RedefinedMacro));
+        assert!(matches!(parse_error("macro add\nendm\n"), ErrorKind::InvalidMacroName));
+        assert!(matches!(parse_error("macro m\nmacro n\nendm\n"), ErrorKind::NestedMacro));
+        assert!(matches!(parse_error("macro m\n d8 1\n"), ErrorKind::UnterminatedMacro));
+        assert!(matches!(parse_error("endm\n"), ErrorKind::UnexpectedEndm));
+    }
+
+    #[test]
+    fn macro_recursion_limit() {
+        let err = error(asm::assemble("macro r\n r\nendm\n seg 0x1000\n r\n"));
+        assert!(matches!(err.kind, AsmErrorKind::Parse(ErrorKind::MacroTooDeep)));
+        assert_eq!(err.line, 2);
+        assert_eq!(err.calls.len(), MAX_MACRO_DEPTH as usize);
+        assert!(err.calls.iter().all(|call| call.name == "r"));
+        assert_eq!(err.calls.last().unwrap().line, 5);
+    }
+
+    #[test]
+    fn error_in_macro_body() {
+        let source = "
+macro outer
+    inner
+endm
+macro inner
+    addi    %a0
+endm
+    seg     0x1000
+    outer
+";
+        let err = error(asm::assemble(source));
+        assert!(matches!(err.kind, AsmErrorKind::Compile(_)));
+        assert_eq!(err.line, 6);
+        let calls: Vec<_> = err.calls.iter().map(|call| (call.name.as_str(), call.line)).collect();
+        assert_eq!(calls, [("inner", 3), ("outer", 9)]);
+    }
+
+    fn assemble_files(files: &[(&str, &str)]) -> Result<Program, asm::Error> {
+        let file_system: HashMap<String, Vec<u8>> =
+            files.iter().map(|&(name, text)| (name.to_owned(), text.as_bytes().to_vec())).collect();
+        let options = Options { file_system: &file_system, ..Default::default() };
+        asm::assemble_with("main.asm", files[0].1, &options)
+    }
+
+    #[test]
+    fn include() {
+        let files = [
+            ("main.asm", " include \"defs.asm\"\n seg 0x1000\n put SIZE\n include \"defs.asm\"\n"),
+            ("defs.asm", "ifndef SIZE\nSIZE = 5\nmacro put v\n d8 v\nendm\nendif\n"),
+        ];
+        let program = assemble_files(&files).unwrap();
+        assert_eq!(program.segments[0].data, [5]);
+        assert_eq!(program.files, ["main.asm", "defs.asm"]);
+
+        let files = [
+            ("main.asm", " seg 0x1000\n d8 1\n include \"lib/data.asm\"\n"),
+            ("lib/data.asm", " d8 2\n d8 x\n"),
+        ];
+        let err = error(assemble_files(&files));
+        assert!(matches!(err.kind, AsmErrorKind::Compile(_)));
+        assert_eq!((err.file.as_str(), err.line), ("lib/data.asm", 2));
+    }
+
+    #[test]
+    fn include_errors() {
+        let files = [
+            ("main.asm", "include \"a.asm\"\n"),
+            ("a.asm", "include \"b.asm\"\n"),
+            ("b.asm", "include \"a.asm\"\n"),
+        ];
+        let err = error(assemble_files(&files));
+        assert!(matches!(err.kind, AsmErrorKind::Parse(ErrorKind::IncludeCycle)));
+        assert_eq!(err.file, "b.asm");
+
+        let files = [("main.asm", "include \"main.asm\"\n")];
+        assert!(matches!(error(assemble_files(&files)).kind, AsmErrorKind::Parse(ErrorKind::IncludeCycle)));
+
+        let files = [("main.asm", "include \"missing.asm\"\n")];
+        let err = error(assemble_files(&files));
+        let AsmErrorKind::Parse(ErrorKind::ReadError(name, _)) = err.kind else {
+            panic!("unexpected error: {}", err.kind);
+        };
+        assert_eq!(name, "missing.asm");
+
+        let files = [("main.asm", "macro m\n include \"a.asm\"\nendm\n"), ("a.asm", "")];
+        assert!(matches!(error(assemble_files(&files)).kind, AsmErrorKind::Parse(ErrorKind::IncludeInMacro)));
+
+        // Files cannot be read without a file system.
+        let err = error(asm::assemble("include \"a.asm\"\n"));
+        assert!(matches!(err.kind, AsmErrorKind::Parse(ErrorKind::ReadError(..))));
+    }
+
+    #[test]
+    fn include_depth() {
+        let names: Vec<_> = (0..=MAX_INCLUDE_DEPTH).map(|i| format!("{}.asm", i)).collect();
+        let texts: Vec<_> = (0..=MAX_INCLUDE_DEPTH).map(|i| format!("include \"{}.asm\"\n", i + 1)).collect();
+        let mut files = vec![("main.asm", "include \"0.asm\"\n")];
+        files.extend(names.iter().zip(&texts).map(|(name, text)| (name.as_str(), text.as_str())));
+        let err = error(assemble_files(&files));
+        assert!(matches!(err.kind, AsmErrorKind::Parse(ErrorKind::IncludeTooDeep)));
+
+        files.truncate(MAX_INCLUDE_DEPTH - 1);
+        let last = format!("{}.asm", MAX_INCLUDE_DEPTH - 2);
+        files.push((&last, ""));
+        assert!(assemble_files(&files).is_ok());
+    }
+
+    #[test]
+    fn incbin() {
+        let files = [
+            (
+                "main.asm",
+                " seg 0x1000\n incbin \"data.bin\"\n incbin \"data.bin\", 2\n incbin \"data.bin\", 1, 2\n",
+            ),
+            ("data.bin", "abcd"),
+        ];
+        assert_eq!(assemble_files(&files).unwrap().segments[0].data, b"abcdcdbc");
+
+        let files = [("main.asm", " seg 0x1000\n incbin \"data.bin\", 3, 2\n"), ("data.bin", "abcd")];
+        let err = error(assemble_files(&files));
+        assert!(matches!(err.kind, AsmErrorKind::Compile(asm::CompileError::InvalidRange)));
+    }
+
+    #[test]
+    fn conditions() {
+        let source = "
+A = 2
+    seg     0x1000
+if A - 2
+    d8      0
+elif A
+    d8      2
+    if 0
+    d8      0
+    else
+    d8      3
+    endif
+elif A
+    d8      0
+else
+    d8      0
+endif
+ifdef A
+    d8      4
+endif
+ifndef label
+    d8      5
+endif
+ifdef label
+    d8      0
+endif
+label:
+ifdef label
+    d8      6
+endif
+";
+        assert_eq!(data(source), [2, 3, 4, 5, 6]);
+
+        // Skipped lines are not assembled.
+        let source = "seg 0x1000\nif 0\n unknown %a0\n include \"missing.asm\"\nendif\n d8 1\n";
+        assert_eq!(data(source), [1]);
+
+        let source = "
+macro zeros n
+if n
+    d8      0
+    zeros   n-1
+endif
+endm
+    seg     0x1000
+    zeros   3
+";
+        assert_eq!(data(source), [0, 0, 0]);
+    }
+
+    #[test]
+    fn condition_errors() {
+        assert!(matches!(parse_error("seg 0x1000\nl:\nif l\nendif\n"), ErrorKind::NotConstant));
+        assert!(matches!(parse_error("if A\nendif\nA = 1\n"), ErrorKind::NotConstant));
+        assert!(matches!(parse_error("ifdef 1\nendif\n"), ErrorKind::InvalidCondition));
+        assert!(matches!(parse_error("endif\n"), ErrorKind::UnexpectedCond));
+        assert!(matches!(parse_error("if 1\nelse\nelif 1\nendif\n"), ErrorKind::CondAfterElse));
+        assert!(matches!(parse_error("if 1\n"), ErrorKind::UnterminatedIf));
+        assert!(matches!(parse_error("macro m\nif 1\nendm\n m\nendif\n"), ErrorKind::UnterminatedIf));
+    }
+
+    #[test]
+    fn defines() {
+        let defines = [("DEBUG".to_owned(), 1), ("SIZE".to_owned(), 7), ("SIZE".to_owned(), 9)];
+        let options = Options { defines: &defines, ..Default::default() };
+        let assemble = |source| asm::assemble_with("main.asm", source, &options);
+
+        let source = "seg 0x1000\nifdef DEBUG\n d8 SIZE\nendif\nifndef DEBUG\n d8 0\nendif\n";
+        assert_eq!(assemble(source).unwrap().segments[0].data, [9]);
+
+        // Defines replace assignments in the source.
+        let source = "
+SIZE = 4
+DOUBLE = SIZE * 2
+    seg     0x1000
+    d8      DOUBLE
+if SIZE - 9
+    d8      0
+else
+    d8      1
+endif
+";
+        assert_eq!(assemble(source).unwrap().segments[0].data, [18, 1]);
+
+        let err = error(assemble("seg 0x1000\nDEBUG:\n"));
+        assert!(matches!(err.kind, AsmErrorKind::Compile(asm::CompileError::RedefinedSymbol)));
+    }
+}
@@ -0,0 +1,59 @@
+use std::fs;
+use std::path::Path;
+
+use my_vm::archive::Archive;
+use my_vm::object::Object;
+
+struct Error;
+
+fn run() -> Result<(), Error> {
+    let args: Vec<_> = std::env::args_os().collect();
+
+    if args.len() < 3 {
+        eprintln!("Usage: {} OUTPUT OBJECT....", Path::new(&args[0]).display());
+        return Err(Error);
+    }
+    let output_name = Path::new(&args[1]);
+
+    let mut archive = Archive::new();
+    for name in args[2..].iter().map(Path::new) {
+        let object = match fs::read(name) {
+            Ok(data) => Object::from_bytes(&data).map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match object {
+            Ok(object) => {
+                let member_name = name.file_name().unwrap_or(name.as_os_str()).to_string_lossy();
+                archive.add(&member_name, object);
+            }
+            Err(err) => {
+                eprintln!("Failed to load file {}: {}.", name.display(), err);
+                return Err(Error);
+            }
+        }
+    }
+
+    let output = match archive.to_bytes() {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Failed to serialize file {}: {}.", output_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    match fs::write(output_name, &output) {
+        Ok(()) => {}
+        Err(err) => {
+            eprintln!("Failed to write file {}: {}.", output_name.display(), err);
+            return Err(Error);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if run().is_err() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,185 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use my_vm::asm::{self, Options, SearchPaths};
+use my_vm::binfile;
+
+struct Error;
+
+fn parse_value(s: &str) -> Option<u32> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+// `NAME=VALUE`, or just `NAME` for 1.
+fn parse_define(s: &str) -> Option<(String, u32)> {
+    let (name, value) = match s.split_once('=') {
+        Some((name, value)) => (name, parse_value(value)?),
+        None => (s, 1),
+    };
+    if !asm::is_symbol_name(name) {
+        return None;
+    }
+    Some((name.to_owned(), value))
+}
+
+fn print_usage(program: &OsStr) {
+    eprintln!(
+        "Usage: {} [--strip] [--object] [-I DIR]... [-D NAME[=VALUE]]... SOURCE OUTPUT.",
+        Path::new(program).display()
+    );
+    eprintln!("NAME is an identifier other than the name of an instruction or a directive.");
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<_> = std::env::args_os().collect();
+
+    let mut strip = false;
+    let mut object = false;
+    let mut search_paths = SearchPaths::default();
+    let mut defines = Vec::new();
+    let mut names = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--strip" {
+            strip = true;
+            i += 1;
+        } else if args[i] == "--object" {
+            object = true;
+            i += 1;
+        } else if args[i] == "-I" && i + 1 < args.len() {
+            search_paths.dirs.push(PathBuf::from(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "-D" && i + 1 < args.len() {
+            match args[i + 1].to_str().and_then(parse_define) {
+                Some(define) => defines.push(define),
+                None => {
+                    eprintln!("Invalid definition {}.", args[i + 1].to_string_lossy());
+                    print_usage(&args[0]);
+                    return Err(Error);
+                }
+            }
+            i += 2;
+        } else {
+            names.push(Path::new(&args[i]));
+            i += 1;
+        }
+    }
+
+    let (source_name, output_name) = match names[..] {
+        [source_name, output_name] => (source_name, output_name),
+        _ => {
+            print_usage(&args[0]);
+            return Err(Error);
+        }
+    };
+
+    let source = match fs::read_to_string(source_name) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", source_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let file_name = source_name.display().to_string();
+    let options = Options { file_system: &search_paths, defines: &defines };
+    let output = if object {
+        assemble_object(&file_name, &source, &options, strip)
+    } else {
+        assemble_program(&file_name, &source, &options, strip)
+    };
+
+    let output = match output? {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Failed to serialize file {}: {}.", output_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    match fs::write(output_name, &output) {
+        Ok(()) => {}
+        Err(err) => {
+            eprintln!("Failed to write file {}: {}.", output_name.display(), err);
+            return Err(Error);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_error(err: &asm::Error) {
+    eprintln!("Error in {}:{}: {}.", err.file, err.line, err);
+    // Recursive calls from the same line are printed once.
+    let mut i = 0;
+    while i < err.calls.len() {
+        let call = &err.calls[i];
+        let same_place = |next: &&asm::MacroCall| next.line == call.line && next.file == call.file;
+        let count = err.calls[i..].iter().take_while(same_place).count();
+        let place = format!("{}:{}", call.file, call.line);
+        match count {
+            1 => eprintln!("    in macro {} called from {}.", call.name, place),
+            _ => eprintln!("    in macro {} called from {} ({} times).", call.name, place, count),
+        }
+        i += count;
+    }
+}
+
+fn assemble_program(
+    file_name: &str,
+    source: &str,
+    options: &Options,
+    strip: bool,
+) -> Result<binfile::Result<Vec<u8>>, Error> {
+    let mut program = match asm::assemble_with(file_name, source, options) {
+        Ok(program) => program,
+        Err(err) => {
+            print_error(&err);
+            return Err(Error);
+        }
+    };
+
+    if strip {
+        program.symbols.clear();
+        program.lines.clear();
+    }
+
+    Ok(program.to_binfile())
+}
+
+// Stripping an object file keeps the symbols that relocations need.
+fn assemble_object(
+    file_name: &str,
+    source: &str,
+    options: &Options,
+    strip: bool,
+) -> Result<binfile::Result<Vec<u8>>, Error> {
+    let mut object = match asm::assemble_object_with(file_name, source, options) {
+        Ok(object) => object,
+        Err(err) => {
+            print_error(&err);
+            return Err(Error);
+        }
+    };
+
+    if strip {
+        object.lines.clear();
+    }
+
+    Ok(object.to_bytes())
+}
+
+fn main() {
+    if run().is_err() {
+        std::process::exit(1);
+    }
+}
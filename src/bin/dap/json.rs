@@ -0,0 +1,395 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> &Value {
+        match self {
+            Value::Object(fields) => {
+                fields.iter().find(|(k, _)| k == key).map_or(&Value::Null, |(_, v)| v)
+            }
+            _ => &Value::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Value] {
+        match self {
+            Value::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Self {
+        Value::Array(items)
+    }
+}
+
+macro_rules! object {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::json::Value::Object(vec![$(($key.to_string(), $crate::json::Value::from($value))),*])
+    };
+}
+
+pub(crate) use object;
+
+pub fn parse(s: &str) -> Option<Value> {
+    let mut parser = Parser { bytes: s.as_bytes(), pos: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos == parser.bytes.len() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Option<()> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn parse_value(&mut self) -> Option<Value> {
+        self.skip_whitespace();
+        match *self.bytes.get(self.pos)? {
+            b'n' => self.expect("null").map(|_| Value::Null),
+            b't' => self.expect("true").map(|_| Value::Bool(true)),
+            b'f' => self.expect("false").map(|_| Value::Bool(false)),
+            b'"' => self.parse_string().map(Value::String),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Some(Value::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match *self.bytes.get(self.pos)? {
+                        b',' => self.pos += 1,
+                        b']' => {
+                            self.pos += 1;
+                            return Some(Value::Array(items));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Some(Value::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    fields.push((key, self.parse_value()?));
+                    self.skip_whitespace();
+                    match *self.bytes.get(self.pos)? {
+                        b',' => self.pos += 1,
+                        b'}' => {
+                            self.pos += 1;
+                            return Some(Value::Object(fields));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            _ => self.parse_number().map(Value::Number),
+        }
+    }
+
+    // Accepts only the JSON syntax, which is stricter than that of `f64`.
+    fn parse_number(&mut self) -> Option<f64> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let start = parser.pos;
+            while parser.bytes.get(parser.pos).is_some_and(u8::is_ascii_digit) {
+                parser.pos += 1;
+            }
+            parser.pos - start
+        };
+
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        // No leading zeros.
+        let int_start = self.pos;
+        let count = digits(self);
+        if count == 0 || count > 1 && self.bytes[int_start] == b'0' {
+            return None;
+        }
+        if self.bytes.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if digits(self) == 0 {
+                return None;
+            }
+        }
+        if let Some(b'e' | b'E') = self.bytes.get(self.pos) {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.bytes.get(self.pos) {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return None;
+            }
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos]).ok()?.parse().ok()
+    }
+
+    fn parse_string(&mut self) -> Option<String> {
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(*self.bytes.get(self.pos)?, b'"' | b'\\') {
+                self.pos += 1;
+            }
+            s.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).ok()?);
+
+            let byte = self.bytes[self.pos];
+            self.pos += 1;
+            if byte == b'"' {
+                return Some(s);
+            }
+
+            let escape = *self.bytes.get(self.pos)?;
+            self.pos += 1;
+            match escape {
+                b'"' => s.push('"'),
+                b'\\' => s.push('\\'),
+                b'/' => s.push('/'),
+                b'b' => s.push('\u{8}'),
+                b'f' => s.push('\u{C}'),
+                b'n' => s.push('\n'),
+                b'r' => s.push('\r'),
+                b't' => s.push('\t'),
+                b'u' => {
+                    let mut code = self.parse_hex4()?;
+                    if (0xD800..0xDC00).contains(&code) {
+                        self.expect("\\u")?;
+                        let low = self.parse_hex4()?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    s.push(char::from_u32(code)?);
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Option<u32> {
+        let hex = self.bytes.get(self.pos..self.pos + 4)?;
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        self.pos += 4;
+        u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => {
+                f.write_str("\"")?;
+                for ch in s.chars() {
+                    match ch {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\u{0}'..='\u{1F}' => write!(f, "\\u{:04x}", ch as u32)?,
+                        _ => write!(f, "{}", ch)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{}", Value::String(key.clone()), value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        let value = parse(r#""a\"b\\c\/d\b\f\n\r\t\u0041\u00e9\u4e2d""#).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c/d\u{8}\u{C}\n\r\tA\u{E9}\u{4E2D}"));
+        assert_eq!(parse(r#""\ud83d\ude00""#).unwrap().as_str(), Some("\u{1F600}"));
+        assert_eq!(parse("\"кириллица\"").unwrap().as_str(), Some("кириллица"));
+
+        let s = Value::from("q\"\\\n\r\t\u{1}é");
+        assert_eq!(s.to_string(), r#""q\"\\\n\r\t\u0001é""#);
+        assert_eq!(parse(&s.to_string()), Some(s));
+    }
+
+    #[test]
+    fn numbers() {
+        let number = |s| parse(s).and_then(|value| value.as_i64());
+        assert_eq!(number("0"), Some(0));
+        assert_eq!(number("-17"), Some(-17));
+        assert_eq!(number("4096"), Some(4096));
+        assert_eq!(number("1e3"), Some(1000));
+        assert_eq!(number("25E-1"), None);
+        assert_eq!(parse("2.5"), Some(Value::Number(2.5)));
+        assert_eq!(parse("-0.5e+1"), Some(Value::Number(-5.0)));
+        assert_eq!(Value::from(-42).to_string(), "-42");
+        assert_eq!(Value::Number(0.25).to_string(), "0.25");
+    }
+
+    #[test]
+    fn nesting() {
+        let text = r#" { "a" : [1, {"b": null}, [], {}], "c": true, "d": false, "e": "x" } "#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("a").as_array().len(), 4);
+        assert_eq!(value.get("a").as_array()[1].get("b"), &Value::Null);
+        assert_eq!(value.get("c").as_bool(), Some(true));
+        assert_eq!(value.get("d").as_bool(), Some(false));
+        assert_eq!(value.get("missing"), &Value::Null);
+        assert_eq!(value.to_string(), r#"{"a":[1,{"b":null},[],{}],"c":true,"d":false,"e":"x"}"#);
+        assert_eq!(parse(&value.to_string()), Some(value));
+
+        let built = object! { "seq" => 1, "body" => object! { "threads" => vec![Value::from("main")] } };
+        assert_eq!(built.to_string(), r#"{"seq":1,"body":{"threads":["main"]}}"#);
+    }
+
+    #[test]
+    fn malformed_input() {
+        for text in [
+            "",
+            " ",
+            "nul",
+            "tru",
+            "[1,",
+            "[1 2]",
+            "[1,]",
+            "{\"a\" 1}",
+            "{\"a\":1,}",
+            "{a:1}",
+            "{\"a\":1",
+            "\"abc",
+            "\"\\x\"",
+            "\"\\u12\"",
+            "\"\\u+123\"",
+            "\"\\ud83d\"",
+            "\"\\ud83d\\u0041\"",
+            "\"\\ude00\"",
+            "01",
+            "-",
+            "+1",
+            ".5",
+            "1.",
+            "1e",
+            "1 2",
+            "{} x",
+        ] {
+            assert_eq!(parse(text), None, "{:?}", text);
+        }
+    }
+}
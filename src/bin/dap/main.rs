@@ -0,0 +1,734 @@
+mod json;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{stdin, stdout, BufRead, BufReader, Cursor, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+use my_vm::disasm::{Disasm, REG_NAMES};
+use my_vm::isa::{Instruction, Rc, Rrc};
+use my_vm::asm::{self, LineEntry};
+use my_vm::{binfile, vm};
+
+use crate::json::{object, Value};
+
+// Number of instructions executed between checks for incoming requests.
+const RUN_CHUNK: u64 = 100_000;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+
+type Sysfn = vm::Console<Cursor<Vec<u8>>, Vec<u8>>;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+    Instruction,
+}
+
+enum Stop {
+    Reason(vm::StopReason),
+    Step,
+    Pause,
+    Disconnect,
+}
+
+struct Program {
+    machine: vm::Machine,
+    source: Option<String>,
+    lines: Vec<LineEntry>,
+    source_breakpoints: Vec<u32>,
+    exit_status: Option<u32>,
+}
+
+// The sending half of the protocol.
+struct Output<W> {
+    writer: W,
+    seq: i64,
+}
+
+struct Session<W> {
+    requests: Receiver<Value>,
+    pending: VecDeque<Value>,
+    output: Output<W>,
+    sysfn: Sysfn,
+    program: Option<Program>,
+    stop_on_entry: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn serve(&mut self) {
+        loop {
+            let request = match self.pending.pop_front() {
+                Some(request) => request,
+                None => match self.requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                },
+            };
+            if !self.handle(&request) {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request.get("command").as_str().unwrap_or("");
+        let args = request.get("arguments");
+
+        match command {
+            "initialize" => {
+                let body = object! {
+                    "supportsConfigurationDoneRequest" => true,
+                    "supportsReadMemoryRequest" => true,
+                    "supportsSteppingGranularity" => true,
+                };
+                self.respond(request, Ok(body));
+                self.event("initialized", Value::Null);
+            }
+            "launch" => {
+                let result = self.launch(args);
+                self.respond(request, result.map(|()| Value::Null));
+            }
+            "setBreakpoints" => {
+                let lines: Vec<i64> = args
+                    .get("breakpoints")
+                    .as_array()
+                    .iter()
+                    .filter_map(|bp| bp.get("line").as_i64())
+                    .collect();
+                let breakpoints = self.set_breakpoints(&lines);
+                self.respond(request, Ok(object! { "breakpoints" => breakpoints }));
+            }
+            "setExceptionBreakpoints" => {
+                self.respond(request, Ok(object! { "breakpoints" => Vec::new() }));
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(Value::Null));
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    return self.resume(Mode::Continue);
+                }
+            }
+            "threads" => {
+                let thread = object! { "id" => THREAD_ID, "name" => "main" };
+                self.respond(request, Ok(object! { "threads" => vec![thread] }));
+            }
+            "stackTrace" => {
+                let frames = self.stack_trace();
+                let count = frames.len() as i64;
+                let body = object! { "stackFrames" => frames, "totalFrames" => count };
+                self.respond(request, Ok(body));
+            }
+            "scopes" => {
+                let scope = object! {
+                    "name" => "Registers",
+                    "variablesReference" => REGISTERS_REF,
+                    "expensive" => false,
+                };
+                self.respond(request, Ok(object! { "scopes" => vec![scope] }));
+            }
+            "variables" => {
+                let variables = match args.get("variablesReference").as_i64() {
+                    Some(REGISTERS_REF) => self.registers(),
+                    _ => Vec::new(),
+                };
+                self.respond(request, Ok(object! { "variables" => variables }));
+            }
+            "readMemory" => {
+                let result = self.read_memory(args);
+                self.respond(request, result);
+            }
+            "continue" => {
+                let body = object! { "allThreadsContinued" => true };
+                self.respond(request, Ok(body));
+                return self.resume(Mode::Continue);
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, Ok(Value::Null));
+                let mode = match (command, args.get("granularity").as_str()) {
+                    (_, Some("instruction")) => Mode::Instruction,
+                    ("next", _) => Mode::Next,
+                    ("stepIn", _) => Mode::StepIn,
+                    _ => Mode::StepOut,
+                };
+                return self.resume(mode);
+            }
+            "pause" => {
+                self.respond(request, Ok(Value::Null));
+                self.stopped("pause", None);
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null));
+                return false;
+            }
+            _ => {
+                self.respond(request, Err(format!("unsupported request '{}'", command)));
+            }
+        }
+
+        true
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let path = match args.get("program").as_str() {
+            Some(path) => path.to_string(),
+            None => return Err("missing 'program' argument".into()),
+        };
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+
+        if let Some(input) = args.get("stdin").as_str() {
+            match fs::read(input) {
+                Ok(data) => self.sysfn.input = Cursor::new(data),
+                Err(err) => return Err(format!("failed to load file {}: {}", input, err)),
+            }
+        }
+
+        let file_data;
+        let mut source = None;
+        let mut lines = Vec::new();
+        if path.ends_with(".asm") {
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) => return Err(format!("failed to load file {}: {}", path, err)),
+            };
+
+            let program = match asm::assemble_file(&path, &text) {
+                Ok(program) => program,
+                Err(err) => {
+                    let mut msg = format!("error in {}:{}: {}", err.file, err.line, err);
+                    for call in &err.calls {
+                        msg += &format!(", in macro {} called from {}:{}", call.name, call.file, call.line);
+                    }
+                    return Err(msg);
+                }
+            };
+
+            file_data = match program.to_binfile() {
+                Ok(output) => output,
+                Err(err) => return Err(format!("failed to serialize program: {}", err)),
+            };
+            source = Some(path);
+            // Only the main file is shown, so lines of included files are dropped.
+            lines = program.lines.into_iter().filter(|entry| entry.file == 0).collect();
+        } else {
+            file_data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(err) => return Err(format!("failed to load file {}: {}", path, err)),
+            };
+        }
+
+        let machine = match binfile::load(&file_data) {
+            Ok(machine) => machine,
+            Err(err) => return Err(format!("failed to load program: {}", err)),
+        };
+
+        self.program = Some(Program {
+            machine,
+            source,
+            lines,
+            source_breakpoints: Vec::new(),
+            exit_status: None,
+        });
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, requested: &[i64]) -> Vec<Value> {
+        let program = match &mut self.program {
+            Some(program) => program,
+            None => return Vec::new(),
+        };
+
+        for addr in program.source_breakpoints.drain(..) {
+            program.machine.remove_breakpoint(addr);
+        }
+
+        let mut result = Vec::new();
+        for &line in requested {
+            // A breakpoint on a line without code moves to the next line with code.
+            let entry = program
+                .lines
+                .iter()
+                .filter(|entry| entry.line as i64 >= line)
+                .min_by_key(|entry| (entry.line, entry.addr));
+            match entry {
+                Some(entry) => {
+                    program.machine.add_breakpoint(entry.addr);
+                    program.source_breakpoints.push(entry.addr);
+                    result.push(object! {
+                        "verified" => true,
+                        "line" => entry.line as i64,
+                    });
+                }
+                None => result.push(object! { "verified" => false, "line" => line }),
+            }
+        }
+        result
+    }
+
+    fn resume(&mut self, mode: Mode) -> bool {
+        let stop = match &self.program {
+            Some(Program { exit_status: Some(_), .. }) | None => return true,
+            Some(_) => self.run(mode),
+        };
+
+        self.flush_output();
+        match stop {
+            Stop::Reason(vm::StopReason::Exited(status)) => {
+                if let Some(program) = &mut self.program {
+                    program.exit_status = Some(status);
+                }
+                self.event("exited", object! { "exitCode" => status as i64 });
+                self.event("terminated", Value::Null);
+            }
+            Stop::Reason(vm::StopReason::Breakpoint(_)) => self.stopped("breakpoint", None),
+            Stop::Reason(vm::StopReason::Watchpoint(hit)) => {
+                let reason = vm::StopReason::Watchpoint(hit).to_string();
+                self.stopped("data breakpoint", Some(reason));
+            }
+            Stop::Reason(reason) => self.stopped("exception", Some(reason.to_string())),
+            Stop::Step => self.stopped("step", None),
+            Stop::Pause => self.stopped("pause", None),
+            Stop::Disconnect => return false,
+        }
+        true
+    }
+
+    fn run(&mut self, mode: Mode) -> Stop {
+        let program = self.program.as_mut().unwrap();
+        let machine = &mut program.machine;
+        let start_line = location(&program.lines, machine.state.pc);
+        let start_sp = machine.state.regs[2];
+        let start_lr = machine.state.regs[1];
+        let mut skip_until = None;
+        let mut first = true;
+
+        loop {
+            if let Mode::Continue = mode {
+                match machine.run_for(RUN_CHUNK, &mut self.sysfn) {
+                    vm::StopReason::BudgetExhausted => {}
+                    reason => return Stop::Reason(reason),
+                }
+            } else {
+                for _ in 0..RUN_CHUNK {
+                    let pc = machine.state.pc;
+                    if let (Mode::Next, None) = (mode, skip_until) {
+                        if is_call(&machine.memory, pc) {
+                            skip_until = Some((pc.wrapping_add(4), machine.state.regs[2]));
+                        }
+                    }
+
+                    match machine.step(&mut self.sysfn) {
+                        vm::StopReason::Stepped => {}
+                        reason => return Stop::Reason(reason),
+                    }
+
+                    let pc = machine.state.pc;
+                    let sp = machine.state.regs[2];
+                    if let Some((ret, ret_sp)) = skip_until {
+                        if pc != ret || sp < ret_sp {
+                            continue;
+                        }
+                        skip_until = None;
+                    }
+
+                    let done = match mode {
+                        Mode::Instruction => first,
+                        Mode::StepOut => pc == start_lr && sp >= start_sp,
+                        _ => location(&program.lines, pc).is_some_and(|l| Some(l) != start_line),
+                    };
+                    if done {
+                        return Stop::Step;
+                    }
+                    first = false;
+                }
+            }
+
+            self.output.console(&mut self.sysfn);
+            loop {
+                match self.requests.try_recv() {
+                    Ok(request) => match request.get("command").as_str() {
+                        Some("pause") => {
+                            self.output.respond(&request, Ok(Value::Null));
+                            return Stop::Pause;
+                        }
+                        Some("disconnect" | "terminate") => {
+                            self.output.respond(&request, Ok(Value::Null));
+                            return Stop::Disconnect;
+                        }
+                        _ => self.pending.push_back(request),
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Stop::Disconnect,
+                }
+            }
+        }
+    }
+
+    fn stack_trace(&self) -> Vec<Value> {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return Vec::new(),
+        };
+
+        let pc = program.machine.state.pc;
+        let name = match vm::load_u32(&program.machine.memory, pc) {
+            Some(inst) => Disasm::new(inst, pc).to_string(),
+            None => format!("0x{:08X}", pc),
+        };
+        let mut frame = vec![
+            ("id".to_string(), Value::from(0)),
+            ("name".to_string(), Value::from(name)),
+            ("column".to_string(), Value::from(1)),
+            ("instructionPointerReference".to_string(), Value::from(format!("0x{:X}", pc))),
+        ];
+
+        let line = program.lines.iter().rev().find(|entry| entry.addr <= pc);
+        match (&program.source, line) {
+            (Some(source), Some(entry)) => {
+                let name = Path::new(source).file_name().map(|name| name.to_string_lossy());
+                let name = name.unwrap_or_default().into_owned();
+                frame.push(("line".into(), Value::from(entry.line as i64)));
+                frame.push(("source".into(), object! { "name" => name, "path" => source.as_str() }));
+            }
+            _ => frame.push(("line".into(), Value::from(0))),
+        }
+
+        vec![Value::Object(frame)]
+    }
+
+    fn registers(&self) -> Vec<Value> {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return Vec::new(),
+        };
+
+        let state = &program.machine.state;
+        let names = ["pc"].into_iter().chain(REG_NAMES);
+        let values = [state.pc].into_iter().chain(state.regs);
+        names
+            .zip(values)
+            .map(|(name, value)| {
+                object! {
+                    "name" => name,
+                    "value" => format!("0x{:08X} ({})", value, value as i32),
+                    "variablesReference" => 0,
+                    "memoryReference" => format!("0x{:X}", value),
+                }
+            })
+            .collect()
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return Err("no program".into()),
+        };
+
+        let reference = args.get("memoryReference").as_str().unwrap_or("");
+        let base = match reference.strip_prefix("0x").or(reference.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+            None => reference.parse().ok(),
+        };
+        let base = match base {
+            Some(base) => base,
+            None => return Err(format!("invalid memory reference '{}'", reference)),
+        };
+        let addr = base + args.get("offset").as_i64().unwrap_or(0);
+        let count = args.get("count").as_i64().unwrap_or(0).max(0);
+
+        let memory = &program.machine.memory;
+        let start = addr.clamp(0, memory.len() as i64);
+        let end = (addr + count).clamp(start, memory.len() as i64);
+        let data = &memory[start as usize..end as usize];
+        Ok(object! {
+            "address" => format!("0x{:X}", start),
+            "data" => base64(data),
+            "unreadableBytes" => count - data.len() as i64,
+        })
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+        let mut body = vec![
+            ("reason".to_string(), Value::from(reason)),
+            ("threadId".to_string(), Value::from(THREAD_ID)),
+            ("allThreadsStopped".to_string(), Value::from(true)),
+        ];
+        if let Some(text) = text {
+            body.push(("text".into(), Value::from(text)));
+        }
+        self.event("stopped", Value::Object(body));
+    }
+
+    fn flush_output(&mut self) {
+        self.output.console(&mut self.sysfn);
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        self.output.respond(request, result);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.output.event(event, body);
+    }
+}
+
+impl<W: Write> Output<W> {
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut message = vec![
+            ("type".to_string(), Value::from("response")),
+            ("request_seq".to_string(), request.get("seq").clone()),
+            ("command".to_string(), request.get("command").clone()),
+        ];
+        match result {
+            Ok(body) => {
+                message.push(("success".into(), Value::from(true)));
+                if body != Value::Null {
+                    message.push(("body".into(), body));
+                }
+            }
+            Err(err) => {
+                message.push(("success".into(), Value::from(false)));
+                message.push(("message".into(), Value::from(err)));
+            }
+        }
+        self.send(message);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let mut message = vec![
+            ("type".to_string(), Value::from("event")),
+            ("event".to_string(), Value::from(event)),
+        ];
+        if body != Value::Null {
+            message.push(("body".into(), body));
+        }
+        self.send(message);
+    }
+
+    // Forwards the program's console output as an `output` event.
+    fn console(&mut self, sysfn: &mut Sysfn) {
+        if sysfn.output.is_empty() {
+            return;
+        }
+        let output = String::from_utf8_lossy(&sysfn.output).into_owned();
+        sysfn.output.clear();
+        self.event("output", object! { "category" => "stdout", "output" => output });
+    }
+
+    fn send(&mut self, mut message: Vec<(String, Value)>) {
+        self.seq += 1;
+        message.insert(0, ("seq".into(), Value::from(self.seq)));
+        let body = Value::Object(message).to_string();
+
+        write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.writer.flush().unwrap();
+    }
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0_u8; length?];
+    reader.read_exact(&mut body).ok()?;
+    json::parse(std::str::from_utf8(&body).ok()?)
+}
+
+fn location(lines: &[LineEntry], pc: u32) -> Option<u32> {
+    // Without a line table every instruction is a separate location.
+    if lines.is_empty() {
+        return Some(pc);
+    }
+    match lines.binary_search_by_key(&pc, |entry| entry.addr) {
+        Ok(index) => Some(lines[index].line),
+        Err(_) => None,
+    }
+}
+
+fn is_call(memory: &[u8], pc: u32) -> bool {
+    match vm::load_u32(memory, pc).map(Instruction::decode) {
+        Some(Ok(Instruction::Jal(Rc { r1, .. }) | Instruction::Jalr(Rrc { r1, .. }))) => r1 != 0,
+        _ => false,
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn main() {
+    let (sender, requests) = channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stdin());
+        while let Some(message) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session {
+        requests,
+        pending: VecDeque::new(),
+        output: Output { writer: stdout().lock(), seq: 0 },
+        sysfn: Sysfn { input: Cursor::new(Vec::new()), output: Vec::new() },
+        program: None,
+        stop_on_entry: false,
+    };
+    session.serve();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "\
+    mem     0x2000
+    seg     0x1000
+    addi    %a0, %zero, 'A'
+    sysfn   %a0, 2
+    addi    %a0, %zero, 5
+
+    sysfn   %a0, 0
+";
+
+    // Runs a scripted session to the end and returns everything it sent.
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let (sender, receiver) = channel();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            if let Value::Object(fields) = &mut request {
+                fields.insert(0, ("seq".into(), Value::from(seq as i64 + 1)));
+                fields.insert(1, ("type".into(), Value::from("request")));
+            }
+            sender.send(request).unwrap();
+        }
+        drop(sender);
+
+        let mut session = Session {
+            requests: receiver,
+            pending: VecDeque::new(),
+            output: Output { writer: Vec::new(), seq: 0 },
+            sysfn: Sysfn { input: Cursor::new(Vec::new()), output: Vec::new() },
+            program: None,
+            stop_on_entry: false,
+        };
+        session.serve();
+
+        let mut reader = Cursor::new(session.output.writer);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader) {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn scripted_session() {
+        let path = std::env::temp_dir().join(format!("dap-test-{}.asm", std::process::id()));
+        fs::write(&path, PROGRAM).unwrap();
+        let path = path.to_str().unwrap();
+
+        let messages = session(&[
+            object! { "command" => "initialize", "arguments" => object! { "adapterID" => "my_vm" } },
+            object! { "command" => "launch", "arguments" => object! { "program" => path } },
+            object! {
+                "command" => "setBreakpoints",
+                "arguments" => object! {
+                    "source" => object! { "path" => path },
+                    "breakpoints" => vec![object! { "line" => 6 }, object! { "line" => 40 }],
+                },
+            },
+            object! { "command" => "configurationDone" },
+            object! { "command" => "stackTrace", "arguments" => object! { "threadId" => THREAD_ID } },
+            object! { "command" => "continue", "arguments" => object! { "threadId" => THREAD_ID } },
+            object! { "command" => "disconnect" },
+        ]);
+        fs::remove_file(path).unwrap();
+
+        let summary: Vec<String> = messages
+            .iter()
+            .enumerate()
+            .map(|(index, message)| {
+                assert_eq!(message.get("seq").as_i64(), Some(index as i64 + 1));
+                match message.get("type").as_str() {
+                    Some("response") => {
+                        assert_eq!(message.get("success").as_bool(), Some(true), "{}", message);
+                        format!("response {}", message.get("command").as_str().unwrap())
+                    }
+                    _ => format!("event {}", message.get("event").as_str().unwrap()),
+                }
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "response initialize",
+                "event initialized",
+                "response launch",
+                "response setBreakpoints",
+                "response configurationDone",
+                "event output",
+                "event stopped",
+                "response stackTrace",
+                "response continue",
+                "event exited",
+                "event terminated",
+                "response disconnect",
+            ]
+        );
+
+        let capabilities = messages[0].get("body");
+        assert_eq!(capabilities.get("supportsConfigurationDoneRequest").as_bool(), Some(true));
+
+        // The breakpoint on the empty line moves to the next line with code; line 40 has none.
+        let breakpoints = messages[3].get("body").get("breakpoints").as_array();
+        assert_eq!(breakpoints[0].get("verified").as_bool(), Some(true));
+        assert_eq!(breakpoints[0].get("line").as_i64(), Some(7));
+        assert_eq!(breakpoints[1].get("verified").as_bool(), Some(false));
+
+        assert_eq!(messages[5].get("body").get("output").as_str(), Some("A"));
+        assert_eq!(messages[6].get("body").get("reason").as_str(), Some("breakpoint"));
+
+        let frames = messages[7].get("body").get("stackFrames").as_array();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].get("line").as_i64(), Some(7));
+        assert_eq!(frames[0].get("instructionPointerReference").as_str(), Some("0x100C"));
+        assert_eq!(frames[0].get("source").get("path").as_str(), Some(path));
+
+        assert_eq!(messages[9].get("body").get("exitCode").as_i64(), Some(5));
+    }
+}
@@ -0,0 +1,647 @@
+use std::fs;
+use std::io::{stdin, stdout, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+
+use my_vm::disasm::{Disasm, REG_NAMES};
+use my_vm::isa::{Instruction, Rc, Rrc};
+use my_vm::{snapshot, vm};
+
+struct Error;
+
+// A number or the current value of a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Num(u32),
+    Reg(usize),
+    Pc,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Step(u32),
+    Next,
+    Finish,
+    Continue,
+    Break(Value),
+    Delete(Value),
+    Watch(vm::WatchKind, Value, u32),
+    Unwatch(usize),
+    Info,
+    Regs,
+    Print(String, Value),
+    // Units of 0 bytes are strings.
+    Examine { count: u32, unit: u32, addr: Value },
+    Disas(Option<Value>, Option<u32>),
+    Save(String),
+    Quit,
+    Help,
+}
+
+struct Debugger<R, W> {
+    machine: vm::Machine,
+    // The debugger shares the console with the program.
+    sysfn: vm::Console<R, W>,
+    exit_status: Option<u32>,
+}
+
+const HELP: &str = "\
+Commands:
+  s, step [N]             execute N instructions (default 1)
+  n, next                 execute one instruction, stepping over calls
+  finish                  run until the current function returns to %lr
+  c, continue             run until a breakpoint, watchpoint or exit
+  b, break ADDR           set a breakpoint
+  w, watch [r|w|a] ADDR [LEN]
+                          set a read, write or access watchpoint
+  d, delete ADDR          delete a breakpoint
+  unwatch ID              delete a watchpoint
+  info                    list breakpoints and watchpoints
+  r, regs                 print all registers
+  p, print REG            print a register
+  x/[N][b|h|w|s] ADDR     examine memory as bytes, halfwords, words or strings
+  disas [ADDR [N]]        disassemble N instructions (default: around pc)
+  save FILE               save a snapshot of the machine state
+  q, quit                 exit the debugger
+An empty line repeats the previous command. ADDR may be a number or a register.";
+
+// Returns `None` for an empty line and the message to print for an invalid
+// command.
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    let args: Vec<&str> = words.collect();
+
+    let usage = |usage: &str| format!("Usage: {}.", usage);
+    let invalid_arg = |arg: &str| format!("Invalid argument '{}'.", arg);
+    let value = |arg: &str| parse_value(arg).ok_or_else(|| invalid_arg(arg));
+    let count = |arg: &str| parse_num(arg).ok_or_else(|| invalid_arg(arg));
+
+    let command = match command {
+        "s" | "step" => match args.first() {
+            Some(arg) => Command::Step(count(arg)?),
+            None => Command::Step(1),
+        },
+        "n" | "next" => Command::Next,
+        "finish" => Command::Finish,
+        "c" | "continue" => Command::Continue,
+        "b" | "break" => match args.first() {
+            Some(arg) => Command::Break(value(arg)?),
+            None => return Err(usage("break ADDR")),
+        },
+        "d" | "delete" => match args.first() {
+            Some(arg) => Command::Delete(value(arg)?),
+            None => return Err(usage("delete ADDR")),
+        },
+        "w" | "watch" => {
+            let (kind, args) = match args.first() {
+                Some(&"r") => (vm::WatchKind::Read, &args[1..]),
+                Some(&"w") => (vm::WatchKind::Write, &args[1..]),
+                Some(&"a") => (vm::WatchKind::Access, &args[1..]),
+                _ => (vm::WatchKind::Write, &args[..]),
+            };
+            let addr = args.first().and_then(|arg| parse_value(arg));
+            let len = match args.get(1) {
+                Some(arg) => parse_num(arg),
+                None => Some(4),
+            };
+            match (addr, len) {
+                (Some(addr), Some(len)) if len != 0 => Command::Watch(kind, addr, len),
+                _ => return Err(usage("watch [r|w|a] ADDR [LEN]")),
+            }
+        }
+        "unwatch" => match args.first().and_then(|arg| arg.parse::<usize>().ok()) {
+            Some(id) => Command::Unwatch(id),
+            None => return Err(usage("unwatch ID")),
+        },
+        "info" => Command::Info,
+        "r" | "regs" => Command::Regs,
+        "p" | "print" => match args.first() {
+            Some(arg) => Command::Print(arg.to_string(), value(arg)?),
+            None => return Err(usage("print REG")),
+        },
+        "disas" => {
+            let addr = args.first().map(|arg| value(arg)).transpose()?;
+            let count = args.get(1).map(|arg| count(arg)).transpose()?;
+            Command::Disas(addr, count)
+        }
+        "save" => match args.first() {
+            Some(path) => Command::Save(path.to_string()),
+            None => return Err(usage("save FILE")),
+        },
+        "q" | "quit" => Command::Quit,
+        "h" | "help" => Command::Help,
+        _ => {
+            let unknown = || format!("Unknown command '{}'. Type 'help' for a list of commands.", command);
+            let format = match command.strip_prefix("x") {
+                Some("") => "",
+                Some(format) => format.strip_prefix('/').ok_or_else(unknown)?,
+                None => return Err(unknown()),
+            };
+            let usage = || usage("x/[N][b|h|w|s] ADDR");
+            let digits = format.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(format.len());
+            let count = if digits == 0 { 1 } else { format[..digits].parse::<u32>().map_err(|_| usage())? };
+            let unit = match &format[digits..] {
+                "" | "w" => 4,
+                "h" => 2,
+                "b" => 1,
+                "s" => 0,
+                _ => return Err(usage()),
+            };
+            match args.first().and_then(|arg| parse_value(arg)) {
+                Some(addr) => Command::Examine { count, unit, addr },
+                None => return Err(usage()),
+            }
+        }
+    };
+    Ok(Some(command))
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    let name = s.strip_prefix('%').unwrap_or(s);
+    if name == "pc" {
+        return Some(Value::Pc);
+    }
+    if let Some(reg) = parse_reg(name) {
+        return Some(Value::Reg(reg));
+    }
+    parse_num(s).map(Value::Num)
+}
+
+fn parse_num(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse::<u32>().ok()
+    }
+}
+
+fn parse_reg(name: &str) -> Option<usize> {
+    if let Some(index) = REG_NAMES.iter().position(|&reg| reg == name) {
+        return Some(index);
+    }
+    let index = name.strip_prefix('x')?.parse::<usize>().ok()?;
+    if index < 16 {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    // Returns false if the debugger should exit.
+    fn execute(&mut self, line: &str) -> bool {
+        let command = match parse_command(line) {
+            Ok(Some(command)) => command,
+            Ok(None) => return true,
+            Err(message) => {
+                writeln!(self.sysfn.output, "{}", message).unwrap();
+                return true;
+            }
+        };
+
+        macro_rules! say {
+            ($($arg:tt)*) => {
+                writeln!(self.sysfn.output, $($arg)*).unwrap()
+            };
+        }
+
+        match command {
+            Command::Step(count) => {
+                self.resume(|machine, sysfn| {
+                    for _ in 0..count {
+                        match machine.step(sysfn) {
+                            vm::StopReason::Stepped => {}
+                            reason => return reason,
+                        }
+                    }
+                    vm::StopReason::Stepped
+                });
+            }
+            Command::Next => {
+                let pc = self.machine.state.pc;
+                let is_call = match vm::load_u32(&self.machine.memory, pc).map(Instruction::decode) {
+                    Some(Ok(Instruction::Jal(Rc { r1, .. }) | Instruction::Jalr(Rrc { r1, .. }))) => r1 != 0,
+                    _ => false,
+                };
+                if is_call {
+                    let ret = pc.wrapping_add(4);
+                    let sp = self.machine.state.regs[2];
+                    self.resume(|machine, sysfn| match machine.step(sysfn) {
+                        vm::StopReason::Stepped => {
+                            machine.run_until(|state| state.pc == ret && state.regs[2] >= sp, sysfn)
+                        }
+                        reason => reason,
+                    });
+                } else {
+                    self.resume(|machine, sysfn| machine.step(sysfn));
+                }
+            }
+            Command::Finish => {
+                // Comparing %sp as well stops in the right frame of a recursive function.
+                let ret = self.machine.state.regs[1];
+                let sp = self.machine.state.regs[2];
+                self.resume(|machine, sysfn| {
+                    machine.run_until(|state| state.pc == ret && state.regs[2] >= sp, sysfn)
+                });
+            }
+            Command::Continue => {
+                self.resume(|machine, sysfn| machine.run_until(|_| false, sysfn));
+            }
+            Command::Break(addr) => {
+                let addr = self.value(addr);
+                self.machine.add_breakpoint(addr);
+                say!("Breakpoint at 0x{:08X}.", addr);
+            }
+            Command::Delete(addr) => {
+                let addr = self.value(addr);
+                if !self.machine.remove_breakpoint(addr) {
+                    say!("No breakpoint at 0x{:08X}.", addr);
+                }
+            }
+            Command::Watch(kind, addr, len) => {
+                let addr = self.value(addr);
+                let id = self.machine.add_watchpoint(vm::Watchpoint { addr, len, kind });
+                say!("Watchpoint {} at 0x{:08X}, length {}.", id, addr, len);
+            }
+            Command::Unwatch(id) => {
+                if self.machine.remove_watchpoint(id).is_none() {
+                    say!("No watchpoint {}.", id);
+                }
+            }
+            Command::Info => {
+                for addr in self.machine.breakpoints() {
+                    say!("Breakpoint at 0x{:08X}", addr);
+                }
+                for (id, watchpoint) in self.machine.watchpoints() {
+                    say!(
+                        "Watchpoint {}: {:?} 0x{:08X}, length {}",
+                        id, watchpoint.kind, watchpoint.addr, watchpoint.len,
+                    );
+                }
+            }
+            Command::Regs => {
+                let state = &self.machine.state;
+                let out = &mut self.sysfn.output;
+                writeln!(out, "pc   0x{:08X}", state.pc).unwrap();
+                for (i, name) in REG_NAMES.iter().enumerate() {
+                    let separator = if i % 4 == 3 { "\n" } else { "    " };
+                    write!(out, "{:<4} 0x{:08X}{}", name, state.regs[i], separator).unwrap();
+                }
+            }
+            Command::Print(arg, value) => {
+                let value = self.value(value);
+                say!("{} = 0x{:08X} ({})", arg, value, value as i32);
+            }
+            Command::Disas(addr, count) => {
+                let pc = self.machine.state.pc;
+                let start = match addr {
+                    Some(addr) => self.value(addr) & !3,
+                    None => pc.saturating_sub(4 * 4),
+                };
+                for i in 0..count.unwrap_or(9) {
+                    let addr = start.wrapping_add(i * 4);
+                    match vm::load_u32(&self.machine.memory, addr) {
+                        Some(inst) => {
+                            let marker = if addr == pc { "=>" } else { "  " };
+                            say!("{} 0x{:08X}:  {}", marker, addr, Disasm::new(inst, addr));
+                        }
+                        None => break,
+                    }
+                }
+            }
+            Command::Save(path) => {
+                let path = Path::new(&path);
+                let mut buffer = Vec::new();
+                let device = vm::Sysfn::save(&self.sysfn);
+                let machine = &self.machine;
+                let result = snapshot::serialize(
+                    &machine.state,
+                    &machine.memory,
+                    machine.memory_map(),
+                    &device,
+                    &mut buffer,
+                );
+                let result = result
+                    .map_err(|err| err.to_string())
+                    .and_then(|()| fs::write(path, &buffer).map_err(|err| err.to_string()));
+                match result {
+                    Ok(()) => say!("Snapshot saved to {}.", path.display()),
+                    Err(err) => say!("Failed to save snapshot {}: {}.", path.display(), err),
+                }
+            }
+            Command::Quit => return false,
+            Command::Help => say!("{}", HELP),
+            Command::Examine { count, unit, addr } => self.examine(count, unit, self.value(addr)),
+        }
+
+        true
+    }
+
+    fn examine(&mut self, count: u32, unit: u32, mut addr: u32) {
+        let out = &mut self.sysfn.output;
+        let memory = &self.machine.memory;
+        if unit == 0 {
+            for _ in 0..count {
+                let mut bytes = Vec::new();
+                let start = addr;
+                while let Some(&byte) = memory.get(addr as usize) {
+                    addr = addr.wrapping_add(1);
+                    if byte == 0 {
+                        break;
+                    }
+                    bytes.push(byte);
+                }
+                writeln!(out, "0x{:08X}:  {:?}", start, String::from_utf8_lossy(&bytes)).unwrap();
+            }
+            return;
+        }
+
+        let per_line = 16 / unit;
+        for i in 0..count {
+            if i % per_line == 0 {
+                if i != 0 {
+                    writeln!(out).unwrap();
+                }
+                write!(out, "0x{:08X}:", addr).unwrap();
+            }
+            let start = addr as usize;
+            let bytes = match memory.get(start..start + unit as usize) {
+                Some(bytes) => bytes,
+                None => {
+                    write!(out, "  <invalid address>").unwrap();
+                    break;
+                }
+            };
+            match unit {
+                1 => write!(out, "  0x{:02X}", bytes[0]).unwrap(),
+                2 => write!(out, "  0x{:04X}", u16::from_le_bytes([bytes[0], bytes[1]])).unwrap(),
+                _ => write!(out, "  0x{:08X}", u32::from_le_bytes(bytes.try_into().unwrap())).unwrap(),
+            }
+            addr = addr.wrapping_add(unit);
+        }
+        writeln!(out).unwrap();
+    }
+
+    fn resume<F>(&mut self, run: F)
+    where
+        F: FnOnce(&mut vm::Machine, &mut vm::Console<R, W>) -> vm::StopReason,
+    {
+        if let Some(status) = self.exit_status {
+            writeln!(self.sysfn.output, "The program has exited with status {}.", status).unwrap();
+            return;
+        }
+
+        let reason = run(&mut self.machine, &mut self.sysfn);
+        match reason {
+            vm::StopReason::Stepped => {}
+            // Reached the target of `next` or `finish`.
+            vm::StopReason::Condition => {}
+            vm::StopReason::Exited(status) => {
+                self.exit_status = Some(status);
+                writeln!(self.sysfn.output, "The program has exited with status {}.", status).unwrap();
+                return;
+            }
+            reason => writeln!(self.sysfn.output, "Stopped: {}.", reason).unwrap(),
+        }
+        self.print_current();
+    }
+
+    fn print_current(&mut self) {
+        let pc = self.machine.state.pc;
+        let out = &mut self.sysfn.output;
+        match vm::load_u32(&self.machine.memory, pc) {
+            Some(inst) => writeln!(out, "=> 0x{:08X}:  {}", pc, Disasm::new(inst, pc)).unwrap(),
+            None => writeln!(out, "=> 0x{:08X}:  <invalid address>", pc).unwrap(),
+        }
+    }
+
+    fn value(&self, value: Value) -> u32 {
+        match value {
+            Value::Num(value) => value,
+            Value::Reg(reg) => self.machine.state.regs[reg],
+            Value::Pc => self.machine.state.pc,
+        }
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<_> = std::env::args_os().collect();
+
+    if args.len() != 2 {
+        eprintln!("Usage: {} FILE.", Path::new(&args[0]).display());
+        return Err(Error);
+    }
+
+    let file_name = Path::new(&args[1]);
+    let file_data = match fs::read(file_name) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let mut sysfn = vm::Console {
+        input: BufReader::new(stdin()),
+        output: LineWriter::new(stdout()),
+    };
+
+    let machine = match snapshot::load(&file_data, &mut sysfn) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let mut debugger = Debugger {
+        machine,
+        sysfn,
+        exit_status: None,
+    };
+
+    debugger.print_current();
+
+    let mut last_line = String::new();
+    loop {
+        write!(debugger.sysfn.output, "(debug) ").unwrap();
+        debugger.sysfn.output.flush().unwrap();
+
+        let mut line = String::new();
+        match debugger.sysfn.input.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        if line.trim().is_empty() {
+            line = last_line.clone();
+        } else {
+            last_line = line.clone();
+        }
+
+        if !debugger.execute(&line) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if run().is_err() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The code at 0x1000 calls a function; the data is at 0x100.
+    const CODE: [u32; 7] = [
+        0x0001_0388, // 0x1000: addi    %a0, %zero, 1
+        0x0000_31A0, // 0x1004: call    0x1014
+        0x000A_3388, // 0x1008: addi    %a0, %a0, 10
+        0x0100_0386, // 0x100C: st      %a0, %zero, 0x100
+        0x0000_0383, // 0x1010: sysfn   %a0, 0
+        0x0001_3388, // 0x1014: addi    %a0, %a0, 1
+        0x0000_10A1, // 0x1018: ret
+    ];
+    const DATA: &[u8] = b"\x78\x56\x34\x12hi\0";
+
+    fn start() -> Debugger<&'static [u8], Vec<u8>> {
+        let mut machine = vm::Machine::new(vec![0; 0x2000]);
+        for (i, word) in CODE.iter().enumerate() {
+            machine.memory[0x1000 + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+        machine.memory[0x100..0x100 + DATA.len()].copy_from_slice(DATA);
+        machine.state.regs[2] = 0x2000;
+        let sysfn = vm::Console { input: &b""[..], output: Vec::new() };
+        Debugger { machine, sysfn, exit_status: None }
+    }
+
+    // Executes the command and returns its output.
+    fn run(debugger: &mut Debugger<&[u8], Vec<u8>>, line: &str) -> String {
+        assert!(debugger.execute(line));
+        String::from_utf8(std::mem::take(&mut debugger.sysfn.output)).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        let parse = |line| parse_command(line).unwrap().unwrap();
+        assert_eq!(parse("s"), Command::Step(1));
+        assert_eq!(parse("  step   0x10 "), Command::Step(16));
+        assert_eq!(parse("n"), Command::Next);
+        assert_eq!(parse("b %lr"), Command::Break(Value::Reg(1)));
+        assert_eq!(parse("delete 4096"), Command::Delete(Value::Num(4096)));
+        assert_eq!(parse("watch r x3 8"), Command::Watch(vm::WatchKind::Read, Value::Reg(3), 8));
+        assert_eq!(parse("w 0x100"), Command::Watch(vm::WatchKind::Write, Value::Num(0x100), 4));
+        assert_eq!(parse("p pc"), Command::Print("pc".to_owned(), Value::Pc));
+        assert_eq!(parse("x/4b sp"), Command::Examine { count: 4, unit: 1, addr: Value::Reg(2) });
+        assert_eq!(parse("x 0x100"), Command::Examine { count: 1, unit: 4, addr: Value::Num(0x100) });
+        assert_eq!(parse("x/s a0"), Command::Examine { count: 1, unit: 0, addr: Value::Reg(3) });
+        assert_eq!(parse("disas"), Command::Disas(None, None));
+        assert_eq!(parse("disas pc 3"), Command::Disas(Some(Value::Pc), Some(3)));
+        assert_eq!(parse("save out.snp"), Command::Save("out.snp".to_owned()));
+        assert!(matches!(parse_command("  "), Ok(None)));
+
+        let error = |line| parse_command(line).unwrap_err();
+        assert_eq!(error("step a0"), "Invalid argument 'a0'.");
+        assert_eq!(error("step -1"), "Invalid argument '-1'.");
+        assert_eq!(error("disas 0x1000 %sp"), "Invalid argument '%sp'.");
+        assert_eq!(error("break"), "Usage: break ADDR.");
+        assert_eq!(error("break x16"), "Invalid argument 'x16'.");
+        assert_eq!(error("watch r 0x100 a1"), "Usage: watch [r|w|a] ADDR [LEN].");
+        assert_eq!(error("watch 0x100 0"), "Usage: watch [r|w|a] ADDR [LEN].");
+        assert_eq!(error("unwatch a"), "Usage: unwatch ID.");
+        assert_eq!(error("x/4q 0x100"), "Usage: x/[N][b|h|w|s] ADDR.");
+        assert_eq!(error("x/4"), "Usage: x/[N][b|h|w|s] ADDR.");
+        assert!(error("xyz").starts_with("Unknown command 'xyz'."));
+        assert!(error("frobnicate").starts_with("Unknown command 'frobnicate'."));
+    }
+
+    #[test]
+    fn step_next_finish() {
+        let mut debugger = start();
+        assert_eq!(run(&mut debugger, "step 2"), "=> 0x00001014:  addi    %a0, %a0, 1\n");
+        assert_eq!(run(&mut debugger, "finish"), "=> 0x00001008:  addi    %a0, %a0, 10\n");
+        assert_eq!(debugger.machine.state.regs[3], 2);
+
+        let mut debugger = start();
+        run(&mut debugger, "s");
+        assert_eq!(run(&mut debugger, "next"), "=> 0x00001008:  addi    %a0, %a0, 10\n");
+        assert_eq!(run(&mut debugger, "next"), "=> 0x0000100C:  st      %a0, %zero, 256\n");
+        assert_eq!(run(&mut debugger, "c"), "The program has exited with status 12.\n");
+        assert_eq!(run(&mut debugger, "s"), "The program has exited with status 12.\n");
+    }
+
+    #[test]
+    fn break_and_watch() {
+        let mut debugger = start();
+        assert_eq!(run(&mut debugger, "break 0x1014"), "Breakpoint at 0x00001014.\n");
+        assert_eq!(run(&mut debugger, "watch 0x100"), "Watchpoint 0 at 0x00000100, length 4.\n");
+        assert_eq!(
+            run(&mut debugger, "info"),
+            "Breakpoint at 0x00001014\nWatchpoint 0: Write 0x00000100, length 4\n",
+        );
+
+        let output = run(&mut debugger, "c");
+        assert!(output.starts_with("Stopped: breakpoint at 0x1014.\n=> 0x00001014:"), "{}", output);
+        run(&mut debugger, "delete 0x1014");
+        assert_eq!(run(&mut debugger, "delete 0x1014"), "No breakpoint at 0x00001014.\n");
+
+        let output = run(&mut debugger, "c");
+        assert!(output.starts_with("Stopped: watchpoint 0 at 0x100C: [0x100] 0x12345678 -> 0xC.\n"));
+        run(&mut debugger, "unwatch 0");
+        assert_eq!(run(&mut debugger, "unwatch 0"), "No watchpoint 0.\n");
+        assert_eq!(run(&mut debugger, "c"), "The program has exited with status 12.\n");
+    }
+
+    #[test]
+    fn examine_and_print() {
+        let mut debugger = start();
+        assert_eq!(run(&mut debugger, "x 0x100"), "0x00000100:  0x12345678\n");
+        assert_eq!(run(&mut debugger, "x/2h 0x100"), "0x00000100:  0x5678  0x1234\n");
+        assert_eq!(
+            run(&mut debugger, "x/6b 0xFE"),
+            "0x000000FE:  0x00  0x00  0x78  0x56  0x34  0x12\n",
+        );
+        assert_eq!(run(&mut debugger, "x/5w 0x100").lines().count(), 2);
+        assert_eq!(run(&mut debugger, "x/s 0x104"), "0x00000104:  \"hi\"\n");
+        assert_eq!(run(&mut debugger, "x/2w 0x1FFC"), "0x00001FFC:  0x00000000  <invalid address>\n");
+
+        assert_eq!(run(&mut debugger, "print %sp"), "%sp = 0x00002000 (8192)\n");
+        assert_eq!(run(&mut debugger, "p pc"), "pc = 0x00001000 (4096)\n");
+        debugger.machine.state.regs[3] = -2_i32 as u32;
+        assert_eq!(run(&mut debugger, "p x3"), "x3 = 0xFFFFFFFE (-2)\n");
+        assert_eq!(run(&mut debugger, "p 0x10"), "0x10 = 0x00000010 (16)\n");
+        assert_eq!(run(&mut debugger, "p t0"), "Invalid argument 't0'.\n");
+
+        let regs = run(&mut debugger, "regs");
+        assert!(regs.starts_with("pc   0x00001000\nzero 0x00000000    lr   0x00000000"), "{}", regs);
+        assert_eq!(regs.lines().count(), 5);
+    }
+
+    #[test]
+    fn save() {
+        let mut debugger = start();
+        run(&mut debugger, "step 3");
+        let path = std::env::temp_dir().join(format!("debug-test-{}.snp", std::process::id()));
+        let output = run(&mut debugger, &format!("save {}", path.display()));
+        assert_eq!(output, format!("Snapshot saved to {}.\n", path.display()));
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut sysfn = vm::Console { input: &b""[..], output: Vec::new() };
+        let mut machine = snapshot::load(&data, &mut sysfn).unwrap();
+        assert_eq!(machine.state.pc, 0x1018);
+        assert_eq!(machine.state.regs, debugger.machine.state.regs);
+        assert!(matches!(machine.run(&mut sysfn), vm::StopReason::Exited(12)));
+
+        let output = run(&mut debugger, "save /nonexistent/dir/file.snp");
+        assert!(output.starts_with("Failed to save snapshot /nonexistent/dir/file.snp: "), "{}", output);
+    }
+}
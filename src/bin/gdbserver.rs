@@ -0,0 +1,667 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, stdin, BufRead, BufReader, Read, Stdin, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+use my_vm::disasm::REG_NAMES;
+use my_vm::{binfile, vm};
+
+const DEFAULT_PORT: u16 = 1234;
+
+// Number of instructions executed between checks for an interrupt request.
+const RUN_CHUNK: u64 = 100_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+type Sysfn = vm::Console<BufReader<Stdin>, Vec<u8>>;
+
+struct Error;
+
+struct Server {
+    machine: vm::Machine,
+    sysfn: Sysfn,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    no_ack: bool,
+    closed: bool,
+    exit_status: Option<u32>,
+}
+
+enum Packet {
+    Command(Vec<u8>),
+    Interrupt,
+}
+
+impl Server {
+    fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(Packet::Command(packet)) => packet,
+                Some(Packet::Interrupt) => {
+                    self.send_packet(format!("S{:02x}", SIGINT).as_bytes())?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            if let Some(reply) = self.handle(&packet)? {
+                self.send_packet(reply.as_bytes())?;
+            }
+            if self.closed {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> io::Result<Option<String>> {
+        let packet = String::from_utf8_lossy(packet);
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => self.stop_reply(&vm::StopReason::Breakpoint(self.machine.state.pc)),
+            "g" => {
+                let mut reply = String::new();
+                for reg in self.machine.state.regs.iter().chain([self.machine.state.pc].iter()) {
+                    write_hex_u32(&mut reply, *reg);
+                }
+                reply
+            }
+            "G" => match parse_hex_bytes(args) {
+                Some(bytes) if bytes.len() == 17 * 4 => {
+                    for (i, chunk) in bytes.chunks_exact(4).enumerate() {
+                        self.set_reg(i, u32::from_le_bytes(chunk.try_into().unwrap()));
+                    }
+                    "OK".into()
+                }
+                _ => "E01".into(),
+            },
+            "p" => match u32::from_str_radix(args, 16) {
+                Ok(reg) if reg <= 16 => {
+                    let mut reply = String::new();
+                    write_hex_u32(&mut reply, self.get_reg(reg as usize));
+                    reply
+                }
+                _ => "E01".into(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    let value = parse_hex_bytes(value)?;
+                    Some((reg, u32::from_le_bytes(value.try_into().ok()?)))
+                });
+                match parsed {
+                    Some((reg, value)) if reg <= 16 => {
+                        self.set_reg(reg, value);
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            "m" => match parse_addr_len(args).and_then(|(addr, len)| self.memory(addr, len)) {
+                Some(bytes) => {
+                    let mut reply = String::new();
+                    for byte in bytes {
+                        write!(reply, "{:02x}", byte).unwrap();
+                    }
+                    reply
+                }
+                None => "E01".into(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_addr_len(range)?;
+                    let data = parse_hex_bytes(data)?;
+                    if data.len() != len as usize {
+                        return None;
+                    }
+                    Some((addr, data))
+                });
+                match parsed {
+                    Some((addr, data)) => {
+                        let start = addr as usize;
+                        match self.machine.memory.get_mut(start..start + data.len()) {
+                            Some(memory) => {
+                                memory.copy_from_slice(&data);
+                                "OK".into()
+                            }
+                            None => "E01".into(),
+                        }
+                    }
+                    None => "E01".into(),
+                }
+            }
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match u32::from_str_radix(args, 16) {
+                        Ok(addr) => self.machine.state.pc = addr,
+                        Err(_) => return Ok(Some("E01".into())),
+                    }
+                }
+                let reason = if command == "s" { Some(self.step()) } else { self.resume()? };
+                self.flush_output()?;
+                match reason {
+                    Some(reason) => self.stop_reply(&reason),
+                    None => format!("S{:02x}", SIGINT),
+                }
+            }
+            "Z" | "z" => {
+                let insert = command == "Z";
+                let mut fields = args.split(',');
+                let kind = fields.next().unwrap_or("");
+                let addr = fields.next().and_then(|addr| u32::from_str_radix(addr, 16).ok());
+                let len = fields.next().and_then(|len| u32::from_str_radix(len, 16).ok());
+                match (kind, addr, len) {
+                    ("0" | "1", Some(addr), _) => {
+                        if insert {
+                            self.machine.add_breakpoint(addr);
+                        } else {
+                            self.machine.remove_breakpoint(addr);
+                        }
+                        "OK".into()
+                    }
+                    ("2" | "3" | "4", Some(addr), Some(len)) => {
+                        let kind = match kind {
+                            "2" => vm::WatchKind::Write,
+                            "3" => vm::WatchKind::Read,
+                            _ => vm::WatchKind::Access,
+                        };
+                        if insert {
+                            self.machine.add_watchpoint(vm::Watchpoint { addr, len, kind });
+                        } else {
+                            let id = self.machine.watchpoints().find_map(|(id, w)| {
+                                (w.addr == addr && w.len == len && w.kind == kind).then_some(id)
+                            });
+                            if let Some(id) = id {
+                                self.machine.remove_watchpoint(id);
+                            }
+                        }
+                        "OK".into()
+                    }
+                    _ => String::new(),
+                }
+            }
+            "q" => {
+                if args.starts_with("Supported") {
+                    "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+".into()
+                } else if let Some(annex) = args.strip_prefix("Xfer:features:read:") {
+                    match annex.split_once(':') {
+                        Some(("target.xml", range)) => match parse_addr_len(range) {
+                            Some((offset, len)) => xfer_reply(&target_xml(), offset, len),
+                            None => "E01".into(),
+                        },
+                        _ => "E00".into(),
+                    }
+                } else if args == "Attached" {
+                    "1".into()
+                } else if args == "C" {
+                    "QC1".into()
+                } else if args == "fThreadInfo" {
+                    "m1".into()
+                } else if args == "sThreadInfo" {
+                    "l".into()
+                } else {
+                    String::new()
+                }
+            }
+            "Q" => {
+                if args == "StartNoAckMode" {
+                    // The reply itself is still acknowledged.
+                    self.send_packet(b"OK")?;
+                    self.no_ack = true;
+                    return Ok(None);
+                }
+                String::new()
+            }
+            "H" | "T" => "OK".into(),
+            "D" => {
+                self.send_packet(b"OK")?;
+                self.closed = true;
+                return Ok(None);
+            }
+            "k" => {
+                self.closed = true;
+                return Ok(None);
+            }
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    fn get_reg(&self, reg: usize) -> u32 {
+        if reg == 16 {
+            self.machine.state.pc
+        } else {
+            self.machine.state.regs[reg]
+        }
+    }
+
+    fn set_reg(&mut self, reg: usize, value: u32) {
+        match reg {
+            0 => {}
+            16 => self.machine.state.pc = value,
+            _ => self.machine.state.regs[reg] = value,
+        }
+    }
+
+    fn memory(&self, addr: u32, len: u32) -> Option<&[u8]> {
+        let start = addr as usize;
+        self.machine.memory.get(start..start.checked_add(len as usize)?)
+    }
+
+    fn step(&mut self) -> vm::StopReason {
+        if let Some(status) = self.exit_status {
+            return vm::StopReason::Exited(status);
+        }
+        self.machine.step(&mut self.sysfn)
+    }
+
+    // Returns `None` if execution was interrupted by the client.
+    fn resume(&mut self) -> io::Result<Option<vm::StopReason>> {
+        if let Some(status) = self.exit_status {
+            return Ok(Some(vm::StopReason::Exited(status)));
+        }
+        loop {
+            match self.machine.run_for(RUN_CHUNK, &mut self.sysfn) {
+                vm::StopReason::BudgetExhausted => {}
+                reason => return Ok(Some(reason)),
+            }
+            self.flush_output()?;
+            if self.poll_interrupt()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn stop_reply(&mut self, reason: &vm::StopReason) -> String {
+        match reason {
+            vm::StopReason::Exited(status) => {
+                self.exit_status = Some(*status);
+                format!("W{:02x}", *status as u8)
+            }
+            vm::StopReason::Breakpoint(pc) if self.machine.breakpoints().any(|b| b == *pc) => {
+                format!("T{:02x}swbreak:;", SIGTRAP)
+            }
+            vm::StopReason::Watchpoint(hit) => {
+                // GDB matches the stop against its own watchpoints by their start address.
+                let (kind, addr) = match self.machine.watchpoint(hit.id) {
+                    Some(w) if w.kind == vm::WatchKind::Read => ("rwatch", w.addr),
+                    Some(w) if w.kind == vm::WatchKind::Write => ("watch", w.addr),
+                    Some(w) => ("awatch", w.addr),
+                    None => ("awatch", hit.access.addr),
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr)
+            }
+            vm::StopReason::Faulted(vm::Error::InvalidAddr(_) | vm::Error::InvalidPc(_)) => {
+                format!("S{:02x}", SIGSEGV)
+            }
+            vm::StopReason::Faulted(_) => format!("S{:02x}", SIGILL),
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let result = match self.reader.fill_buf() {
+            Ok(buf) => Ok(buf.first() == Some(&0x03)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        if let Ok(true) = result {
+            self.reader.consume(1);
+        }
+        result
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        if self.sysfn.output.is_empty() {
+            return Ok(());
+        }
+        let mut packet = String::from("O");
+        for byte in std::mem::take(&mut self.sysfn.output) {
+            write!(packet, "{:02x}", byte).unwrap();
+        }
+        self.send_packet(packet.as_bytes())
+    }
+
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let mut byte = [0_u8];
+            if self.reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => break,
+                0x03 => return Ok(Some(Packet::Interrupt)),
+                _ => {}
+            }
+        }
+
+        let mut data = Vec::new();
+        self.reader.read_until(b'#', &mut data)?;
+        if data.pop() != Some(b'#') {
+            return Ok(None);
+        }
+
+        let mut checksum = [0_u8; 2];
+        self.reader.read_exact(&mut checksum)?;
+
+        if !self.no_ack {
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected == Some(compute_checksum(&data)) {
+                self.writer.write_all(b"+")?;
+            } else {
+                self.writer.write_all(b"-")?;
+                return self.read_packet();
+            }
+        }
+
+        Ok(Some(Packet::Command(unescape(&data))))
+    }
+
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.push(b'#');
+        packet.extend_from_slice(format!("{:02x}", compute_checksum(data)).as_bytes());
+
+        loop {
+            self.writer.write_all(&packet)?;
+            self.writer.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            let mut ack = [0_u8];
+            if self.reader.read(&mut ack)? == 0 || ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn compute_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == b'}' {
+            if let Some(&next) = iter.next() {
+                result.push(next ^ 0x20);
+            }
+        } else {
+            result.push(byte);
+        }
+    }
+    result
+}
+
+fn write_hex_u32(s: &mut String, value: u32) {
+    for byte in value.to_le_bytes() {
+        write!(s, "{:02x}", byte).unwrap();
+    }
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_addr_len(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+fn xfer_reply(data: &str, offset: u32, len: u32) -> String {
+    let offset = (offset as usize).min(data.len());
+    let end = offset.saturating_add(len as usize).min(data.len());
+    let prefix = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", prefix, &data[offset..end])
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <feature name=\"org.my_vm.core\">\n",
+    ));
+    for (i, name) in REG_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "lr" => "code_ptr",
+            "sp" => "data_ptr",
+            _ => "uint32",
+        };
+        writeln!(
+            xml,
+            "    <reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, i,
+        )
+        .unwrap();
+    }
+    xml.push_str("    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"16\"/>\n");
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<_> = std::env::args_os().collect();
+
+    let (port, file_name) = match args.len() {
+        2 => (DEFAULT_PORT, Path::new(&args[1])),
+        4 if args[1] == "--port" => match args[2].to_str().and_then(|s| s.parse().ok()) {
+            Some(port) => (port, Path::new(&args[3])),
+            None => {
+                eprintln!("Invalid port {}.", args[2].to_string_lossy());
+                return Err(Error);
+            }
+        },
+        _ => {
+            eprintln!("Usage: {} [--port PORT] FILE.", Path::new(&args[0]).display());
+            return Err(Error);
+        }
+    };
+
+    let file_data = match fs::read(file_name) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let machine = match binfile::load(&file_data) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Failed to listen on port {}: {}.", port, err);
+            return Err(Error);
+        }
+    };
+    eprintln!("Listening on 127.0.0.1:{}.", port);
+
+    let stream = match listener.accept() {
+        Ok((stream, _)) => stream,
+        Err(err) => {
+            eprintln!("Failed to accept connection: {}.", err);
+            return Err(Error);
+        }
+    };
+    let _ = stream.set_nodelay(true);
+
+    let writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("Failed to accept connection: {}.", err);
+            return Err(Error);
+        }
+    };
+
+    let mut server = Server {
+        machine,
+        sysfn: Sysfn { input: BufReader::new(stdin()), output: Vec::new() },
+        reader: BufReader::new(stream),
+        writer,
+        no_ack: false,
+        closed: false,
+        exit_status: None,
+    };
+
+    if let Err(err) = server.serve() {
+        eprintln!("Connection error: {}.", err);
+        return Err(Error);
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if run().is_err() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    // A scripted RSP client.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        no_ack: bool,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, compute_checksum(data.as_bytes()));
+            self.writer.write_all(packet.as_bytes()).unwrap();
+            if !self.no_ack {
+                let mut ack = [0_u8];
+                self.reader.read_exact(&mut ack).unwrap();
+                assert_eq!(ack[0], b'+');
+            }
+        }
+
+        fn receive(&mut self) -> String {
+            let mut data = Vec::new();
+            self.reader.read_until(b'$', &mut data).unwrap();
+            data.clear();
+            self.reader.read_until(b'#', &mut data).unwrap();
+            data.pop();
+            let mut checksum = [0_u8; 2];
+            self.reader.read_exact(&mut checksum).unwrap();
+            assert_eq!(checksum, format!("{:02x}", compute_checksum(&data)).as_bytes());
+            if !self.no_ack {
+                self.writer.write_all(b"+").unwrap();
+            }
+            String::from_utf8(data).unwrap()
+        }
+
+        fn command(&mut self, data: &str) -> String {
+            self.send(data);
+            self.receive()
+        }
+    }
+
+    // Runs a server for `code` placed at 0x1000.
+    fn start(code: &[u32]) -> (Client, JoinHandle<()>) {
+        let mut machine = vm::Machine::new(vec![0; 0x2000]);
+        for (i, word) in code.iter().enumerate() {
+            machine.memory[0x1000 + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let mut server = Server {
+            machine,
+            sysfn: Sysfn { input: BufReader::new(stdin()), output: Vec::new() },
+            reader: BufReader::new(server_stream.try_clone().unwrap()),
+            writer: server_stream,
+            no_ack: false,
+            closed: false,
+            exit_status: None,
+        };
+        let handle = thread::spawn(move || server.serve().unwrap());
+
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        (Client { reader, writer: stream, no_ack: false }, handle)
+    }
+
+    #[test]
+    fn session() {
+        let (mut client, server) = start(&[
+            0x0041_0388, // 0x1000: addi    %a0, %zero, 'A'
+            0x0000_2383, // 0x1004: sysfn   %a0, 2
+            0x0007_0388, // 0x1008: addi    %a0, %zero, 7
+            0x0100_0386, // 0x100C: st      %a0, %zero, 0x100
+            0x0000_0383, // 0x1010: sysfn   %a0, 0
+        ]);
+
+        assert!(client.command("qSupported:swbreak+").contains("swbreak+"));
+        assert_eq!(client.command("?"), "S05");
+        let regs = client.command("g");
+        assert_eq!(regs.len(), 17 * 8);
+        assert!(regs.ends_with("00100000"));
+
+        assert_eq!(client.command("Z0,1008,4"), "OK");
+        assert_eq!(client.command("c"), "O41");
+        assert_eq!(client.receive(), "T05swbreak:;");
+        assert_eq!(client.command("p10"), "08100000");
+        assert_eq!(client.command("z0,1008,4"), "OK");
+
+        // The reply names the watchpoint's start, not the address of the store.
+        assert_eq!(client.command("Z2,fc,8"), "OK");
+        assert_eq!(client.command("c"), "T05watch:fc;");
+        assert_eq!(client.command("m100,4"), "07000000");
+        assert_eq!(client.command("M100,2:2a00"), "OK");
+        assert_eq!(client.command("m100,4"), "2a000000");
+        assert_eq!(client.command("P3=2a000000"), "OK");
+
+        assert_eq!(client.command("s"), "W2a");
+        assert_eq!(client.command("c"), "W2a");
+        assert_eq!(client.command("D"), "OK");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn interrupt() {
+        let (mut client, server) = start(&[0xFFFF_F0A0]); // jmp 0x1000
+
+        assert_eq!(client.command("QStartNoAckMode"), "OK");
+        client.no_ack = true;
+        client.send("c");
+        client.writer.write_all(&[0x03]).unwrap();
+        assert_eq!(client.receive(), "S02");
+        assert_eq!(client.command("p10"), "00100000");
+
+        client.send("k");
+        server.join().unwrap();
+    }
+}
@@ -1,19 +1,32 @@
+use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
 
-use my_vm::binfile;
+use my_vm::archive::{self, Archive};
+use my_vm::binfile::SymbolKind;
+use my_vm::disasm::{Disasm, REG_NAMES};
+use my_vm::isa::{Instruction, Rc, Rrc};
+use my_vm::object::{self, Object, Place, RelocKind, Target};
+use my_vm::symbols::SymbolMap;
+use my_vm::vm::Perms;
+use my_vm::{binfile, coredump, snapshot, vm};
+
+const MAX_FRAMES: usize = 32;
 
 struct Error;
 
 fn run() -> Result<(), Error> {
     let args: Vec<_> = std::env::args_os().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} FILE.", Path::new(&args[0]).display());
-        return Err(Error);
-    }
+    let (disasm, file_name) = match args.len() {
+        2 => (false, Path::new(&args[1])),
+        3 if args[1] == "--disasm" => (true, Path::new(&args[2])),
+        _ => {
+            eprintln!("Usage: {} [--disasm] FILE.", Path::new(&args[0]).display());
+            return Err(Error);
+        }
+    };
 
-    let file_name = Path::new(&args[1]);
     let file_data = match fs::read(file_name) {
         Ok(data) => data,
         Err(err) => {
@@ -22,6 +35,19 @@ fn run() -> Result<(), Error> {
         }
     };
 
+    if coredump::is_core(&file_data) {
+        return inspect_core(file_name, &file_data);
+    }
+    if snapshot::is_snapshot(&file_data) {
+        return inspect_snapshot(file_name, &file_data);
+    }
+    if object::is_object(&file_data) {
+        return inspect_object(file_name, &file_data);
+    }
+    if archive::is_archive(&file_data) {
+        return inspect_archive(file_name, &file_data);
+    }
+
     let file = match binfile::File::from_bytes(&file_data) {
         Ok(file) => file,
         Err(err) => {
@@ -30,20 +56,374 @@ fn run() -> Result<(), Error> {
         }
     };
 
+    let symbols = match file.symbols() {
+        Ok(symbols) => symbols,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    if disasm {
+        return disassemble(file_name, &file, &symbols);
+    }
+
     println!("File size:     0x{:X}", file_data.len());
     println!("Memory size:   0x{:X}", file.memory_size());
+    println!("Entry:         0x{:X}", file.entry());
+    if let Some(sp) = file.initial_sp() {
+        println!("Initial sp:    0x{:X}", sp);
+    }
     println!("Segment count: {}", file.segment_count());
 
     for (i, segment) in file.raw_segments().enumerate() {
         println!("Segment {}:", i);
-        println!("\tOffset:  0x{:X}", segment.offset);
+        if segment.zero_fill {
+            println!("\tOffset:  zero-filled");
+        } else {
+            println!("\tOffset:  0x{:X}", segment.offset);
+        }
         println!("\tAddress: 0x{:X}", segment.addr);
         println!("\tSize:    0x{:X}", segment.size);
+        println!("\tPerms:   {}", segment.perms);
+    }
+
+    if !symbols.is_empty() {
+        println!("Symbols:");
+        for symbol in &symbols {
+            let kind = match symbol.kind {
+                SymbolKind::Code     => "code",
+                SymbolKind::Data     => "data",
+                SymbolKind::Constant => "const",
+            };
+            println!("\t0x{:08X}  0x{:<6X} {:<6} {}", symbol.value, symbol.size, kind, symbol.name);
+        }
+    }
+
+    Ok(())
+}
+
+// Prints the file as `asm` source that assembles back into the same file.
+fn disassemble(file_name: &Path, file: &binfile::File, symbols: &[binfile::Symbol]) -> Result<(), Error> {
+    let map = SymbolMap::from_symbols(symbols);
+    // Labels at the end of a segment may also start the next one.
+    let mut printed = HashSet::new();
+    let mut labels = |addr: u32| {
+        if printed.insert(addr) {
+            for name in map.names_at(addr) {
+                println!("{}:", name);
+            }
+        }
+    };
+
+    for symbol in symbols.iter().filter(|symbol| symbol.kind == SymbolKind::Constant) {
+        println!("{} = 0x{:X}", symbol.name, symbol.value);
     }
+    println!("    {:<8}0x{:X}", "mem", file.memory_size());
+    match file.initial_sp() {
+        Some(sp) => println!("    {:<8}0x{:X}, 0x{:X}", "entry", file.entry(), sp),
+        None if file.entry() != binfile::DEFAULT_ENTRY => println!("    {:<8}0x{:X}", "entry", file.entry()),
+        None => {}
+    }
+    for segment in file.segments() {
+        let segment = match segment {
+            Ok(segment) => segment,
+            Err(segment) => {
+                let err = binfile::Error::InvalidOffsetRange { offset: segment.offset, size: segment.size };
+                eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+                return Err(Error);
+            }
+        };
+
+        println!();
+        if segment.perms == Perms::ALL {
+            println!("    {:<8}0x{:X}", "seg", segment.addr);
+        } else {
+            println!("    {:<8}0x{:X}, \"{}\"", "seg", segment.addr, segment.perms);
+        }
+        let mut addr = segment.addr;
+        let mut data = segment.data;
+        while addr & 3 != 0 && !data.is_empty() {
+            labels(addr);
+            print_byte(data[0], addr);
+            addr = addr.wrapping_add(1);
+            data = &data[1..];
+        }
+        let mut words = data.chunks_exact(4);
+        for word in &mut words {
+            // A label inside the word splits it into bytes.
+            if (1..4).any(|i| map.names_at(addr.wrapping_add(i)).next().is_some()) {
+                for &byte in word {
+                    labels(addr);
+                    print_byte(byte, addr);
+                    addr = addr.wrapping_add(1);
+                }
+                continue;
+            }
+            let inst = u32::from_le_bytes(word.try_into().unwrap());
+            labels(addr);
+            let line = Disasm::with_symbols(inst, addr, &map).to_string();
+            println!("    {:<32}; 0x{:08X}  0x{:08X}", line, addr, inst);
+            addr = addr.wrapping_add(4);
+        }
+        for &byte in words.remainder() {
+            labels(addr);
+            print_byte(byte, addr);
+            addr = addr.wrapping_add(1);
+        }
+        // Labels split reserved space into pieces.
+        let end = addr as u64 + segment.zero_size as u64;
+        let mut starts: Vec<_> = symbols
+            .iter()
+            .filter(|symbol| symbol.kind != SymbolKind::Constant)
+            .map(|symbol| symbol.value as u64)
+            .filter(|&value| value > addr as u64 && value < end)
+            .collect();
+        starts.sort_unstable();
+        starts.dedup();
+        starts.push(end);
+        for next in starts {
+            if next == addr as u64 {
+                break;
+            }
+            labels(addr);
+            let size = format!("0x{:X}", next - addr as u64);
+            println!("    {:<8}{:<24}; 0x{:08X}", "space", size, addr);
+            addr = next as u32;
+        }
+        labels(addr);
+    }
+    Ok(())
+}
+
+// `d8` takes signed values.
+fn print_byte(byte: u8, addr: u32) {
+    let value = if byte < 0x80 {
+        format!("0x{:02X}", byte)
+    } else {
+        format!("-0x{:02X}", byte.wrapping_neg())
+    };
+    println!("    {:<8}{:<24}; 0x{:08X}", "d8", value, addr);
+}
+
+fn inspect_snapshot(file_name: &Path, file_data: &[u8]) -> Result<(), Error> {
+    let file = match snapshot::File::from_bytes(file_data) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
 
+    println!("Snapshot");
+    println!("File size:     0x{:X}", file_data.len());
+    print_snapshot(&file);
     Ok(())
 }
 
+fn inspect_object(file_name: &Path, file_data: &[u8]) -> Result<(), Error> {
+    let object = match Object::from_bytes(file_data) {
+        Ok(object) => object,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let target = |target: Target, addend: u32| match target {
+        Target::Absolute => format!("0x{:X}", addend),
+        Target::Section(index) => format!("{}+0x{:X}", object.sections[index as usize].name, addend),
+        Target::Symbol(index) => format!("{}+0x{:X}", object.symbols[index as usize].name, addend),
+    };
+
+    println!("Object file");
+    println!("File size:     0x{:X}", file_data.len());
+    println!("Memory size:   0x{:X}", object.memory_size);
+    if let Some((entry, addend)) = object.entry {
+        println!("Entry:         {}", target(entry, addend));
+    }
+    if let Some(sp) = object.initial_sp {
+        println!("Initial sp:    0x{:X}", sp);
+    }
+    println!("Section count: {}", object.sections.len());
+
+    for (i, section) in object.sections.iter().enumerate() {
+        println!("Section {}:", i);
+        println!("\tName:    {}", section.name);
+        println!("\tSize:    0x{:X}", section.data.len());
+        if section.zero_size != 0 {
+            println!("\tZeros:   0x{:X}", section.zero_size);
+        }
+        println!("\tPerms:   {}", section.perms);
+    }
+
+    if !object.symbols.is_empty() {
+        println!("Symbols:");
+        for symbol in &object.symbols {
+            let place = match symbol.place {
+                Place::Undefined => "undefined".to_owned(),
+                Place::Absolute => format!("0x{:X}", symbol.value),
+                Place::Section(index) => target(Target::Section(index), symbol.value),
+            };
+            let kind = match (symbol.place, symbol.kind) {
+                (Place::Undefined, _)     => "extern",
+                (_, SymbolKind::Code)     => "code",
+                (_, SymbolKind::Data)     => "data",
+                (_, SymbolKind::Constant) => "const",
+            };
+            let binding = if symbol.global { "global" } else { "local" };
+            println!("\t{:<20} 0x{:<6X} {:<6} {:<6} {}", place, symbol.size, kind, binding, symbol.name);
+        }
+    }
+
+    if !object.relocs.is_empty() {
+        println!("Relocations:");
+        for reloc in &object.relocs {
+            let kind = match reloc.kind {
+                RelocKind::Abs32  => "abs32",
+                RelocKind::Li     => "li",
+                RelocKind::Lui    => "lui",
+                RelocKind::Branch => "branch",
+                RelocKind::Jal    => "jal",
+                RelocKind::Low    => "low",
+            };
+            let place = target(Target::Section(reloc.section), reloc.offset);
+            println!("\t{:<20} {:<6} {}", place, kind, target(reloc.target, reloc.addend));
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect_archive(file_name: &Path, file_data: &[u8]) -> Result<(), Error> {
+    let archive = match Archive::from_bytes(file_data) {
+        Ok(archive) => archive,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    println!("Archive");
+    println!("File size:     0x{:X}", file_data.len());
+    println!("Member count:  {}", archive.members.len());
+
+    for (i, member) in archive.members.iter().enumerate() {
+        println!("Member {}:", i);
+        println!("\tName:    {}", member.name);
+        println!("\tSections:");
+        for section in &member.object.sections {
+            println!("\t\t{:<12} 0x{:X}", section.name, section.size());
+        }
+        println!("\tSymbols:");
+        for entry in archive.index.iter().filter(|entry| entry.member == i as u32) {
+            println!("\t\t{}", entry.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect_core(file_name: &Path, file_data: &[u8]) -> Result<(), Error> {
+    let (file, memory) = match coredump::File::from_bytes(file_data) {
+        Ok(file) => match file.snapshot.to_memory() {
+            Ok(memory) => (file, memory),
+            Err(err) => {
+                eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+                return Err(Error);
+            }
+        },
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let state = file.snapshot.state();
+    println!("Core file");
+    println!("File size:     0x{:X}", file_data.len());
+    println!("Fault:         {}", file.fault);
+    match vm::load_u32(&memory, state.pc) {
+        Some(inst) => println!("Instruction:   0x{:08X}:  {}", state.pc, Disasm::new(inst, state.pc)),
+        None => println!("Instruction:   0x{:08X}:  <invalid address>", state.pc),
+    }
+    println!("Backtrace:");
+    for (i, (pc, source)) in backtrace(&state, &memory).into_iter().enumerate() {
+        println!("\t#{:<2} 0x{:08X}  {}", i, pc, source);
+    }
+    print_snapshot(&file.snapshot);
+    Ok(())
+}
+
+fn print_snapshot(file: &snapshot::File) {
+    let state = file.state();
+    println!("Memory size:   0x{:X}", file.memory_size());
+    println!("Device size:   0x{:X}", file.device().len());
+    println!("Registers:");
+    println!("\tpc:   0x{:08X}", state.pc);
+    for (name, value) in REG_NAMES.iter().zip(state.regs) {
+        println!("\t{:<5} 0x{:08X}", format!("{}:", name), value);
+    }
+    println!("Segment count: {}", file.segment_count());
+
+    for (i, segment) in file.raw_segments().enumerate() {
+        println!("Segment {}:", i);
+        println!("\tOffset:  0x{:X}", segment.offset);
+        println!("\tAddress: 0x{:X}", segment.addr);
+        println!("\tSize:    0x{:X}", segment.size);
+    }
+
+    let memory_map = file.memory_map();
+    println!("Default perms: {}", memory_map.default_perms());
+    println!("Region count:  {}", memory_map.regions().len());
+    for (i, region) in memory_map.regions().iter().enumerate() {
+        println!("Region {}:", i);
+        println!("\tAddress: 0x{:X}", region.addr);
+        println!("\tSize:    0x{:X}", region.size);
+        println!("\tPerms:   {}", region.perms);
+    }
+}
+
+// A return address is a word preceded by `jal`/`jalr` that writes `%lr`.
+fn is_return_addr(memory: &[u8], addr: u32) -> bool {
+    if addr & 3 != 0 || addr < 4 {
+        return false;
+    }
+    matches!(
+        vm::load_u32(memory, addr - 4).map(Instruction::decode),
+        Some(Ok(Instruction::Jal(Rc { r1: 1, .. }) | Instruction::Jalr(Rrc { r1: 1, .. })))
+    )
+}
+
+// The frame list is a guess: the faulting pc, the call site in %lr and then
+// every word on the stack that looks like a return address.
+fn backtrace(state: &vm::State, memory: &[u8]) -> Vec<(u32, String)> {
+    let mut frames = vec![(state.pc, "pc".to_string())];
+    // A function that has saved %lr on the stack would otherwise be listed
+    // twice.
+    let mut lr = None;
+    if is_return_addr(memory, state.regs[1]) {
+        lr = Some(state.regs[1]);
+        frames.push((state.regs[1] - 4, "lr".to_string()));
+    }
+
+    let sp = state.regs[2] & !3;
+    let mut addr = sp;
+    while frames.len() < MAX_FRAMES {
+        let value = match vm::load_u32(memory, addr) {
+            Some(value) => value,
+            None => break,
+        };
+        if lr == Some(value) {
+            lr = None;
+        } else if is_return_addr(memory, value) {
+            frames.push((value - 4, format!("sp+0x{:X}", addr - sp)));
+        }
+        addr += 4;
+    }
+    frames
+}
+
 fn main() {
     if run().is_err() {
         std::process::exit(1);
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+
+use my_vm::archive::{self, Archive};
+use my_vm::link::{self, Layout};
+use my_vm::object::Object;
+
+struct Error;
+
+fn parse_u32(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<_> = std::env::args_os().collect();
+
+    let mut layout = Layout::default();
+    let mut strip = false;
+    let mut output_name = None;
+    let mut file_names = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--base" && i + 1 < args.len() {
+            match args[i + 1].to_str().and_then(parse_u32) {
+                Some(addr) => layout.base = addr,
+                None => {
+                    eprintln!("Invalid address {}.", args[i + 1].to_string_lossy());
+                    return Err(Error);
+                }
+            }
+            i += 2;
+        } else if args[i] == "--section" && i + 1 < args.len() {
+            let fixed = args[i + 1].to_str().and_then(|s| {
+                let (name, addr) = s.split_once('=')?;
+                Some((name.to_owned(), parse_u32(addr)?))
+            });
+            match fixed {
+                Some(fixed) => layout.fixed.push(fixed),
+                None => {
+                    eprintln!("Invalid section placement {}.", args[i + 1].to_string_lossy());
+                    return Err(Error);
+                }
+            }
+            i += 2;
+        } else if args[i] == "-o" && i + 1 < args.len() {
+            output_name = Some(Path::new(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "--strip" {
+            strip = true;
+            i += 1;
+        } else {
+            file_names.push(Path::new(&args[i]));
+            i += 1;
+        }
+    }
+
+    let output_name = match output_name {
+        Some(output_name) if !file_names.is_empty() => output_name,
+        _ => {
+            eprintln!(
+                "Usage: {} [--base ADDR] [--section NAME=ADDR]... [--strip] -o OUTPUT FILE....",
+                Path::new(&args[0]).display()
+            );
+            return Err(Error);
+        }
+    };
+
+    let mut objects = Vec::with_capacity(file_names.len());
+    let mut names = Vec::with_capacity(file_names.len());
+    let mut archives = Vec::new();
+    let mut archive_names = Vec::new();
+    for name in &file_names {
+        let data = match fs::read(name) {
+            Ok(data) => data,
+            Err(err) => {
+                eprintln!("Failed to load file {}: {}.", name.display(), err);
+                return Err(Error);
+            }
+        };
+        let result = if archive::is_archive(&data) {
+            Archive::from_bytes(&data).map(|archive| {
+                archives.push(archive);
+                archive_names.push(name.display().to_string());
+            })
+        } else {
+            Object::from_bytes(&data).map(|object| {
+                objects.push(object);
+                names.push(name.display().to_string());
+            })
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to load file {}: {}.", name.display(), err);
+            return Err(Error);
+        }
+    }
+
+    for (i, member) in link::select_members(&objects, &archives) {
+        let member = &archives[i].members[member];
+        objects.push(member.object.clone());
+        names.push(format!("{}({})", archive_names[i], member.name));
+    }
+
+    let mut program = match link::link(&objects, &layout) {
+        Ok(program) => program,
+        Err(err) => {
+            match err.object {
+                Some(index) => eprintln!("Error in {}: {}.", names[index], err),
+                None => eprintln!("Error: {}.", err),
+            }
+            return Err(Error);
+        }
+    };
+
+    if strip {
+        program.symbols.clear();
+        program.lines.clear();
+    }
+
+    let output = match program.to_binfile() {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Failed to serialize file {}: {}.", output_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    match fs::write(output_name, &output) {
+        Ok(()) => {}
+        Err(err) => {
+            eprintln!("Failed to write file {}: {}.", output_name.display(), err);
+            return Err(Error);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if run().is_err() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,331 @@
+mod profile;
+mod trace;
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, stderr, stdin, stdout, BufReader, BufWriter, LineWriter, Stdin, Stdout, Write};
+use std::path::Path;
+
+use my_vm::binfile::LineTable;
+use my_vm::disasm::Disasm;
+use my_vm::symbols::SymbolMap;
+use my_vm::{binfile, coredump, snapshot, vm};
+
+use profile::Profiler;
+use trace::{Filter, Format, Tracer};
+
+type Sysfn = vm::Console<BufReader<Stdin>, LineWriter<Stdout>>;
+
+struct Error;
+
+fn create_output(path: &Path) -> io::Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(stderr()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_range(s: &OsStr) -> Option<(u64, u64)> {
+    let (start, end) = s.to_str()?.split_once(':')?;
+    Some((parse_u64(start)?, parse_u64(end)?))
+}
+
+fn parse_pc_range(s: &OsStr) -> Option<(u32, u32)> {
+    let (start, end) = parse_range(s)?;
+    Some((start.try_into().ok()?, end.try_into().ok()?))
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<_> = std::env::args_os().collect();
+
+    let mut fuel = None;
+    let mut trace_file = None;
+    let mut profile_file = None;
+    let mut snapshot_file = None;
+    let mut core_file = None;
+    let mut folded_file = None;
+    let mut trace_format = Format::Text;
+    let mut filter = Filter::default();
+    let mut file_name = None;
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--fuel" && i + 1 < args.len() {
+            match args[i + 1].to_str().and_then(|s| s.parse::<u64>().ok()) {
+                Some(value) => fuel = Some(value),
+                None => {
+                    eprintln!("Invalid fuel amount {}.", args[i + 1].to_string_lossy());
+                    return Err(Error);
+                }
+            }
+            i += 2;
+        } else if args[i] == "--trace" && i + 1 < args.len() {
+            trace_file = Some(Path::new(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "--snapshot" && i + 1 < args.len() {
+            snapshot_file = Some(Path::new(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "--core" && i + 1 < args.len() {
+            core_file = Some(Path::new(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "--profile" && i + 1 < args.len() {
+            profile_file = Some(Path::new(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "--profile-folded" && i + 1 < args.len() {
+            folded_file = Some(Path::new(&args[i + 1]));
+            i += 2;
+        } else if args[i] == "--trace-format" && i + 1 < args.len() {
+            trace_format = match args[i + 1].to_str() {
+                Some("text") => Format::Text,
+                Some("binary") => Format::Binary,
+                _ => {
+                    eprintln!("Invalid trace format {}.", args[i + 1].to_string_lossy());
+                    return Err(Error);
+                }
+            };
+            i += 2;
+        } else if args[i] == "--trace-pc" && i + 1 < args.len() {
+            match parse_pc_range(&args[i + 1]) {
+                Some(range) => filter.pc = Some(range),
+                None => {
+                    eprintln!("Invalid PC range {}.", args[i + 1].to_string_lossy());
+                    return Err(Error);
+                }
+            }
+            i += 2;
+        } else if args[i] == "--trace-window" && i + 1 < args.len() {
+            match parse_range(&args[i + 1]) {
+                Some(range) => filter.window = Some(range),
+                None => {
+                    eprintln!("Invalid instruction window {}.", args[i + 1].to_string_lossy());
+                    return Err(Error);
+                }
+            }
+            i += 2;
+        } else if file_name.is_none() {
+            file_name = Some(Path::new(&args[i]));
+            i += 1;
+        } else {
+            file_name = None;
+            break;
+        }
+    }
+
+    let file_name = match file_name {
+        Some(file_name) => file_name,
+        None => {
+            eprintln!(
+                "Usage: {} [--fuel N] [--trace FILE [--trace-format text|binary] \
+                 [--trace-pc START:END] [--trace-window FROM:TO]] \
+                 [--profile FILE] [--profile-folded FILE] [--snapshot FILE] [--core FILE] FILE.\n\
+                 The snapshot is written whenever the machine stops; it does not keep --fuel.",
+                Path::new(&args[0]).display()
+            );
+            return Err(Error);
+        }
+    };
+    let file_data = match fs::read(file_name) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    let mut sysfn = Sysfn {
+        input: BufReader::new(stdin()),
+        output: LineWriter::new(stdout()),
+    };
+
+    let mut machine = match snapshot::load(&file_data, &mut sysfn) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+            return Err(Error);
+        }
+    };
+
+    // Snapshots carry no symbols or line tables.
+    let (symbols, lines) = if snapshot::is_snapshot(&file_data) {
+        (SymbolMap::new(), LineTable::default())
+    } else {
+        let debug_info = binfile::File::from_bytes(&file_data)
+            .and_then(|file| Ok((SymbolMap::from_file(&file)?, file.line_table()?)));
+        match debug_info {
+            Ok(debug_info) => debug_info,
+            Err(err) => {
+                eprintln!("Failed to load file {}: {}.", file_name.display(), err);
+                return Err(Error);
+            }
+        }
+    };
+
+    let mut tracer = match trace_file {
+        None => None,
+        Some(path) => {
+            let out = match create_output(path) {
+                Ok(out) => out,
+                Err(err) => {
+                    eprintln!("Failed to create trace file {}: {}.", path.display(), err);
+                    return Err(Error);
+                }
+            };
+            match Tracer::new(out, trace_format, filter) {
+                Ok(tracer) => Some(tracer),
+                Err(err) => {
+                    eprintln!("Failed to write trace: {}.", err);
+                    return Err(Error);
+                }
+            }
+        }
+    };
+
+    machine.set_fuel(fuel);
+    let mut profiler = None;
+    if profile_file.is_some() || folded_file.is_some() {
+        profiler = Some(Profiler::new(machine.memory.len(), machine.state.pc, &symbols));
+    }
+    let reason = match run_machine(&mut machine, &mut sysfn, tracer.as_mut(), profiler.as_mut()) {
+        Ok(reason) => reason,
+        Err(err) => {
+            eprintln!("Failed to write trace: {}.", err);
+            return Err(Error);
+        }
+    };
+
+    if let Some(profiler) = &profiler {
+        sysfn.output.flush().unwrap();
+        let outputs = [(profile_file, false), (folded_file, true)];
+        for (path, folded) in outputs {
+            let Some(path) = path else { continue };
+            let result = create_output(path).and_then(|mut out| {
+                if folded {
+                    profiler.write_folded(&mut out)?;
+                } else {
+                    profiler.write_report(&mut out, &machine.memory)?;
+                }
+                out.flush()
+            });
+            if let Err(err) = result {
+                eprintln!("Failed to write profile {}: {}.", path.display(), err);
+                return Err(Error);
+            }
+        }
+    }
+
+    match reason {
+        vm::StopReason::Exited(status) => {
+            eprintln!("Success (exit status {}).", status);
+            if let Some(path) = snapshot_file {
+                save_snapshot(path, &machine, &sysfn);
+            }
+        }
+        reason => {
+            sysfn.output.flush().unwrap();
+            let pc = machine.state.pc;
+            if let Some((file, line)) = lines.find(pc) {
+                let inst = match vm::load_u32(&machine.memory, pc) {
+                    Some(inst) => compact(&Disasm::with_symbols(inst, pc, &symbols).to_string()),
+                    None => "?".to_string(),
+                };
+                eprintln!("Error: {} at {}:{} (`{}`).", reason, file, line, inst);
+            } else if symbols.is_empty() {
+                eprintln!("Error: {}.", reason);
+            } else {
+                eprintln!("Error: {} at {}.", reason, symbols.symbolize(pc));
+            }
+            eprint!("State:\n{}", machine.state);
+            if let Some(path) = snapshot_file {
+                save_snapshot(path, &machine, &sysfn);
+            }
+            if let (Some(path), vm::StopReason::Faulted(err)) = (core_file, &reason) {
+                save_core(path, err, &machine);
+            }
+            return Err(Error);
+        }
+    }
+
+    Ok(())
+}
+
+fn save_snapshot(path: &Path, machine: &vm::Machine, sysfn: &Sysfn) {
+    let mut buffer = Vec::new();
+    let device = vm::Sysfn::save(sysfn);
+    let memory_map = machine.memory_map();
+    if let Err(err) = snapshot::serialize(&machine.state, &machine.memory, memory_map, &device, &mut buffer) {
+        eprintln!("Failed to save snapshot {}: {}.", path.display(), err);
+        return;
+    }
+    match fs::write(path, &buffer) {
+        Ok(()) => eprintln!("Snapshot saved to {}.", path.display()),
+        Err(err) => eprintln!("Failed to save snapshot {}: {}.", path.display(), err),
+    }
+}
+
+fn save_core(path: &Path, err: &vm::Error, machine: &vm::Machine) {
+    let mut buffer = Vec::new();
+    let memory_map = machine.memory_map();
+    if let Err(err) = coredump::serialize(err, &machine.state, &machine.memory, memory_map, &mut buffer) {
+        eprintln!("Failed to save core file {}: {}.", path.display(), err);
+        return;
+    }
+    match fs::write(path, &buffer) {
+        Ok(()) => eprintln!("Core dumped to {}.", path.display()),
+        Err(err) => eprintln!("Failed to save core file {}: {}.", path.display(), err),
+    }
+}
+
+fn run_machine(
+    machine: &mut vm::Machine,
+    sysfn: &mut Sysfn,
+    mut tracer: Option<&mut Tracer>,
+    mut profiler: Option<&mut Profiler>,
+) -> io::Result<vm::StopReason> {
+    let reason = loop {
+        let tracing = tracer.as_ref().is_some_and(|tracer| !tracer.finished());
+        if !tracing && profiler.is_none() {
+            break machine.run(sysfn);
+        }
+
+        let pc = machine.state.pc;
+        let inst = vm::load_u32(&machine.memory, pc);
+        let reason = match &mut tracer {
+            Some(tracer) if tracing => tracer.step(machine, sysfn)?,
+            _ => machine.step_with(sysfn, |_| {}),
+        };
+        if let (Some(profiler), Some(inst)) = (&mut profiler, inst) {
+            if let vm::StopReason::Exited(_) | vm::StopReason::Stepped = reason {
+                profiler.record(pc, inst, &machine.state);
+            }
+        }
+        match reason {
+            vm::StopReason::Stepped => {}
+            reason => break reason,
+        }
+    };
+    if let Some(tracer) = tracer {
+        tracer.flush()?;
+    }
+    Ok(reason)
+}
+
+// Drops the padding that aligns operands in the disassembly listing.
+fn compact(inst: &str) -> String {
+    match inst.split_once(' ') {
+        Some((mnemonic, operands)) => format!("{} {}", mnemonic, operands.trim_start()),
+        None => inst.to_string(),
+    }
+}
+
+fn main() {
+    if run().is_err() {
+        std::process::exit(1);
+    }
+}
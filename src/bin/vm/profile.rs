@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use my_vm::disasm::Disasm;
+use my_vm::isa::{self, Instruction, Rc, Rrc};
+use my_vm::symbols::SymbolMap;
+use my_vm::vm::State;
+
+const LR: u8 = 1;
+const HOT_SPOTS: usize = 20;
+
+// A node of the call tree: one per distinct call stack.
+struct Node {
+    func: u32,
+    parent: usize,
+    children: HashMap<u32, usize>,
+    self_count: u64,
+}
+
+struct Frame {
+    node: usize,
+    ret: u32,
+}
+
+#[derive(Default)]
+struct FuncStats {
+    self_count: u64,
+    inclusive: u64,
+    calls: u64,
+}
+
+pub struct Profiler<'a> {
+    symbols: &'a SymbolMap,
+    pc_counts: Vec<u64>,
+    opcode_counts: [u64; 256],
+    nodes: Vec<Node>,
+    stack: Vec<Frame>,
+    calls: HashMap<(u32, u32), u64>,
+    total: u64,
+}
+
+impl<'a> Profiler<'a> {
+    pub fn new(memory_size: usize, entry: u32, symbols: &'a SymbolMap) -> Profiler<'a> {
+        let root = Node { func: entry, parent: 0, children: HashMap::new(), self_count: 0 };
+        Profiler {
+            symbols,
+            pc_counts: vec![0; memory_size / 4],
+            opcode_counts: [0; 256],
+            nodes: vec![root],
+            stack: vec![Frame { node: 0, ret: !0 }],
+            calls: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    // Records an instruction at `pc` that has been executed successfully;
+    // `state` is the state after its execution.
+    pub fn record(&mut self, pc: u32, inst: u32, state: &State) {
+        self.total += 1;
+        self.pc_counts[(pc / 4) as usize] += 1;
+        self.opcode_counts[(inst & 0xFF) as usize] += 1;
+        let node = self.stack.last().unwrap().node;
+        self.nodes[node].self_count += 1;
+
+        match Instruction::decode(inst) {
+            Ok(Instruction::Jal(Rc { r1: LR, .. }) | Instruction::Jalr(Rrc { r1: LR, .. })) => {
+                self.call(node, state.pc, pc.wrapping_add(4));
+            }
+            Ok(Instruction::Jalr(Rrc { r2: LR, .. })) => {
+                if let Some(depth) = self.stack.iter().rposition(|frame| frame.ret == state.pc) {
+                    self.stack.truncate(depth);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn call(&mut self, node: usize, target: u32, ret: u32) {
+        let caller = self.nodes[node].func;
+        *self.calls.entry((caller, target)).or_insert(0) += 1;
+
+        let child = match self.nodes[node].children.get(&target) {
+            Some(&child) => child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node { func: target, parent: node, children: HashMap::new(), self_count: 0 });
+                self.nodes[node].children.insert(target, child);
+                child
+            }
+        };
+        self.stack.push(Frame { node: child, ret });
+    }
+
+    fn stack_of(&self, mut node: usize) -> Vec<u32> {
+        let mut funcs = vec![self.nodes[node].func];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            funcs.push(self.nodes[node].func);
+        }
+        funcs.reverse();
+        funcs
+    }
+
+    fn func_stats(&self) -> HashMap<u32, FuncStats> {
+        let mut stats: HashMap<u32, FuncStats> = HashMap::new();
+        for (id, node) in self.nodes.iter().enumerate() {
+            stats.entry(node.func).or_default().self_count += node.self_count;
+            if node.self_count == 0 {
+                continue;
+            }
+            // Recursive functions are counted once per stack.
+            let mut funcs = self.stack_of(id);
+            funcs.sort_unstable();
+            funcs.dedup();
+            for func in funcs {
+                stats.entry(func).or_default().inclusive += node.self_count;
+            }
+        }
+        for (&(_, callee), &count) in &self.calls {
+            stats.entry(callee).or_default().calls += count;
+        }
+        stats
+    }
+
+    pub fn write_report(&self, out: &mut dyn Write, memory: &[u8]) -> io::Result<()> {
+        let total = self.total.max(1) as f64;
+        let percent = |count: u64| count as f64 * 100.0 / total;
+        writeln!(out, "Total instructions: {}", self.total)?;
+
+        writeln!(out)?;
+        writeln!(out, "Flat profile:")?;
+        writeln!(out, "{:>12} {:>7} {:>12} {:>7} {:>10}  function", "self", "%", "inclusive", "%", "calls")?;
+        let stats = self.func_stats();
+        let mut funcs: Vec<_> = stats.iter().collect();
+        funcs.sort_by(|a, b| b.1.self_count.cmp(&a.1.self_count).then(a.0.cmp(b.0)));
+        for (&func, stat) in funcs {
+            writeln!(
+                out,
+                "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>10}  {}",
+                stat.self_count,
+                percent(stat.self_count),
+                stat.inclusive,
+                percent(stat.inclusive),
+                stat.calls,
+                self.func_name(func)
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Call graph:")?;
+        let mut funcs: Vec<_> = stats.iter().collect();
+        funcs.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (&func, stat) in funcs {
+            let name = self.func_name(func);
+            writeln!(out, "  {} ({} inclusive, {:.2}%)", name, stat.inclusive, percent(stat.inclusive))?;
+            let mut callers: Vec<_> = self.calls.iter().filter(|((_, callee), _)| *callee == func).collect();
+            callers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (&(caller, _), count) in callers {
+                writeln!(out, "    <- {:<24} {:>10} calls", self.func_name(caller), count)?;
+            }
+            let mut callees: Vec<_> = self.calls.iter().filter(|((caller, _), _)| *caller == func).collect();
+            callees.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (&(_, callee), count) in callees {
+                writeln!(out, "    -> {:<24} {:>10} calls", self.func_name(callee), count)?;
+            }
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Hot spots:")?;
+        let mut pcs: Vec<_> = (0..self.pc_counts.len()).filter(|&i| self.pc_counts[i] != 0).collect();
+        pcs.sort_by(|&a, &b| self.pc_counts[b].cmp(&self.pc_counts[a]).then(a.cmp(&b)));
+        for i in pcs.into_iter().take(HOT_SPOTS) {
+            let pc = (i * 4) as u32;
+            let inst = u32::from_le_bytes(memory[i * 4..i * 4 + 4].try_into().unwrap());
+            let count = self.pc_counts[i];
+            let disasm = Disasm::with_symbols(inst, pc, self.symbols);
+            writeln!(out, "{:>12} {:>6.2}%  0x{:08X}  {}", count, percent(count), pc, disasm)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Opcodes:")?;
+        let mut ops: Vec<_> = (0..256).filter(|&op| self.opcode_counts[op] != 0).collect();
+        ops.sort_by(|&a, &b| self.opcode_counts[b].cmp(&self.opcode_counts[a]).then(a.cmp(&b)));
+        for op in ops {
+            let count = self.opcode_counts[op];
+            let name = isa::info_by_opcode(op as u32).map_or("unknown", |info| info.mnemonic);
+            writeln!(out, "{:>12} {:>6.2}%  {}", count, percent(count), name)?;
+        }
+        Ok(())
+    }
+
+    // Writes stacks in the folded format: `main;print_uint;div 42`.
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (id, node) in self.nodes.iter().enumerate() {
+            if node.self_count == 0 {
+                continue;
+            }
+            let names: Vec<_> = self.stack_of(id).into_iter().map(|func| self.func_name(func)).collect();
+            writeln!(out, "{} {}", names.join(";"), node.self_count)?;
+        }
+        Ok(())
+    }
+
+    fn func_name(&self, addr: u32) -> String {
+        self.symbols.symbolize(addr).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use my_vm::binfile::{self, SymbolKind};
+    use my_vm::vm;
+
+    use super::*;
+
+    // `main` calls `twice`, which calls `leaf` twice, and then calls `leaf`.
+    const CODE: [u32; 12] = [
+        0x0000_21A0, // 0x1000: call    0x100C          main
+        0x0000_81A0, // 0x1004: call    0x1028
+        0x0000_0083, // 0x1008: sysfn   %zero, 0
+        0xFFFC_2288, // 0x100C: addi    %sp, %sp, -4    twice
+        0x0000_2186, // 0x1010: st      %lr, %sp, 0
+        0x0000_41A0, // 0x1014: call    0x1028
+        0x0000_31A0, // 0x1018: call    0x1028
+        0x0000_219A, // 0x101C: ld      %lr, %sp, 0
+        0x0004_2288, // 0x1020: addi    %sp, %sp, 4
+        0x0000_10A1, // 0x1024: ret
+        0x0001_3388, // 0x1028: addi    %a0, %a0, 1     leaf
+        0x0000_10A1, // 0x102C: ret
+    ];
+
+    // Runs the program the way `run_machine` does and returns the profile.
+    fn profile(symbols: &SymbolMap) -> (Profiler<'_>, Vec<u8>) {
+        let mut machine = vm::Machine::new(vec![0; 0x2000]);
+        for (i, word) in CODE.iter().enumerate() {
+            machine.memory[0x1000 + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+        machine.state.regs[2] = 0x2000;
+        let mut sysfn = vm::Console { input: io::empty(), output: io::sink() };
+        let mut profiler = Profiler::new(machine.memory.len(), machine.state.pc, symbols);
+        loop {
+            let pc = machine.state.pc;
+            let inst = vm::load_u32(&machine.memory, pc).unwrap();
+            match machine.step(&mut sysfn) {
+                vm::StopReason::Stepped => profiler.record(pc, inst, &machine.state),
+                vm::StopReason::Exited(0) => {
+                    profiler.record(pc, inst, &machine.state);
+                    break;
+                }
+                reason => panic!("{}", reason),
+            }
+        }
+        (profiler, machine.memory)
+    }
+
+    fn symbols() -> SymbolMap {
+        let symbol = |name, value, size| binfile::Symbol { name, value, size, kind: SymbolKind::Code };
+        SymbolMap::from_symbols(&[
+            symbol("main", 0x1000, 12),
+            symbol("twice", 0x100C, 28),
+            symbol("leaf", 0x1028, 8),
+        ])
+    }
+
+    #[test]
+    fn attribution() {
+        let symbols = symbols();
+        let (profiler, _) = profile(&symbols);
+        assert_eq!(profiler.total, 16);
+        assert_eq!(profiler.pc_counts[0x1000 / 4], 1);
+        assert_eq!(profiler.pc_counts[0x1014 / 4], 1);
+        assert_eq!(profiler.pc_counts[0x1028 / 4], 3);
+        assert_eq!(profiler.pc_counts[0x102C / 4], 3);
+        assert_eq!(profiler.opcode_counts[isa::opcode::JAL as usize], 4);
+
+        let stats = profiler.func_stats();
+        let stat = |func| {
+            let stat = &stats[&func];
+            (stat.self_count, stat.inclusive, stat.calls)
+        };
+        assert_eq!(stat(0x1000), (3, 16, 0));
+        assert_eq!(stat(0x100C), (7, 11, 1));
+        assert_eq!(stat(0x1028), (6, 6, 3));
+
+        let mut calls: Vec<_> = profiler.calls.iter().map(|(&edge, &count)| (edge, count)).collect();
+        calls.sort_unstable();
+        assert_eq!(calls, [((0x1000, 0x100C), 1), ((0x1000, 0x1028), 1), ((0x100C, 0x1028), 2)]);
+    }
+
+    #[test]
+    fn report() {
+        let symbols = symbols();
+        let (profiler, memory) = profile(&symbols);
+        let mut out = Vec::new();
+        profiler.write_report(&mut out, &memory).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+
+        assert_eq!(lines[0], "Total instructions: 16");
+        let flat = lines.iter().position(|line| *line == "Flat profile:").unwrap();
+        let names: Vec<_> = lines[flat + 2..flat + 5].iter().map(|line| line.rsplit(' ').next()).collect();
+        assert_eq!(names, [Some("twice"), Some("leaf"), Some("main")]);
+        assert!(lines[flat + 2].contains("  43.75% "), "{}", lines[flat + 2]);
+
+        let graph = lines.iter().position(|line| *line == "Call graph:").unwrap();
+        assert_eq!(lines[graph + 1], "  main (16 inclusive, 100.00%)");
+        assert!(lines[graph + 2].starts_with("    -> twice "), "{}", lines[graph + 2]);
+        assert!(lines[graph + 2].ends_with(" 1 calls"), "{}", lines[graph + 2]);
+
+        let hot = lines.iter().position(|line| *line == "Hot spots:").unwrap();
+        assert!(lines[hot + 1].contains("0x00001028"), "{}", lines[hot + 1]);
+    }
+
+    #[test]
+    fn folded() {
+        let symbols = symbols();
+        let (profiler, _) = profile(&symbols);
+        let mut out = Vec::new();
+        profiler.write_folded(&mut out).unwrap();
+        let mut stacks: Vec<_> = String::from_utf8(out).unwrap().lines().map(String::from).collect();
+        stacks.sort();
+        assert_eq!(stacks, ["main 3", "main;leaf 2", "main;twice 7", "main;twice;leaf 4"]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+
+use my_vm::disasm::{Disasm, REG_NAMES};
+use my_vm::vm::{self, Machine, StopReason};
+
+const MAGIC: u32 = u32::from_le_bytes(*b"\x80TRC");
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+// Both conditions must hold for an instruction to be traced. Ranges are
+// half-open: `[start, end)`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Filter {
+    pub pc: Option<(u32, u32)>,
+    pub window: Option<(u64, u64)>,
+}
+
+impl Filter {
+    fn matches(&self, pc: u32, index: u64) -> bool {
+        self.pc.is_none_or(|(start, end)| start <= pc && pc < end)
+            && self.window.is_none_or(|(from, to)| from <= index && index < to)
+    }
+
+    fn finished(&self, index: u64) -> bool {
+        self.window.is_some_and(|(_, to)| index >= to)
+    }
+}
+
+struct MemWrite {
+    addr: u32,
+    width: u32,
+    value: u32,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    filter: Filter,
+    count: u64,
+}
+
+impl Tracer {
+    pub fn new(mut out: Box<dyn Write>, format: Format, filter: Filter) -> io::Result<Tracer> {
+        if format == Format::Binary {
+            out.write_all(&MAGIC.to_le_bytes())?;
+            out.write_all(&VERSION.to_le_bytes())?;
+        }
+        Ok(Tracer { out, format, filter, count: 0 })
+    }
+
+    // Returns true when no further instruction can be traced.
+    pub fn finished(&self) -> bool {
+        self.filter.finished(self.count)
+    }
+
+    pub fn step(&mut self, machine: &mut Machine, sysfn: &mut dyn vm::Sysfn) -> io::Result<StopReason> {
+        let index = self.count;
+        let pc = machine.state.pc;
+        if !self.filter.matches(pc, index) {
+            let reason = machine.step_with(sysfn, |_| {});
+            self.count += 1;
+            return Ok(reason);
+        }
+
+        let regs = machine.state.regs;
+        let inst = vm::load_u32(&machine.memory, pc);
+        let mut writes = Vec::new();
+        let reason = machine.step_with(sysfn, |access| {
+            if access.write {
+                writes.push(MemWrite { addr: access.addr, width: access.width, value: access.new });
+            }
+        });
+        self.count += 1;
+
+        // Faulted instructions have no effects and are reported by the caller.
+        if let (Some(inst), StopReason::Exited(_) | StopReason::Stepped) = (inst, &reason) {
+            let changes: Vec<_> = (1..16)
+                .filter(|&i| regs[i] != machine.state.regs[i])
+                .map(|i| (i, regs[i], machine.state.regs[i]))
+                .collect();
+            match self.format {
+                Format::Text => self.write_text(pc, inst, &changes, &writes)?,
+                Format::Binary => self.write_binary(pc, inst, &changes, &writes)?,
+            }
+        }
+        Ok(reason)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn write_text(
+        &mut self,
+        pc: u32,
+        inst: u32,
+        changes: &[(usize, u32, u32)],
+        writes: &[MemWrite],
+    ) -> io::Result<()> {
+        let disasm = Disasm::new(inst, pc);
+        let mut effects = Vec::new();
+        for &(reg, old, new) in changes {
+            effects.push(format!("{}: 0x{:X} -> 0x{:X}", REG_NAMES[reg], old, new));
+        }
+        for write in writes {
+            effects.push(format!("[0x{:X}] <- 0x{:X}", write.addr, write.value));
+        }
+
+        if effects.is_empty() {
+            writeln!(self.out, "0x{:08X}  0x{:08X}  {}", pc, inst, disasm)
+        } else {
+            let disasm = disasm.to_string();
+            writeln!(self.out, "0x{:08X}  0x{:08X}  {:<28}{}", pc, inst, disasm, effects.join(", "))
+        }
+    }
+
+    fn write_binary(
+        &mut self,
+        pc: u32,
+        inst: u32,
+        changes: &[(usize, u32, u32)],
+        writes: &[MemWrite],
+    ) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(10 + changes.len() * 5 + writes.len() * 9);
+        buf.extend_from_slice(&pc.to_le_bytes());
+        buf.extend_from_slice(&inst.to_le_bytes());
+        buf.push(changes.len() as u8);
+        buf.push(writes.len() as u8);
+        for &(reg, _, new) in changes {
+            buf.push(reg as u8);
+            buf.extend_from_slice(&new.to_le_bytes());
+        }
+        for write in writes {
+            buf.extend_from_slice(&write.addr.to_le_bytes());
+            buf.push(write.width as u8);
+            buf.extend_from_slice(&write.value.to_le_bytes());
+        }
+        self.out.write_all(&buf)
+    }
+}
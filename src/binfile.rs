@@ -4,11 +4,30 @@ use std::marker::PhantomData;
 
 use smallvec::SmallVec;
 
-const FILE_HEADER_SIZE: usize = 4 * 4;
-const SEGMENT_HEADER_SIZE: usize = 3 * 4;
+use crate::vm::{Machine, MemoryMap, Perms, Region, State};
+
+const FILE_HEADER_SIZE_V1: usize = 4 * 4;
+const FILE_HEADER_SIZE_V2: usize = 6 * 4;
+const FILE_HEADER_SIZE_V3: usize = 7 * 4;
+const SEGMENT_HEADER_SIZE_V1: usize = 3 * 4;
+const SEGMENT_HEADER_SIZE_V4: usize = 4 * 4;
+const SECTION_HEADER_SIZE: usize = 3 * 4;
+const SYMBOL_SIZE: usize = 5 * 4;
+const FILE_NAME_SIZE: usize = 2 * 4;
+const LINE_SIZE: usize = 4 * 4;
 
 const FILE_MAGIC: [u8; 4] = [0x80, b'B', b'I', b'N'];
 
+// Version 1 files have no entry point and start here.
+pub const DEFAULT_ENTRY: u32 = 0x1000;
+
+// Kinds of sections; readers skip sections of unknown kinds.
+pub const SECTION_SYMBOLS: u32 = 1;
+pub const SECTION_LINES:   u32 = 2;
+
+// Segment flags above the permission bits.
+pub const SEGMENT_ZERO_FILL: u32 = 8;
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Clone, Debug)]
@@ -19,18 +38,38 @@ pub enum Error {
     FileTooLarge,
     InvalidOffsetRange { offset: u32, size: u32 },
     InvalidAddrRange { addr: u32, size: u32 },
+    InvalidSection(u32),
+    InvalidPerms(u32),
 }
 
 pub struct File<'a> {
     data: &'a [u8],
+    header_size: usize,
+    segment_header_size: usize,
     memory_size: u32,
     segment_count: u32,
+    section_count: u32,
+    entry: u32,
+    initial_sp: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header<'a> {
+    pub memory_size: u32,
+    pub entry: u32,
+    pub initial_sp: Option<u32>,
+    pub symbols: &'a [Symbol<'a>],
+    pub files: &'a [&'a str],
+    pub lines: &'a [Line],
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Segment<'a> {
     pub addr: u32,
     pub data: &'a [u8],
+    // Zero bytes after `data` that take no space in the file.
+    pub zero_size: u32,
+    pub perms: Perms,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -38,15 +77,86 @@ pub struct RawSegment {
     pub offset: u32,
     pub addr: u32,
     pub size: u32,
+    pub perms: Perms,
+    pub zero_fill: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RawSection {
+    pub kind: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Code,
+    Data,
+    Constant,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub value: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+// Bytes `addr..addr + size` come from `line` of the source file with index
+// `file`.
+#[derive(Clone, Copy, Debug)]
+pub struct Line {
+    pub addr: u32,
+    pub size: u32,
+    pub file: u32,
+    pub line: u32,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct LineTable<'a> {
+    pub files: Vec<&'a str>,
+    // Sorted by address.
+    pub lines: Vec<Line>,
 }
 
 unsafe fn load_u32(ptr: *const u8, index: usize) -> u32 {
     u32::from_le_bytes(*(ptr.add(index * 4) as *const [u8; 4]))
 }
 
+fn read_u32(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+impl LineTable<'_> {
+    // Returns the file name and line that `addr` was assembled from.
+    pub fn find(&self, addr: u32) -> Option<(&str, u32)> {
+        let index = self.lines.partition_point(|line| line.addr <= addr);
+        let line = &self.lines[index.checked_sub(1)?];
+        if addr - line.addr < line.size {
+            Some((self.files[line.file as usize], line.line))
+        } else {
+            None
+        }
+    }
+}
+
+impl Header<'_> {
+    pub fn new(memory_size: u32) -> Header<'static> {
+        Header {
+            memory_size,
+            entry: DEFAULT_ENTRY,
+            initial_sp: None,
+            symbols: &[],
+            files: &[],
+            lines: &[],
+        }
+    }
+}
+
 impl<'a> File<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Result<File<'a>> {
-        if data.len() < FILE_HEADER_SIZE {
+        if data.len() < FILE_HEADER_SIZE_V1 {
             return Err(Error::InvalidFormat);
         }
 
@@ -65,13 +175,47 @@ impl<'a> File<'a> {
             return Err(Error::InvalidFormat);
         }
 
-        if version != 1 {
-            return Err(Error::UnsupportedVersion(version));
+        let header_size = match version {
+            1 => FILE_HEADER_SIZE_V1,
+            2 => FILE_HEADER_SIZE_V2,
+            3 | 4 => FILE_HEADER_SIZE_V3,
+            _ => return Err(Error::UnsupportedVersion(version)),
+        };
+        let segment_header_size = if version >= 4 { SEGMENT_HEADER_SIZE_V4 } else { SEGMENT_HEADER_SIZE_V1 };
+        if data.len() < header_size {
+            return Err(Error::FileTooShort);
         }
 
-        if let Some(size) = (segment_count as usize).checked_mul(SEGMENT_HEADER_SIZE) {
-            if data.len() - FILE_HEADER_SIZE >= size {
-                return Ok(File { data, memory_size, segment_count });
+        let mut entry = DEFAULT_ENTRY;
+        let mut initial_sp = None;
+        if version >= 2 {
+            entry = read_u32(data, 4);
+            initial_sp = Some(read_u32(data, 5)).filter(|&sp| sp != 0);
+        }
+        let section_count = if version >= 3 { read_u32(data, 6) } else { 0 };
+
+        let segments_size = (segment_count as usize).checked_mul(segment_header_size);
+        let sections_size = (section_count as usize).checked_mul(SECTION_HEADER_SIZE);
+        if let Some(size) = segments_size.zip(sections_size).and_then(|(a, b)| a.checked_add(b)) {
+            if data.len() - header_size >= size {
+                if segment_header_size == SEGMENT_HEADER_SIZE_V4 {
+                    for i in 0..segment_count as usize {
+                        let flags = read_u32(&data[header_size + i * segment_header_size..], 3);
+                        if Perms::from_bits(flags & !SEGMENT_ZERO_FILL).is_none() {
+                            return Err(Error::InvalidPerms(flags));
+                        }
+                    }
+                }
+                return Ok(File {
+                    data,
+                    header_size,
+                    segment_header_size,
+                    memory_size,
+                    segment_count,
+                    section_count,
+                    entry,
+                    initial_sp,
+                });
             }
         }
         Err(Error::FileTooShort)
@@ -89,9 +233,147 @@ impl<'a> File<'a> {
         self.segment_count
     }
 
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn initial_sp(&self) -> Option<u32> {
+        self.initial_sp
+    }
+
+    pub fn section_count(&self) -> u32 {
+        self.section_count
+    }
+
+    pub fn raw_sections(&self) -> impl ExactSizeIterator<Item = RawSection> + 'a {
+        let start = self.header_size + self.segment_count as usize * self.segment_header_size;
+        let headers = &self.data[start..];
+        (0..self.section_count as usize).map(move |i| {
+            let header = &headers[i * SECTION_HEADER_SIZE..];
+            RawSection {
+                kind:   read_u32(header, 0),
+                offset: read_u32(header, 1),
+                size:   read_u32(header, 2),
+            }
+        })
+    }
+
+    // Returns the data of the first section of the given kind.
+    pub fn section(&self, kind: u32) -> Result<Option<&'a [u8]>> {
+        let section = match self.raw_sections().find(|section| section.kind == kind) {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let range = (section.offset as usize)..(section.offset as usize).wrapping_add(section.size as usize);
+        match self.data.get(range) {
+            Some(data) => Ok(Some(data)),
+            None => Err(Error::InvalidOffsetRange { offset: section.offset, size: section.size }),
+        }
+    }
+
+    pub fn symbols(&self) -> Result<Vec<Symbol<'a>>> {
+        let data = match self.section(SECTION_SYMBOLS)? {
+            Some(data) => data,
+            None => return Ok(Vec::new()),
+        };
+        let invalid = Error::InvalidSection(SECTION_SYMBOLS);
+        if data.len() < 4 {
+            return Err(invalid);
+        }
+        let count = read_u32(data, 0) as usize;
+        let records = match count.checked_mul(SYMBOL_SIZE).and_then(|size| data.get(4..4 + size)) {
+            Some(records) => records,
+            None => return Err(invalid),
+        };
+
+        let mut symbols = Vec::with_capacity(count);
+        for record in records.chunks_exact(SYMBOL_SIZE) {
+            let kind = match read_u32(record, 2) {
+                0 => SymbolKind::Code,
+                1 => SymbolKind::Data,
+                2 => SymbolKind::Constant,
+                _ => return Err(invalid),
+            };
+            let offset = read_u32(record, 3) as usize;
+            let size = read_u32(record, 4) as usize;
+            let name = match data.get(offset..offset.wrapping_add(size)).map(std::str::from_utf8) {
+                Some(Ok(name)) => name,
+                _ => return Err(invalid),
+            };
+            symbols.push(Symbol { name, value: read_u32(record, 0), size: read_u32(record, 1), kind });
+        }
+        Ok(symbols)
+    }
+
+    pub fn line_table(&self) -> Result<LineTable<'a>> {
+        let data = match self.section(SECTION_LINES)? {
+            Some(data) => data,
+            None => return Ok(LineTable::default()),
+        };
+        let invalid = Error::InvalidSection(SECTION_LINES);
+        if data.len() < 8 {
+            return Err(invalid);
+        }
+        let file_count = read_u32(data, 0) as usize;
+        let line_count = read_u32(data, 1) as usize;
+        let files_size = file_count.checked_mul(FILE_NAME_SIZE).ok_or(invalid.clone())?;
+        let lines_size = line_count.checked_mul(LINE_SIZE).ok_or(invalid.clone())?;
+        let records = match files_size.checked_add(lines_size).and_then(|size| data.get(8..8 + size)) {
+            Some(records) => records,
+            None => return Err(invalid),
+        };
+
+        let mut table = LineTable::default();
+        for record in records[..files_size].chunks_exact(FILE_NAME_SIZE) {
+            let offset = read_u32(record, 0) as usize;
+            let size = read_u32(record, 1) as usize;
+            match data.get(offset..offset.wrapping_add(size)).map(std::str::from_utf8) {
+                Some(Ok(name)) => table.files.push(name),
+                _ => return Err(invalid),
+            }
+        }
+        for record in records[files_size..].chunks_exact(LINE_SIZE) {
+            let line = Line {
+                addr: read_u32(record, 0),
+                size: read_u32(record, 1),
+                file: read_u32(record, 2),
+                line: read_u32(record, 3),
+            };
+            if line.file as usize >= file_count {
+                return Err(invalid);
+            }
+            table.lines.push(line);
+        }
+        table.lines.sort_by_key(|line| line.addr);
+        Ok(table)
+    }
+
+    pub fn initial_state(&self) -> State {
+        let mut state = State::with_pc(self.entry);
+        if let Some(sp) = self.initial_sp {
+            state.regs[2] = sp;
+        }
+        state
+    }
+
+    // Memory is unprotected unless some segment has fewer rights than `rwx`;
+    // then memory outside of segments is readable and writable.
+    pub fn memory_map(&self) -> MemoryMap {
+        if self.raw_segments().all(|segment| segment.perms == Perms::ALL) {
+            return MemoryMap::flat();
+        }
+        let regions = self.raw_segments().map(|segment| Region {
+            addr: segment.addr,
+            size: segment.size,
+            perms: segment.perms,
+        });
+        MemoryMap::new(regions, Perms::RW)
+    }
+
     pub fn segments(&self) -> SegmentIterator<'a> {
         SegmentIterator {
-            cursor: unsafe { self.data.as_ptr().add(FILE_HEADER_SIZE) },
+            cursor: unsafe { self.data.as_ptr().add(self.header_size) },
+            stride: self.segment_header_size,
             remaining: self.segment_count as usize,
             data: self.data,
         }
@@ -99,7 +381,8 @@ impl<'a> File<'a> {
 
     pub fn raw_segments(&self) -> RawSegmentIterator<'a> {
         RawSegmentIterator {
-            cursor: unsafe { self.data.as_ptr().add(FILE_HEADER_SIZE) },
+            cursor: unsafe { self.data.as_ptr().add(self.header_size) },
+            stride: self.segment_header_size,
             remaining: self.segment_count as usize,
             _data: PhantomData,
         }
@@ -124,16 +407,19 @@ impl<'a> File<'a> {
             let addr = segment.addr as usize;
             let size = segment.data.len();
             let data_ptr = segment.data.as_ptr();
+            let total_size = size + segment.zero_size as usize;
 
-            if !addr.checked_add(size).map_or(false, |upper| upper <= memory_size) {
+            if addr.checked_add(total_size).is_none_or(|upper| upper > memory_size) {
                 return Err(Error::InvalidAddrRange {
                     addr: addr as u32,
-                    size: size as u32,
+                    size: total_size as u32,
                 });
             }
 
+            // Earlier segments may overlap the zero-filled part.
             unsafe {
                 memory_ptr.add(addr).copy_from_nonoverlapping(data_ptr, size);
+                memory_ptr.add(addr + size).write_bytes(0, segment.zero_size as usize);
             }
         }
 
@@ -144,6 +430,7 @@ impl<'a> File<'a> {
 #[derive(Clone, Copy)]
 pub struct SegmentIterator<'a> {
     cursor: *const u8,
+    stride: usize,
     remaining: usize,
     data: &'a [u8],
 }
@@ -151,10 +438,22 @@ pub struct SegmentIterator<'a> {
 #[derive(Clone, Copy)]
 pub struct RawSegmentIterator<'a> {
     cursor: *const u8,
+    stride: usize,
     remaining: usize,
     _data: PhantomData<&'a [u8]>,
 }
 
+// Segments of files before version 4 have no flags and allow everything.
+// Flags are validated by `File::from_bytes`.
+unsafe fn load_flags(cursor: *const u8, stride: usize) -> (Perms, bool) {
+    if stride < SEGMENT_HEADER_SIZE_V4 {
+        return (Perms::ALL, false);
+    }
+    let flags = load_u32(cursor, 3);
+    let perms = Perms::from_bits(flags & !SEGMENT_ZERO_FILL).unwrap_or(Perms::NONE);
+    (perms, flags & SEGMENT_ZERO_FILL != 0)
+}
+
 impl<'a> Iterator for SegmentIterator<'a> {
     type Item = Result<Segment<'a>, RawSegment>;
 
@@ -167,15 +466,19 @@ impl<'a> Iterator for SegmentIterator<'a> {
             let offset = load_u32(self.cursor, 0);
             let addr   = load_u32(self.cursor, 1);
             let size   = load_u32(self.cursor, 2);
+            let (perms, zero_fill) = load_flags(self.cursor, self.stride);
 
-            self.cursor = self.cursor.add(SEGMENT_HEADER_SIZE);
+            self.cursor = self.cursor.add(self.stride);
             self.remaining -= 1;
 
+            if zero_fill {
+                return Some(Ok(Segment { addr, data: &[], zero_size: size, perms }));
+            }
             let range = (offset as usize)..(offset as usize).wrapping_add(size as usize);
             if let Some(data) = self.data.get(range) {
-                Some(Ok(Segment { addr, data }))
+                Some(Ok(Segment { addr, data, zero_size: 0, perms }))
             } else {
-                Some(Err(RawSegment { offset, addr, size }))
+                Some(Err(RawSegment { offset, addr, size, perms, zero_fill }))
             }
         }
     }
@@ -199,11 +502,12 @@ impl<'a> Iterator for RawSegmentIterator<'a> {
             let offset = load_u32(self.cursor, 0);
             let addr   = load_u32(self.cursor, 1);
             let size   = load_u32(self.cursor, 2);
+            let (perms, zero_fill) = load_flags(self.cursor, self.stride);
 
-            self.cursor = self.cursor.add(SEGMENT_HEADER_SIZE);
+            self.cursor = self.cursor.add(self.stride);
             self.remaining -= 1;
 
-            Some(RawSegment { offset, addr, size })
+            Some(RawSegment { offset, addr, size, perms, zero_fill })
         }
     }
 
@@ -218,7 +522,24 @@ pub fn to_memory(data: &[u8]) -> Result<Vec<u8>> {
     File::from_bytes(data)?.to_memory()
 }
 
+pub fn load(data: &[u8]) -> Result<Machine> {
+    let file = File::from_bytes(data)?;
+    let mut machine = Machine::with_state(file.initial_state(), file.to_memory()?);
+    machine.set_memory_map(file.memory_map());
+    Ok(machine)
+}
+
+// Writes a file with only the memory size and the segments.
 pub fn serialize<'a, Iter>(memory_size: u32, segments: Iter, buffer: &mut Vec<u8>) -> Result<()>
+where
+    Iter: IntoIterator,
+    Iter::Item: Into<Segment<'a>>,
+{
+    serialize_with(&Header::new(memory_size), segments, buffer)
+}
+
+#[allow(clippy::inconsistent_digit_grouping)]
+pub fn serialize_with<'a, Iter>(header: &Header, segments: Iter, buffer: &mut Vec<u8>) -> Result<()>
 where
     Iter: IntoIterator,
     Iter::Item: Into<Segment<'a>>,
@@ -236,18 +557,55 @@ where
 
         let offset = try_u32!(data.len())?;
         let size = try_u32!(segment.data.len())?;
+        let perms = segment.perms;
 
-        raw_segments.push(RawSegment { offset, addr: segment.addr, size });
-        data.extend_from_slice(segment.data);
+        if size != 0 || segment.zero_size == 0 {
+            raw_segments.push(RawSegment { offset, addr: segment.addr, size, perms, zero_fill: false });
+            data.extend_from_slice(segment.data);
+        }
+        if segment.zero_size != 0 {
+            let addr = segment.addr.wrapping_add(size);
+            let size = segment.zero_size;
+            raw_segments.push(RawSegment { offset: 0, addr, size, perms, zero_fill: true });
+        }
+    }
+
+    let mut raw_sections: SmallVec<[RawSection; 4]> = SmallVec::new();
+    if !header.symbols.is_empty() {
+        let offset = try_u32!(data.len())?;
+        serialize_symbols(header.symbols, &mut data)?;
+        let size = try_u32!(data.len())? - offset;
+        raw_sections.push(RawSection { kind: SECTION_SYMBOLS, offset, size });
+    }
+    if !header.lines.is_empty() {
+        let offset = try_u32!(data.len())?;
+        serialize_lines(header.files, header.lines, &mut data)?;
+        let size = try_u32!(data.len())? - offset;
+        raw_sections.push(RawSection { kind: SECTION_LINES, offset, size });
     }
 
     let segment_count = try_u32!(raw_segments.len())?;
+    let section_count = try_u32!(raw_sections.len())?;
+
+    // The oldest version that can hold the file is written.
+    let has_flags = raw_segments.iter().any(|segment| segment.perms != Perms::ALL || segment.zero_fill);
+    let (version, header_size) = if has_flags {
+        (4, FILE_HEADER_SIZE_V3)
+    } else if !raw_sections.is_empty() {
+        (3, FILE_HEADER_SIZE_V3)
+    } else if header.entry != DEFAULT_ENTRY || header.initial_sp.is_some() {
+        (2, FILE_HEADER_SIZE_V2)
+    } else {
+        (1, FILE_HEADER_SIZE_V1)
+    };
 
-    let offset_to_data = match (segment_count as usize).checked_mul(SEGMENT_HEADER_SIZE) {
-        Some(value) => match value.checked_add(FILE_HEADER_SIZE) {
-            Some(offset) => try_u32!(offset)?,
-            None => return Err(Error::FileTooLarge),
-        },
+    let segment_header_size = if has_flags { SEGMENT_HEADER_SIZE_V4 } else { SEGMENT_HEADER_SIZE_V1 };
+    let headers_size = (segment_count as usize)
+        .checked_mul(segment_header_size)
+        .zip((section_count as usize).checked_mul(SECTION_HEADER_SIZE))
+        .and_then(|(a, b)| a.checked_add(b)?.checked_add(header_size));
+    let offset_to_data = match headers_size {
+        Some(offset) => try_u32!(offset)?,
         None => return Err(Error::FileTooLarge),
     };
 
@@ -256,19 +614,36 @@ where
     }
 
     {
-        let mut header = [0_u8; FILE_HEADER_SIZE];
-        header[0_..4_].copy_from_slice(&FILE_MAGIC);
-        header[4_..8_].copy_from_slice(&u32::to_le_bytes(1));
-        header[8_..12].copy_from_slice(&u32::to_le_bytes(memory_size));
-        header[12..16].copy_from_slice(&u32::to_le_bytes(segment_count));
-        buffer.extend_from_slice(&header);
+        let mut bytes = [0_u8; FILE_HEADER_SIZE_V3];
+        bytes[0_..4_].copy_from_slice(&FILE_MAGIC);
+        bytes[4_..8_].copy_from_slice(&u32::to_le_bytes(version));
+        bytes[8_..12].copy_from_slice(&u32::to_le_bytes(header.memory_size));
+        bytes[12..16].copy_from_slice(&u32::to_le_bytes(segment_count));
+        bytes[16..20].copy_from_slice(&u32::to_le_bytes(header.entry));
+        bytes[20..24].copy_from_slice(&u32::to_le_bytes(header.initial_sp.unwrap_or(0)));
+        bytes[24..28].copy_from_slice(&u32::to_le_bytes(section_count));
+        buffer.extend_from_slice(&bytes[..header_size]);
     }
 
     for segment in raw_segments {
-        let mut header = [0_u8; SEGMENT_HEADER_SIZE];
-        header[0_..4_].copy_from_slice(&u32::to_le_bytes(segment.offset + offset_to_data));
+        let (offset, flags) = if segment.zero_fill {
+            (0, segment.perms.bits() | SEGMENT_ZERO_FILL)
+        } else {
+            (segment.offset + offset_to_data, segment.perms.bits())
+        };
+        let mut header = [0_u8; SEGMENT_HEADER_SIZE_V4];
+        header[0_..4_].copy_from_slice(&u32::to_le_bytes(offset));
         header[4_..8_].copy_from_slice(&u32::to_le_bytes(segment.addr));
         header[8_..12].copy_from_slice(&u32::to_le_bytes(segment.size));
+        header[12..16].copy_from_slice(&u32::to_le_bytes(flags));
+        buffer.extend_from_slice(&header[..segment_header_size]);
+    }
+
+    for section in raw_sections {
+        let mut header = [0_u8; SECTION_HEADER_SIZE];
+        header[0_..4_].copy_from_slice(&u32::to_le_bytes(section.kind));
+        header[4_..8_].copy_from_slice(&u32::to_le_bytes(section.offset + offset_to_data));
+        header[8_..12].copy_from_slice(&u32::to_le_bytes(section.size));
         buffer.extend_from_slice(&header);
     }
 
@@ -277,6 +652,58 @@ where
     Ok(())
 }
 
+// A count followed by fixed-size records and the names they point to; name
+// offsets are relative to the start of the section.
+fn serialize_symbols(symbols: &[Symbol], buffer: &mut Vec<u8>) -> Result<()> {
+    let start = buffer.len();
+    let count = u32::try_from(symbols.len()).map_err(|_| Error::FileTooLarge)?;
+    buffer.extend_from_slice(&u32::to_le_bytes(count));
+
+    let mut name_offset = 4 + symbols.len() * SYMBOL_SIZE;
+    for symbol in symbols {
+        let kind = match symbol.kind {
+            SymbolKind::Code     => 0_u32,
+            SymbolKind::Data     => 1,
+            SymbolKind::Constant => 2,
+        };
+        let offset = u32::try_from(name_offset).map_err(|_| Error::FileTooLarge)?;
+        let size = u32::try_from(symbol.name.len()).map_err(|_| Error::FileTooLarge)?;
+        for value in [symbol.value, symbol.size, kind, offset, size] {
+            buffer.extend_from_slice(&u32::to_le_bytes(value));
+        }
+        name_offset += symbol.name.len();
+    }
+    for symbol in symbols {
+        buffer.extend_from_slice(symbol.name.as_bytes());
+    }
+
+    debug_assert_eq!(buffer.len() - start, name_offset);
+    Ok(())
+}
+
+// File and line counts, file name records, line records and then the names.
+fn serialize_lines(files: &[&str], lines: &[Line], buffer: &mut Vec<u8>) -> Result<()> {
+    let try_u32 = |value: usize| u32::try_from(value).map_err(|_| Error::FileTooLarge);
+    buffer.extend_from_slice(&u32::to_le_bytes(try_u32(files.len())?));
+    buffer.extend_from_slice(&u32::to_le_bytes(try_u32(lines.len())?));
+
+    let mut name_offset = 8 + files.len() * FILE_NAME_SIZE + lines.len() * LINE_SIZE;
+    for file in files {
+        buffer.extend_from_slice(&u32::to_le_bytes(try_u32(name_offset)?));
+        buffer.extend_from_slice(&u32::to_le_bytes(try_u32(file.len())?));
+        name_offset += file.len();
+    }
+    for line in lines {
+        for value in [line.addr, line.size, line.file, line.line] {
+            buffer.extend_from_slice(&u32::to_le_bytes(value));
+        }
+    }
+    for file in files {
+        buffer.extend_from_slice(file.as_bytes());
+    }
+    Ok(())
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Error::*;
@@ -301,8 +728,209 @@ impl fmt::Display for Error {
                     addr, size,
                 )
             }
+            InvalidSection(kind) => write!(f, "invalid section of kind {}", kind),
+            InvalidPerms(flags) => write!(f, "invalid segment flags 0x{:X}", flags),
         }
     }
 }
 
 impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(addr: u32, data: &[u8]) -> Segment<'_> {
+        Segment { addr, data, zero_size: 0, perms: Perms::ALL }
+    }
+
+    fn write<'a>(header: &Header, segments: impl IntoIterator<Item = Segment<'a>>) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        serialize_with(header, segments, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn version_1() {
+        let mut data = Vec::new();
+        serialize(0x2000, [segment(0x1000, &[1, 2, 3, 4]), segment(0x10, &[5])], &mut data).unwrap();
+        assert_eq!(read_u32(&data, 1), 1);
+        assert_eq!(data.len(), FILE_HEADER_SIZE_V1 + 2 * SEGMENT_HEADER_SIZE_V1 + 5);
+
+        let file = File::from_bytes(&data).unwrap();
+        assert_eq!(file.memory_size(), 0x2000);
+        assert_eq!(file.entry(), DEFAULT_ENTRY);
+        assert_eq!(file.initial_sp(), None);
+        let memory = file.to_memory().unwrap();
+        assert_eq!(memory[0x1000..0x1004], [1, 2, 3, 4]);
+        assert_eq!(memory[0x10], 5);
+    }
+
+    #[test]
+    fn version_2() {
+        let header = Header { entry: 0x1800, initial_sp: Some(0x2000), ..Header::new(0x2000) };
+        let data = write(&header, [segment(0x1800, &[1, 2, 3, 4])]);
+        assert_eq!(read_u32(&data, 1), 2);
+
+        let file = File::from_bytes(&data).unwrap();
+        assert_eq!(file.entry(), 0x1800);
+        assert_eq!(file.initial_sp(), Some(0x2000));
+        let state = file.initial_state();
+        assert_eq!((state.pc, state.regs[2]), (0x1800, 0x2000));
+
+        let header = Header { entry: 0x1800, ..Header::new(0x2000) };
+        let data = write(&header, [segment(0x1800, &[1, 2, 3, 4])]);
+        assert_eq!(File::from_bytes(&data).unwrap().initial_sp(), None);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut data = write(&Header::new(0x100), [segment(0x80, &[1, 2, 3, 4])]);
+        assert!(matches!(File::from_bytes(&data[..FILE_HEADER_SIZE_V1 + 4]), Err(Error::FileTooShort)));
+        assert!(matches!(File::from_bytes(&data[..8]), Err(Error::InvalidFormat)));
+
+        // The segment does not fit into memory.
+        data[8..12].copy_from_slice(&u32::to_le_bytes(0x82));
+        assert!(matches!(to_memory(&data), Err(Error::InvalidAddrRange { addr: 0x80, size: 4 })));
+        data[4] = 5;
+        assert!(matches!(File::from_bytes(&data), Err(Error::UnsupportedVersion(5))));
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = [
+            Symbol { name: "main", value: 0x1000, size: 8, kind: SymbolKind::Code },
+            Symbol { name: "buffer", value: 0x1800, size: 0x100, kind: SymbolKind::Data },
+            Symbol { name: "SIZE", value: 0x100, size: 0, kind: SymbolKind::Constant },
+        ];
+        let header = Header { symbols: &symbols, ..Header::new(0x2000) };
+        let data = write(&header, [segment(0x1000, &[0; 8])]);
+        assert_eq!(read_u32(&data, 1), 3);
+
+        let file = File::from_bytes(&data).unwrap();
+        assert_eq!(file.section_count(), 1);
+        assert_eq!(file.to_memory().unwrap().len(), 0x2000);
+        let fields = |s: &Symbol| (s.name.to_owned(), s.value, s.size, s.kind);
+        let read: Vec<_> = file.symbols().unwrap().iter().map(fields).collect();
+        assert_eq!(read, symbols.iter().map(fields).collect::<Vec<_>>());
+        assert!(file.line_table().unwrap().lines.is_empty());
+    }
+
+    #[test]
+    fn invalid_symbols() {
+        let symbols = [Symbol { name: "main", value: 0x1000, size: 8, kind: SymbolKind::Code }];
+        let header = Header { symbols: &symbols, ..Header::new(0x2000) };
+        let mut data = write(&header, [segment(0x1000, &[0; 8])]);
+        let section = File::from_bytes(&data).unwrap().raw_sections().next().unwrap();
+
+        // The name runs past the end of the section.
+        let name_size = section.offset as usize + 4 + 4 * 4;
+        data[name_size] = 5;
+        assert!(matches!(File::from_bytes(&data).unwrap().symbols(), Err(Error::InvalidSection(1))));
+
+        // Sections of unknown kinds are skipped.
+        let kind = FILE_HEADER_SIZE_V3 + SEGMENT_HEADER_SIZE_V1;
+        data[kind] = 9;
+        let file = File::from_bytes(&data).unwrap();
+        assert!(file.symbols().unwrap().is_empty());
+        assert!(file.section(9).unwrap().is_some());
+    }
+
+    #[test]
+    fn line_table() {
+        let files = ["main.asm", "lib/print.asm"];
+        let lines = [
+            Line { addr: 0x1008, size: 4, file: 1, line: 3 },
+            Line { addr: 0x1000, size: 8, file: 0, line: 10 },
+        ];
+        let header = Header { files: &files, lines: &lines, ..Header::new(0x2000) };
+        let data = write(&header, [segment(0x1000, &[0; 12])]);
+        assert_eq!(read_u32(&data, 1), 3);
+
+        let table = File::from_bytes(&data).unwrap().line_table().unwrap();
+        assert_eq!(table.files, files);
+        assert_eq!(table.lines.iter().map(|line| line.addr).collect::<Vec<_>>(), [0x1000, 0x1008]);
+        assert_eq!(table.find(0x1000), Some(("main.asm", 10)));
+        assert_eq!(table.find(0x1007), Some(("main.asm", 10)));
+        assert_eq!(table.find(0x100B), Some(("lib/print.asm", 3)));
+        assert_eq!(table.find(0x100C), None);
+        assert_eq!(table.find(0xFFF), None);
+    }
+
+    #[test]
+    fn invalid_line_table() {
+        let lines = [Line { addr: 0x1000, size: 4, file: 1, line: 1 }];
+        let header = Header { files: &["main.asm"], lines: &lines, ..Header::new(0x2000) };
+        let data = write(&header, [segment(0x1000, &[0; 4])]);
+        let file = File::from_bytes(&data).unwrap();
+        assert!(matches!(file.line_table(), Err(Error::InvalidSection(2))));
+    }
+
+    #[test]
+    fn perms() {
+        let segments = [
+            Segment { perms: Perms::READ | Perms::EXEC, ..segment(0x1000, &[0; 8]) },
+            Segment { perms: Perms::READ, ..segment(0x1800, &[1]) },
+        ];
+        let data = write(&Header::new(0x2000), segments);
+        assert_eq!(read_u32(&data, 1), 4);
+
+        let file = File::from_bytes(&data).unwrap();
+        let perms: Vec<_> = file.raw_segments().map(|segment| segment.perms).collect();
+        assert_eq!(perms, [Perms::READ | Perms::EXEC, Perms::READ]);
+        let map = file.memory_map();
+        assert_eq!(map.default_perms(), Perms::RW);
+        assert_eq!(map.perms(0x1000, 4), Perms::READ | Perms::EXEC);
+        assert_eq!(map.perms(0x1800, 1), Perms::READ);
+        assert_eq!(map.perms(0x1008, 4), Perms::RW);
+        assert_eq!(map.perms(0x1006, 4), Perms::READ);
+    }
+
+    #[test]
+    fn all_perms_leave_memory_flat() {
+        let data = write(&Header::new(0x2000), [segment(0x1000, &[0; 8])]);
+        let file = File::from_bytes(&data).unwrap();
+        assert!(file.memory_map().regions().is_empty());
+        assert_eq!(file.memory_map().default_perms(), Perms::ALL);
+    }
+
+    #[test]
+    fn invalid_perms() {
+        let segments = [Segment { perms: Perms::READ, ..segment(0x1000, &[0; 4]) }];
+        let mut data = write(&Header::new(0x2000), segments);
+        let flags = FILE_HEADER_SIZE_V3 + 3 * 4;
+        data[flags] = 0x11;
+        assert!(matches!(File::from_bytes(&data), Err(Error::InvalidPerms(0x11))));
+    }
+
+    #[test]
+    fn zero_fill() {
+        let segments = [
+            Segment { zero_size: 0x100, ..segment(0x1000, &[1, 2, 3, 4]) },
+            Segment { zero_size: 0x800, ..segment(0x1800, &[]) },
+        ];
+        let data = write(&Header::new(0x2000), segments);
+        assert_eq!(read_u32(&data, 1), 4);
+        assert_eq!(data.len(), FILE_HEADER_SIZE_V3 + 3 * SEGMENT_HEADER_SIZE_V4 + 4);
+
+        let file = File::from_bytes(&data).unwrap();
+        let raw: Vec<_> = file.raw_segments().map(|s| (s.addr, s.size, s.zero_fill)).collect();
+        assert_eq!(raw, [(0x1000, 4, false), (0x1004, 0x100, true), (0x1800, 0x800, true)]);
+        // Zero-filled segments do not restrict access.
+        assert!(file.memory_map().regions().is_empty());
+    }
+
+    #[test]
+    fn zero_fill_overwrites_earlier_segments() {
+        let segments = [
+            segment(0x1000, &[0xFF; 8]),
+            Segment { zero_size: 4, ..segment(0x1004, &[]) },
+        ];
+        let memory = to_memory(&write(&Header::new(0x2000), segments)).unwrap();
+        assert_eq!(memory[0x1000..0x1008], [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+
+        let segments = [Segment { zero_size: 0x100, ..segment(0x1F00, &[1]) }];
+        let data = write(&Header::new(0x2000), segments);
+        assert!(matches!(to_memory(&data), Err(Error::InvalidAddrRange { addr: 0x1F01, size: 0x100 })));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::binfile::{Error, Result};
+use crate::snapshot;
+use crate::vm;
+
+// A core file is a header with the fault followed by a snapshot of the machine
+// at the faulting instruction.
+const FILE_HEADER_SIZE: usize = 4 * 4;
+
+const FILE_MAGIC: [u8; 4] = [0x80, b'C', b'O', b'R'];
+
+pub struct File<'a> {
+    pub fault: vm::Error,
+    pub snapshot: snapshot::File<'a>,
+}
+
+fn load_u32(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+pub fn is_core(data: &[u8]) -> bool {
+    data.starts_with(&FILE_MAGIC)
+}
+
+impl<'a> File<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Result<File<'a>> {
+        if data.len() < FILE_HEADER_SIZE || !is_core(data) {
+            return Err(Error::InvalidFormat);
+        }
+
+        let version = load_u32(data, 1);
+        if version != 1 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let value = load_u32(data, 3);
+        let fault = match load_u32(data, 2) {
+            0 => vm::Error::UnknownSysfn(value),
+            1 => vm::Error::UnknownInst(value),
+            2 => vm::Error::InvalidAddr(value),
+            3 => vm::Error::InvalidPc(value),
+            4 => vm::Error::ExecProtected(value),
+            5 => vm::Error::ReadProtected(value),
+            6 => vm::Error::WriteProtected(value),
+            _ => return Err(Error::InvalidFormat),
+        };
+
+        let snapshot = snapshot::File::from_bytes(&data[FILE_HEADER_SIZE..])?;
+        Ok(File { fault, snapshot })
+    }
+}
+
+#[allow(clippy::inconsistent_digit_grouping)]
+pub fn serialize(
+    fault: &vm::Error,
+    state: &vm::State,
+    memory: &[u8],
+    memory_map: &vm::MemoryMap,
+    buffer: &mut Vec<u8>,
+) -> Result<()> {
+    let (kind, value) = match *fault {
+        vm::Error::UnknownSysfn(value)   => (0, value),
+        vm::Error::UnknownInst(value)    => (1, value),
+        vm::Error::InvalidAddr(value)    => (2, value),
+        vm::Error::InvalidPc(value)      => (3, value),
+        vm::Error::ExecProtected(value)  => (4, value),
+        vm::Error::ReadProtected(value)  => (5, value),
+        vm::Error::WriteProtected(value) => (6, value),
+    };
+
+    let mut header = [0_u8; FILE_HEADER_SIZE];
+    header[0_..4_].copy_from_slice(&FILE_MAGIC);
+    header[4_..8_].copy_from_slice(&u32::to_le_bytes(1));
+    header[8_..12].copy_from_slice(&u32::to_le_bytes(kind));
+    header[12..16].copy_from_slice(&u32::to_le_bytes(value));
+    buffer.extend_from_slice(&header);
+
+    snapshot::serialize(state, memory, memory_map, &[], buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{MemoryMap, Perms, Region, State};
+
+    #[test]
+    fn round_trip() {
+        let faults = [
+            vm::Error::UnknownSysfn(5),
+            vm::Error::UnknownInst(0xFF),
+            vm::Error::InvalidAddr(0x10000),
+            vm::Error::InvalidPc(0x1002),
+            vm::Error::ExecProtected(0x100),
+            vm::Error::ReadProtected(0x200),
+            vm::Error::WriteProtected(0x300),
+        ];
+        let mut state = State::with_pc(0x1004);
+        state.regs[3] = 42;
+        let mut memory = vec![0; 0x1000];
+        memory[0x100] = 0x13;
+        let memory_map = MemoryMap::new([Region { addr: 0, size: 0x800, perms: Perms::READ }], Perms::RW);
+
+        for fault in faults {
+            let mut buffer = Vec::new();
+            serialize(&fault, &state, &memory, &memory_map, &mut buffer).unwrap();
+            let core = File::from_bytes(&buffer).unwrap();
+            assert_eq!(core.fault.to_string(), fault.to_string());
+            assert_eq!(core.snapshot.state().pc, 0x1004);
+            assert_eq!(core.snapshot.state().regs, state.regs);
+            assert_eq!(core.snapshot.to_memory().unwrap(), memory);
+            assert_eq!(core.snapshot.memory_map().regions().len(), 1);
+            assert_eq!(core.snapshot.memory_map().default_perms(), Perms::RW);
+        }
+    }
+
+    #[test]
+    fn invalid_fault() {
+        let mut buffer = Vec::new();
+        serialize(&vm::Error::InvalidPc(2), &State::new(), &[], &MemoryMap::flat(), &mut buffer).unwrap();
+        buffer[8] = 7;
+        assert!(matches!(File::from_bytes(&buffer), Err(Error::InvalidFormat)));
+        assert!(File::from_bytes(&buffer[FILE_HEADER_SIZE..]).is_err());
+    }
+}
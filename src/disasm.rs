@@ -0,0 +1,239 @@
+use std::fmt;
+
+use crate::isa::{ImmKind, Instruction, Operands, Rc, Rrc};
+use crate::symbols::SymbolMap;
+
+pub const REG_NAMES: [&str; 16] = [
+    "zero", "lr", "sp", "a0", "a1", "a2", "a3", "a4",
+    "a5", "s0", "s1", "s2", "s3", "s4", "s5", "s6",
+];
+
+// Formats an instruction as `asm` source. The alternate flag (`{:#}`) disables
+// pseudo-instructions. Branch targets are printed as `name+offset` when
+// symbols are given.
+#[derive(Clone, Copy, Debug)]
+pub struct Disasm<'a> {
+    pub inst: u32,
+    pub addr: u32,
+    pub symbols: Option<&'a SymbolMap>,
+}
+
+impl Disasm<'_> {
+    pub fn new(inst: u32, addr: u32) -> Disasm<'static> {
+        Disasm { inst, addr, symbols: None }
+    }
+
+    pub fn with_symbols(inst: u32, addr: u32, symbols: &SymbolMap) -> Disasm<'_> {
+        Disasm { inst, addr, symbols: Some(symbols) }
+    }
+
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        Instruction::decode(self.inst).ok().map(|inst| inst.mnemonic())
+    }
+
+    pub fn branch_target(&self) -> Option<u32> {
+        let inst = Instruction::decode(self.inst).ok()?;
+        if inst.info().imm != ImmKind::Offset {
+            return None;
+        }
+        let off = match inst.operands() {
+            Operands::Rc(Rc { imm, .. }) | Operands::Rrc(Rrc { imm, .. }) => imm as u32,
+            _ => return None,
+        };
+        let next = u32::wrapping_add(self.addr, 4);
+        Some(u32::wrapping_add(next, off << 2))
+    }
+}
+
+fn reg(r: u8) -> &'static str {
+    REG_NAMES[r as usize]
+}
+
+struct Target<'a>(u32, Option<&'a SymbolMap>);
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            Some(symbols) => symbols.symbolize(self.0).fmt(f),
+            None => write!(f, "0x{:X}", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Disasm<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inst = match Instruction::decode(self.inst) {
+            Ok(inst) => inst,
+            Err(_) => return write!(f, "{:<8}0x{:08X}", "d32", self.inst),
+        };
+        let target = || Target(self.branch_target().unwrap(), self.symbols);
+
+        // Pseudo-instructions of the assembler that encode to exactly `inst`.
+        if !f.alternate() {
+            match inst {
+                Instruction::Addi(Rrc { r1, r2, imm: 0 }) => {
+                    return write!(f, "{:<8}%{}, %{}", "mov", reg(r1), reg(r2));
+                }
+                Instruction::Jalr(Rrc { r1: 0, r2: 1, imm: 0 }) => return f.write_str("ret"),
+                Instruction::Jal(Rc { r1: 0, .. }) => {
+                    return write!(f, "{:<8}{}", "jmp", target());
+                }
+                Instruction::Jal(Rc { r1: 1, .. }) => {
+                    return write!(f, "{:<8}{}", "call", target());
+                }
+                _ => {}
+            }
+        }
+
+        let name = inst.mnemonic();
+        match (inst.operands(), inst.info().imm) {
+            (Operands::Rc(Rc { r1, .. }), ImmKind::Offset) => {
+                write!(f, "{:<8}%{}, {}", name, reg(r1), target())
+            }
+            (Operands::Rc(Rc { r1, imm }), ImmKind::Upper) => {
+                write!(f, "{:<8}%{}, 0x{:X}", name, reg(r1), imm)
+            }
+            (Operands::Rc(Rc { r1, imm }), _) => {
+                write!(f, "{:<8}%{}, {}", name, reg(r1), imm)
+            }
+            (Operands::Rrc(Rrc { r1, r2, .. }), ImmKind::Offset) => {
+                write!(f, "{:<8}%{}, %{}, {}", name, reg(r1), reg(r2), target())
+            }
+            (Operands::Rrc(Rrc { r1, r2, imm }), _) => {
+                write!(f, "{:<8}%{}, %{}, {}", name, reg(r1), reg(r2), imm)
+            }
+            (Operands::Rrr(ops), _) => {
+                write!(f, "{:<8}%{}, %{}, %{}", name, reg(ops.r1), reg(ops.r2), reg(ops.r3))
+            }
+            (Operands::Rrrr(ops), _) => {
+                write!(
+                    f,
+                    "{:<8}%{}, %{}, %{}, %{}",
+                    name, reg(ops.r1), reg(ops.r2), reg(ops.r3), reg(ops.r4),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::*;
+    use crate::asm;
+    use crate::binfile::{Symbol, SymbolKind};
+
+    fn disasm(inst: u32, addr: u32) -> String {
+        Disasm::new(inst, addr).to_string()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(disasm(0xFFFF_B381, 0), "li      %a0, -5");
+        assert_eq!(disasm(0xFFFF_FF82, 0), "lui     %s6, 0xFFFFF");
+        assert_eq!(disasm(0x0000_2083, 0), "sysfn   %zero, 2");
+        assert_eq!(disasm(0x8000_2485, 0), "st.u16  %a1, %sp, -32768");
+        assert_eq!(disasm(0x0007_4388, 0), "addi    %a0, %a1, 7");
+        assert_eq!(disasm(0x0004_31A1, 0), "jalr    %lr, %a0, 4");
+        assert_eq!(disasm(0x000F_43A9, 0), "sub     %a0, %a1, %s6");
+        assert_eq!(disasm(0x0012_43BB, 0), "divu    %a0, %a1, %sp, %lr");
+        assert_eq!(disasm(0xFFFF_FFFF, 0), "d32     0xFFFFFFFF");
+        // Reserved bits of an RRR instruction.
+        assert_eq!(disasm(0x0010_00A8, 0), "d32     0x001000A8");
+        assert_eq!(Disasm::new(0x0000_009C, 0).mnemonic(), Some("ld.u8"));
+        assert_eq!(Disasm::new(0, 0).mnemonic(), None);
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        assert_eq!(disasm(0x0000_2388, 0), "mov     %a0, %sp");
+        assert_eq!(format!("{:#}", Disasm::new(0x0000_2388, 0)), "addi    %a0, %sp, 0");
+        assert_eq!(disasm(0x0000_10A1, 0), "ret");
+        assert_eq!(format!("{:#}", Disasm::new(0x0000_10A1, 0)), "jalr    %zero, %lr, 0");
+
+        assert_eq!(disasm(0x0000_30A0, 0x1000), "jmp     0x1010");
+        assert_eq!(disasm(0xFFFF_E1A0, 0x1000), "call    0xFFC");
+        assert_eq!(disasm(0x0000_03A0, 0x1000), "jal     %a0, 0x1004");
+        // Other forms of `jalr` are not `ret`.
+        assert_eq!(disasm(0x0004_10A1, 0), "jalr    %zero, %lr, 4");
+    }
+
+    #[test]
+    fn branch_targets() {
+        let beq = 0xFFFC_03A2; // beq %a0, %zero, -4
+        assert_eq!(Disasm::new(beq, 0x1010).branch_target(), Some(0x1004));
+        assert_eq!(disasm(beq, 0x1010), "beq     %a0, %zero, 0x1004");
+        assert_eq!(Disasm::new(0x7FFF_F1A0, 0).branch_target(), Some(0x200000));
+        assert_eq!(Disasm::new(0x0001_3388, 0).branch_target(), None);
+
+        let symbols = SymbolMap::from_symbols(&[
+            Symbol { name: "main", value: 0x1000, size: 0x10, kind: SymbolKind::Code },
+            Symbol { name: "exit", value: 0x1020, size: 0, kind: SymbolKind::Code },
+        ]);
+        assert_eq!(Disasm::with_symbols(beq, 0x1010, &symbols).to_string(), "beq     %a0, %zero, main+0x4");
+        let call = 0xFFFF_F1A0; // call -1
+        assert_eq!(Disasm::with_symbols(call, 0x1000, &symbols).to_string(), "call    main");
+        let jmp = 0x0000_70A0; // jmp 7
+        assert_eq!(Disasm::with_symbols(jmp, 0x1000, &symbols).to_string(), "jmp     exit");
+        assert_eq!(Disasm::with_symbols(jmp, 0x1004, &symbols).to_string(), "jmp     0x1024");
+    }
+
+    // Disassembles the segments of a program and assembles the listing
+    // again.
+    #[test]
+    fn reassemble() {
+        let source = "
+    mem     0x2000
+    seg     0x1000
+main:
+    li      %a0, -5
+    lui     %a1, 0x12345
+    call    func
+    beq     %a0, %zero, main
+    bgeu    %a1, %a0, end
+    mulw    %a0, %a1, %a2, %a3
+    sub     %s0, %s1, %s6
+    ld.s8   %a0, %sp, -4
+    st.u16  %a0, %sp, 2
+    jalr    %lr, %a0, 0
+    sysfn   %a0, 0
+func:
+    mov     %a1, %a0
+    xori    %a0, %a0, -1
+    ret
+end:
+    jmp     main
+
+    seg     0x100
+    d32     0x12345678, 0xFFFFFFFF
+    d8      1, 2, 3
+";
+        let program = asm::assemble(source).unwrap();
+
+        let mut listing = format!("mem 0x{:X}\n", program.memory_size);
+        for segment in &program.segments {
+            writeln!(listing, "seg 0x{:X}", segment.addr).unwrap();
+            let mut words = segment.data.chunks_exact(4);
+            let mut addr = segment.addr;
+            for word in &mut words {
+                let inst = u32::from_le_bytes(word.try_into().unwrap());
+                writeln!(listing, "    {}", Disasm::new(inst, addr)).unwrap();
+                addr += 4;
+            }
+            for byte in words.remainder() {
+                writeln!(listing, "    d8 {}", byte).unwrap();
+            }
+        }
+
+        let reassembled = asm::assemble(&listing).unwrap();
+        let segments = |program: &asm::Program| {
+            let mut segments: Vec<_> = program.segments.iter().map(|s| (s.addr, s.data.clone())).collect();
+            segments.sort();
+            segments
+        };
+        assert_eq!(segments(&reassembled), segments(&program));
+        assert!(listing.contains("    call    0x102C\n"), "{}", listing);
+        assert!(listing.contains("    ret\n"), "{}", listing);
+    }
+}
@@ -0,0 +1,360 @@
+use std::error;
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    UnknownOpcode(u32),
+    ReservedBits(u32),
+}
+
+pub const ZERO: u8 = 0;
+pub const LR:   u8 = 1;
+pub const SP:   u8 = 2;
+pub const A0:   u8 = 3;
+pub const A1:   u8 = 4;
+pub const A2:   u8 = 5;
+pub const A3:   u8 = 6;
+pub const A4:   u8 = 7;
+pub const A5:   u8 = 8;
+pub const S0:   u8 = 9;
+pub const S1:   u8 = 10;
+pub const S2:   u8 = 11;
+pub const S3:   u8 = 12;
+pub const S4:   u8 = 13;
+pub const S5:   u8 = 14;
+pub const S6:   u8 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Rc,
+    Rrc,
+    Rrr,
+    Rrrr,
+}
+
+// How the immediate operand of an instruction is interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImmKind {
+    None,
+    Signed,
+    Upper,
+    Offset,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InstInfo {
+    pub mnemonic: &'static str,
+    pub opcode: u32,
+    pub format: Format,
+    pub imm: ImmKind,
+}
+
+// `imm` of an `Upper` instruction holds the unsigned upper 20 bits, `imm` of
+// an `Offset` instruction is counted in words from the next instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rc {
+    pub r1: u8,
+    pub imm: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rrc {
+    pub r1: u8,
+    pub r2: u8,
+    pub imm: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rrr {
+    pub r1: u8,
+    pub r2: u8,
+    pub r3: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rrrr {
+    pub r1: u8,
+    pub r2: u8,
+    pub r3: u8,
+    pub r4: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operands {
+    Rc(Rc),
+    Rrc(Rrc),
+    Rrr(Rrr),
+    Rrrr(Rrrr),
+}
+
+macro_rules! isa {
+    ($($variant:ident = $opcode:ident = $value:literal, $mnemonic:literal, $format:ident, $imm:ident;)*) => {
+        pub mod opcode {
+            $(pub const $opcode: u32 = $value;)*
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Instruction {
+            $($variant($format),)*
+        }
+
+        pub const INSTRUCTIONS: &[InstInfo] = &[
+            $(InstInfo {
+                mnemonic: $mnemonic,
+                opcode: opcode::$opcode,
+                format: Format::$format,
+                imm: ImmKind::$imm,
+            },)*
+        ];
+
+        impl Instruction {
+            pub fn opcode(&self) -> u32 {
+                match self {
+                    $(Instruction::$variant(_) => opcode::$opcode,)*
+                }
+            }
+
+            pub fn operands(&self) -> Operands {
+                match *self {
+                    $(Instruction::$variant(operands) => Operands::$format(operands),)*
+                }
+            }
+
+            // Returns None if the opcode is unknown or takes other operands.
+            pub fn from_parts(opcode: u32, operands: Operands) -> Option<Instruction> {
+                match (opcode, operands) {
+                    $((opcode::$opcode, Operands::$format(operands)) => {
+                        Some(Instruction::$variant(operands))
+                    })*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+isa! {
+    Li    = LI    = 0b10_000_001, "li",     Rc,   Signed;
+    Lui   = LUI   = 0b10_000_010, "lui",    Rc,   Upper;
+    Sysfn = SYSFN = 0b10_000_011, "sysfn",  Rc,   Signed;
+
+    Stu8  = STU8  = 0b10_000_100, "st.u8",  Rrc,  Signed;
+    Stu16 = STU16 = 0b10_000_101, "st.u16", Rrc,  Signed;
+    St    = ST    = 0b10_000_110, "st",     Rrc,  Signed;
+
+    Lds8  = LDS8  = 0b10_011_000, "ld.s8",  Rrc,  Signed;
+    Ldu8  = LDU8  = 0b10_011_100, "ld.u8",  Rrc,  Signed;
+    Lds16 = LDS16 = 0b10_011_001, "ld.s16", Rrc,  Signed;
+    Ldu16 = LDU16 = 0b10_011_101, "ld.u16", Rrc,  Signed;
+    Ld    = LD    = 0b10_011_010, "ld",     Rrc,  Signed;
+
+    Jal   = JAL   = 0b10_100_000, "jal",    Rc,   Offset;
+    Jalr  = JALR  = 0b10_100_001, "jalr",   Rrc,  Signed;
+    Beq   = BEQ   = 0b10_100_010, "beq",    Rrc,  Offset;
+    Bne   = BNE   = 0b10_100_011, "bne",    Rrc,  Offset;
+    Blt   = BLT   = 0b10_100_100, "blt",    Rrc,  Offset;
+    Bge   = BGE   = 0b10_100_101, "bge",    Rrc,  Offset;
+    Bltu  = BLTU  = 0b10_100_110, "bltu",   Rrc,  Offset;
+    Bgeu  = BGEU  = 0b10_100_111, "bgeu",   Rrc,  Offset;
+
+    Addi  = ADDI  = 0b10_001_000, "addi",   Rrc,  Signed;
+    Rsubi = RSUBI = 0b10_001_001, "rsubi",  Rrc,  Signed;
+    Muli  = MULI  = 0b10_001_010, "muli",   Rrc,  Signed;
+    Andi  = ANDI  = 0b10_010_000, "andi",   Rrc,  Signed;
+    Ori   = ORI   = 0b10_010_001, "ori",    Rrc,  Signed;
+    Xori  = XORI  = 0b10_010_010, "xori",   Rrc,  Signed;
+    Shli  = SHLI  = 0b10_010_011, "shli",   Rrc,  Signed;
+    Lshri = LSHRI = 0b10_010_100, "lshri",  Rrc,  Signed;
+    Ashri = ASHRI = 0b10_010_101, "ashri",  Rrc,  Signed;
+
+    Add   = ADD   = 0b10_101_000, "add",    Rrr,  None;
+    Sub   = SUB   = 0b10_101_001, "sub",    Rrr,  None;
+    Mul   = MUL   = 0b10_101_010, "mul",    Rrr,  None;
+    And   = AND   = 0b10_110_000, "and",    Rrr,  None;
+    Or    = OR    = 0b10_110_001, "or",     Rrr,  None;
+    Xor   = XOR   = 0b10_110_010, "xor",    Rrr,  None;
+    Shl   = SHL   = 0b10_110_011, "shl",    Rrr,  None;
+    Lshr  = LSHR  = 0b10_110_100, "lshr",   Rrr,  None;
+    Ashr  = ASHR  = 0b10_110_101, "ashr",   Rrr,  None;
+
+    Mulw  = MULW  = 0b10_111_000, "mulw",   Rrrr, None;
+    Mulwu = MULWU = 0b10_111_001, "mulwu",  Rrrr, None;
+    Div   = DIV   = 0b10_111_010, "div",    Rrrr, None;
+    Divu  = DIVU  = 0b10_111_011, "divu",   Rrrr, None;
+}
+
+pub fn info_by_opcode(opcode: u32) -> Option<&'static InstInfo> {
+    INSTRUCTIONS.iter().find(|info| info.opcode == opcode)
+}
+
+pub fn info_by_mnemonic(mnemonic: &str) -> Option<&'static InstInfo> {
+    INSTRUCTIONS.iter().find(|info| info.mnemonic == mnemonic)
+}
+
+impl Instruction {
+    pub fn info(&self) -> &'static InstInfo {
+        info_by_opcode(self.opcode()).unwrap()
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    // Operands that do not fit in their fields are truncated.
+    pub fn encode(&self) -> u32 {
+        let reg = |r: u8| (r & 0xF) as u32;
+        let operands = match self.operands() {
+            Operands::Rc(Rc { r1, imm }) => {
+                reg(r1) << 8 | (imm as u32) << 12
+            }
+            Operands::Rrc(Rrc { r1, r2, imm }) => {
+                reg(r1) << 8 | reg(r2) << 12 | (imm as u32) << 16
+            }
+            Operands::Rrr(Rrr { r1, r2, r3 }) => {
+                reg(r1) << 8 | reg(r2) << 12 | reg(r3) << 16
+            }
+            Operands::Rrrr(Rrrr { r1, r2, r3, r4 }) => {
+                reg(r1) << 8 | reg(r2) << 12 | reg(r3) << 16 | reg(r4) << 20
+            }
+        };
+        self.opcode() | operands
+    }
+
+    // Words with nonzero unused bits are rejected, so that `encode` always
+    // returns the decoded word.
+    pub fn decode(inst: u32) -> Result<Instruction> {
+        let info = info_by_opcode(inst & 0xFF).ok_or(Error::UnknownOpcode(inst))?;
+        if inst & reserved_bits(info.format) != 0 {
+            return Err(Error::ReservedBits(inst));
+        }
+        let operands = match info.format {
+            Format::Rc => {
+                let (r1, imm) = decode_rc(inst);
+                let imm = if info.imm == ImmKind::Upper { imm & 0xF_FFFF } else { imm };
+                Operands::Rc(Rc { r1: r1 as u8, imm: imm as i32 })
+            }
+            Format::Rrc => {
+                let (r1, r2, imm) = decode_rrc(inst);
+                Operands::Rrc(Rrc { r1: r1 as u8, r2: r2 as u8, imm: imm as i32 })
+            }
+            Format::Rrr => {
+                let (r1, r2, r3) = decode_rrr(inst);
+                Operands::Rrr(Rrr { r1: r1 as u8, r2: r2 as u8, r3: r3 as u8 })
+            }
+            Format::Rrrr => {
+                let (r1, r2, r3, r4) = decode_rrrr(inst);
+                Operands::Rrrr(Rrrr { r1: r1 as u8, r2: r2 as u8, r3: r3 as u8, r4: r4 as u8 })
+            }
+        };
+        Ok(Instruction::from_parts(info.opcode, operands).unwrap())
+    }
+}
+
+// Unused bits of the format, which must be zero.
+#[inline(always)]
+pub fn reserved_bits(format: Format) -> u32 {
+    match format {
+        Format::Rc | Format::Rrc => 0,
+        Format::Rrr => !0 << 20,
+        Format::Rrrr => !0 << 24,
+    }
+}
+
+pub fn decode_rc(inst: u32) -> (usize, u32) {
+    let r1 = (inst >> 8) & 0xF;
+    let imm = (inst as i32) >> 12;
+    (r1 as usize, imm as u32)
+}
+
+pub fn decode_rrc(inst: u32) -> (usize, usize, u32) {
+    let r1 = (inst >> 8) & 0xF;
+    let r2 = (inst >> 12) & 0xF;
+    let imm = (inst as i32) >> 16;
+    (r1 as usize, r2 as usize, imm as u32)
+}
+
+pub fn decode_rrr(inst: u32) -> (usize, usize, usize) {
+    let r1 = (inst >> 8) & 0xF;
+    let r2 = (inst >> 12) & 0xF;
+    let r3 = (inst >> 16) & 0xF;
+    (r1 as usize, r2 as usize, r3 as usize)
+}
+
+pub fn decode_rrrr(inst: u32) -> (usize, usize, usize, usize) {
+    let r1 = (inst >> 8) & 0xF;
+    let r2 = (inst >> 12) & 0xF;
+    let r3 = (inst >> 16) & 0xF;
+    let r4 = (inst >> 20) & 0xF;
+    (r1 as usize, r2 as usize, r3 as usize, r4 as usize)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownOpcode(inst) => write!(f, "unknown instruction 0x{:08X}", inst),
+            Error::ReservedBits(inst) => write!(f, "reserved bits set in instruction 0x{:08X}", inst),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Operands with the smallest and the largest registers and immediates
+    // of the format.
+    fn boundary_operands(info: &InstInfo) -> Vec<Operands> {
+        let (min, max) = match (info.format, info.imm) {
+            (Format::Rc, ImmKind::Upper) => (0, 0xF_FFFF),
+            (Format::Rc, _) => (-(1 << 19), (1 << 19) - 1),
+            _ => (-(1 << 15), (1 << 15) - 1),
+        };
+        [(0, min), (15, max), (7, 0)]
+            .into_iter()
+            .map(|(r, imm)| match info.format {
+                Format::Rc => Operands::Rc(Rc { r1: r, imm }),
+                Format::Rrc => Operands::Rrc(Rrc { r1: r, r2: 15 - r, imm }),
+                Format::Rrr => Operands::Rrr(Rrr { r1: r, r2: 15 - r, r3: r }),
+                Format::Rrrr => Operands::Rrrr(Rrrr { r1: r, r2: 15 - r, r3: r, r4: 15 - r }),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for info in INSTRUCTIONS {
+            for operands in boundary_operands(info) {
+                let inst = Instruction::from_parts(info.opcode, operands).unwrap();
+                assert_eq!(inst.mnemonic(), info.mnemonic);
+                let word = inst.encode();
+                assert_eq!(word & 0xFF, info.opcode);
+                let decoded = Instruction::decode(word).unwrap();
+                assert_eq!(decoded, inst, "{}", info.mnemonic);
+                assert_eq!(decoded.encode(), word);
+            }
+        }
+        assert_eq!(info_by_mnemonic("ld.u16").unwrap().opcode, opcode::LDU16);
+        assert!(Instruction::from_parts(opcode::ADD, Operands::Rc(Rc { r1: 0, imm: 0 })).is_none());
+    }
+
+    #[test]
+    fn reserved_bits_are_rejected() {
+        for info in INSTRUCTIONS {
+            for bit in 8..32 {
+                let word = info.opcode | 1 << bit;
+                let reserved = reserved_bits(info.format) & 1 << bit != 0;
+                match Instruction::decode(word) {
+                    Err(Error::ReservedBits(inst)) => assert!(reserved && inst == word),
+                    Ok(_) => assert!(!reserved, "{} 0x{:08X}", info.mnemonic, word),
+                    Err(err) => panic!("{}", err),
+                }
+            }
+        }
+        assert!(matches!(Instruction::decode(0x0000_0000), Err(Error::UnknownOpcode(0))));
+        assert!(matches!(Instruction::decode(0x0010_00FF), Err(Error::UnknownOpcode(0x0010_00FF))));
+    }
+}
@@ -1,3 +1,21 @@
+pub mod archive;
+pub mod asm;
 pub mod binfile;
-pub mod opcode;
+pub mod coredump;
+pub mod disasm;
+pub mod isa;
+pub mod link;
+pub mod object;
+pub mod snapshot;
+pub mod symbols;
 pub mod vm;
+
+#[cfg(test)]
+mod testing;
+
+pub use isa::opcode;
+
+// Runs the Rust examples of the documentation.
+#[cfg(doctest)]
+#[doc = include_str!("../docs/assembler.md")]
+struct AssemblerDocs;
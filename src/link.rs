@@ -0,0 +1,517 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+
+use crate::archive::Archive;
+use crate::asm::{LineEntry, Program, Segment, Symbol};
+use crate::binfile;
+use crate::isa::{Instruction, Operands, Rc, Rrc};
+use crate::object::{Object, Place, RelocKind, Target};
+use crate::vm::Perms;
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    // Index of the object the error is about.
+    pub object: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErrorKind {
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    RedefinedEntry,
+    SectionOverlap(String),
+    AddrOverflow,
+    RelocOverflow(u32),
+    MisalignedTarget(u32),
+    InvalidReloc(u32),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Sections with the same name and permissions are merged in the order of the
+// objects. Merged sections go one after another from `base` unless `fixed`
+// gives their address.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub base: u32,
+    pub fixed: Vec<(String, u32)>,
+}
+
+struct Group<'a> {
+    name: &'a str,
+    perms: Perms,
+    addr: u32,
+    data: Vec<u8>,
+    zero_size: u32,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout { base: binfile::DEFAULT_ENTRY, fixed: Vec::new() }
+    }
+}
+
+fn align(value: u32) -> Option<u32> {
+    Some(value.checked_add(3)? & !3)
+}
+
+fn fits(value: u32, bits: u32) -> bool {
+    let shift = 32 - bits;
+    ((value as i32) << shift) >> shift == value as i32
+}
+
+fn set_imm(word: u32, imm: u32) -> Option<u32> {
+    let inst = Instruction::decode(word).ok()?;
+    let operands = match inst.operands() {
+        Operands::Rc(ops) => Operands::Rc(Rc { imm: imm as i32, ..ops }),
+        Operands::Rrc(ops) => Operands::Rrc(Rrc { imm: imm as i32, ..ops }),
+        _ => return None,
+    };
+    Some(Instruction::from_parts(inst.opcode(), operands)?.encode())
+}
+
+fn add_symbols<'a>(object: &'a Object, defined: &mut HashSet<&'a str>, undefined: &mut Vec<&'a str>) {
+    for symbol in &object.symbols {
+        match symbol.place {
+            Place::Undefined => undefined.push(&symbol.name),
+            _ if symbol.global => {
+                defined.insert(&symbol.name);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Picks the archive members that define symbols undefined in `objects`, then
+// the members that those need, and so on. Returns archive and member indices
+// in the order the members are picked.
+pub fn select_members(objects: &[Object], archives: &[Archive]) -> Vec<(usize, usize)> {
+    let mut defined = HashSet::new();
+    let mut undefined = Vec::new();
+    for object in objects {
+        add_symbols(object, &mut defined, &mut undefined);
+    }
+
+    let mut selected = Vec::new();
+    loop {
+        undefined.retain(|name| !defined.contains(name));
+        let next = undefined.iter().find_map(|name| {
+            archives.iter().enumerate().find_map(|(i, archive)| {
+                let member = (i, archive.find(name)? as usize);
+                Some(member).filter(|member| !selected.contains(member))
+            })
+        });
+        let Some((i, member)) = next else { break };
+        selected.push((i, member));
+        add_symbols(&archives[i].members[member].object, &mut defined, &mut undefined);
+    }
+    selected
+}
+
+// Combines objects into a program; stops at the first error.
+pub fn link(objects: &[Object], layout: &Layout) -> Result<Program> {
+    macro_rules! err {
+        ($kind:expr, $object:expr) => {
+            Err(Error { kind: $kind, object: $object })
+        };
+    }
+
+    // Merge sections. `places[i][j]` is the group of section `j` of object
+    // `i` and its offset in the group.
+    let mut groups: Vec<Group> = Vec::new();
+    let mut places = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        let mut object_places = Vec::with_capacity(object.sections.len());
+        for section in &object.sections {
+            let index = groups
+                .iter()
+                .position(|group| group.name == section.name && group.perms == section.perms);
+            let index = match index {
+                Some(index) => index,
+                None => {
+                    groups.push(Group {
+                        name: &section.name,
+                        perms: section.perms,
+                        addr: 0,
+                        data: Vec::new(),
+                        zero_size: 0,
+                    });
+                    groups.len() - 1
+                }
+            };
+            let group = &mut groups[index];
+            // Space reserved by the previous section is filled in.
+            let offset = (group.data.len() as u32)
+                .checked_add(group.zero_size)
+                .and_then(align)
+                .ok_or(Error { kind: ErrorKind::AddrOverflow, object: Some(i) })?;
+            group.data.resize(offset as usize, 0);
+            group.data.extend_from_slice(&section.data);
+            group.zero_size = section.zero_size;
+            object_places.push((index, offset));
+        }
+        places.push(object_places);
+    }
+
+    let mut addr = Some(layout.base);
+    let mut ranges = Vec::new();
+    for group in &mut groups {
+        let size = (group.data.len() as u32).checked_add(group.zero_size);
+        let fixed = layout.fixed.iter().find(|(name, _)| name == group.name);
+        // Only the first of the sections with the same name is fixed.
+        let fixed = fixed.filter(|_| !ranges.iter().any(|&(_, _, name)| name == group.name));
+        group.addr = match fixed {
+            Some(&(_, addr)) => addr,
+            None => match addr.and_then(align) {
+                Some(addr) => addr,
+                None => return err!(ErrorKind::AddrOverflow, None),
+            },
+        };
+        let end = match size.and_then(|size| group.addr.checked_add(size)) {
+            Some(end) => end,
+            None => return err!(ErrorKind::AddrOverflow, None),
+        };
+        if fixed.is_none() {
+            addr = Some(end);
+        }
+        if end != group.addr {
+            ranges.push((group.addr, end, group.name));
+        }
+    }
+    ranges.sort_unstable();
+    for pair in ranges.windows(2) {
+        if pair[1].0 < pair[0].1 {
+            return err!(ErrorKind::SectionOverlap(pair[1].2.to_owned()), None);
+        }
+    }
+
+    let group_addrs: Vec<u32> = groups.iter().map(|group| group.addr).collect();
+    let section_addr = |object: usize, section: u32| {
+        let (group, offset) = places[object][section as usize];
+        group_addrs[group] + offset
+    };
+
+    let mut globals: HashMap<&str, u32> = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            let value = match symbol.place {
+                Place::Undefined => continue,
+                Place::Absolute => symbol.value,
+                Place::Section(section) => section_addr(i, section).wrapping_add(symbol.value),
+            };
+            if globals.insert(&symbol.name, value).is_some() {
+                return err!(ErrorKind::DuplicateSymbol(symbol.name.clone()), Some(i));
+            }
+        }
+    }
+
+    let target_addr = |object: usize, target: Target| match target {
+        Target::Absolute => Ok(0),
+        Target::Section(section) => Ok(section_addr(object, section)),
+        Target::Symbol(index) => {
+            let symbol = &objects[object].symbols[index as usize];
+            match symbol.place {
+                Place::Undefined => match globals.get(symbol.name.as_str()) {
+                    Some(&value) => Ok(value),
+                    None => err!(ErrorKind::UndefinedSymbol(symbol.name.clone()), Some(object)),
+                },
+                Place::Absolute => Ok(symbol.value),
+                Place::Section(section) => Ok(section_addr(object, section).wrapping_add(symbol.value)),
+            }
+        }
+    };
+
+    let mut program = Program::new();
+    for (i, object) in objects.iter().enumerate() {
+        for reloc in &object.relocs {
+            let value = target_addr(i, reloc.target)?.wrapping_add(reloc.addend);
+            let (group, offset) = places[i][reloc.section as usize];
+            let group = &mut groups[group];
+            let addr = group.addr + offset + reloc.offset;
+            let index = (offset + reloc.offset) as usize;
+            let word = u32::from_le_bytes(group.data[index..index + 4].try_into().unwrap());
+
+            macro_rules! check {
+                ($cond:expr, $kind:expr) => {
+                    if !$cond {
+                        return err!($kind, Some(i));
+                    }
+                };
+            }
+
+            let imm = match reloc.kind {
+                RelocKind::Abs32 => None,
+                RelocKind::Li => {
+                    check!(fits(value, 20), ErrorKind::RelocOverflow(addr));
+                    Some(value)
+                }
+                RelocKind::Lui => Some(value >> 12),
+                RelocKind::Low => Some(value & 0xFFF),
+                RelocKind::Branch | RelocKind::Jal => {
+                    let offset = value.wrapping_sub(addr).wrapping_sub(4);
+                    check!(offset & 3 == 0, ErrorKind::MisalignedTarget(addr));
+                    let imm = ((offset as i32) >> 2) as u32;
+                    let bits = if reloc.kind == RelocKind::Branch { 16 } else { 20 };
+                    check!(fits(imm, bits), ErrorKind::RelocOverflow(addr));
+                    Some(imm)
+                }
+            };
+            let word = match imm {
+                Some(imm) => match set_imm(word, imm) {
+                    Some(word) => word,
+                    None => return err!(ErrorKind::InvalidReloc(addr), Some(i)),
+                },
+                None => value,
+            };
+            group.data[index..index + 4].copy_from_slice(&u32::to_le_bytes(word));
+        }
+
+        if let Some((target, addend)) = object.entry {
+            if objects[..i].iter().any(|object| object.entry.is_some()) {
+                return err!(ErrorKind::RedefinedEntry, Some(i));
+            }
+            program.entry = target_addr(i, target)?.wrapping_add(addend);
+            program.initial_sp = object.initial_sp;
+        }
+        program.memory_size = cmp::max(program.memory_size, object.memory_size);
+
+        for symbol in &object.symbols {
+            let value = match symbol.place {
+                Place::Undefined => continue,
+                Place::Absolute => symbol.value,
+                Place::Section(section) => section_addr(i, section).wrapping_add(symbol.value),
+            };
+            program.symbols.push(Symbol {
+                name: symbol.name.clone(),
+                value,
+                size: symbol.size,
+                kind: symbol.kind,
+            });
+        }
+
+        let first_file = program.files.len() as u32;
+        program.files.extend(object.files.iter().cloned());
+        for line in &object.lines {
+            program.lines.push(LineEntry {
+                addr: section_addr(i, line.section).wrapping_add(line.offset),
+                size: line.size,
+                file: first_file + line.file,
+                line: line.line,
+            });
+        }
+    }
+    // Without `entry` execution starts at the first "text" section.
+    if objects.iter().all(|object| object.entry.is_none()) {
+        let text = groups.iter().find(|group| group.name == "text");
+        program.entry = text.map_or(layout.base, |group| group.addr);
+    }
+    program.lines.sort_by_key(|entry| entry.addr);
+
+    for group in groups {
+        let segment = Segment {
+            addr: group.addr,
+            data: group.data,
+            zero_size: group.zero_size,
+            perms: group.perms,
+        };
+        if !segment.is_empty() {
+            program.memory_size = cmp::max(program.memory_size, segment.addr + segment.size());
+            program.segments.push(segment);
+        }
+    }
+
+    Ok(program)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol {}", name),
+            ErrorKind::DuplicateSymbol(name) => write!(f, "symbol {} is defined more than once", name),
+            ErrorKind::RedefinedEntry => write!(f, "entry point redefined"),
+            ErrorKind::SectionOverlap(name) => write!(f, "section {} overlaps another section", name),
+            ErrorKind::AddrOverflow => write!(f, "address overflow"),
+            ErrorKind::RelocOverflow(addr) => write!(f, "relocated value does not fit at 0x{:X}", addr),
+            ErrorKind::MisalignedTarget(addr) => write!(f, "misaligned branch target at 0x{:X}", addr),
+            ErrorKind::InvalidReloc(addr) => write!(f, "invalid relocation at 0x{:X}", addr),
+        }
+    }
+}
+
+impl error::Error for ErrorKind {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::testing::NoDevice;
+    use crate::vm::StopReason;
+
+    fn object(source: &str) -> Object {
+        asm::assemble_object("test.asm", source).unwrap()
+    }
+
+    fn run(program: &Program) -> StopReason {
+        program.to_machine().unwrap().run(&mut NoDevice)
+    }
+
+    fn link_err(objects: &[Object], layout: &Layout) -> Error {
+        match link(objects, layout) {
+            Ok(_) => panic!("linked"),
+            Err(err) => err,
+        }
+    }
+
+    const MAIN: &str = "
+    extern  add2, value, ptr, finish
+    mem     0x4000
+    li      %a0, value                  ; Li
+    ld      %a0, %a0, 0
+    lui     %a1, ptr >> 12              ; Lui
+    addi    %a1, %a1, ptr & 0xFFF       ; Low
+    ld      %a1, %a1, 0
+    ld      %a1, %a1, 0                 ; Abs32 of ptr
+    call    add2                        ; Jal
+    bne     %a0, %zero, finish          ; Branch
+    sysfn   %zero, 0
+";
+
+    const LIB: &str = "
+    global  add2, value, ptr, finish
+add2:
+    add     %a0, %a0, %a1
+    addi    %a0, %a0, 2
+    ret
+finish:
+    sysfn   %a0, 0
+
+    section \"data\", \"rw-\"
+value:
+    d32     40
+ptr:
+    d32     value
+";
+
+    #[test]
+    fn relocations() {
+        let objects = [object(MAIN), object(LIB)];
+        let program = link(&objects, &Layout::default()).unwrap();
+        assert!(matches!(run(&program), StopReason::Exited(82)));
+
+        // Text sections are merged in the order of the objects, then data.
+        let segments: Vec<_> = program.segments.iter().map(|s| (s.addr, s.size())).collect();
+        assert_eq!(segments, [(0x1000, 0x24 + 0x10), (0x1034, 8)]);
+        assert_eq!(program.entry, 0x1000);
+        assert_eq!(program.memory_size, 0x4000);
+        let value = program.symbols.iter().find(|symbol| symbol.name == "value").unwrap();
+        assert_eq!(value.value, 0x1034);
+
+        let layout = Layout { base: 0x2000, fixed: vec![("data".to_owned(), 0x100)] };
+        let program = link(&objects, &layout).unwrap();
+        assert_eq!(program.segments[1].addr, 0x100);
+        assert!(matches!(run(&program), StopReason::Exited(82)));
+    }
+
+    #[test]
+    fn symbol_errors() {
+        let err = link_err(&[object(MAIN)], &Layout::default());
+        assert!(matches!(err.kind, ErrorKind::UndefinedSymbol(ref name) if name == "value"));
+        assert_eq!(err.object, Some(0));
+
+        let err = link_err(&[object(MAIN), object(LIB), object(LIB)], &Layout::default());
+        assert!(matches!(err.kind, ErrorKind::DuplicateSymbol(ref name) if name == "add2"));
+        assert_eq!(err.object, Some(2));
+
+        let start = object("entry start\nstart:\n sysfn %zero, 0\n");
+        let err = link_err(&[start.clone(), start], &Layout::default());
+        assert!(matches!(err.kind, ErrorKind::RedefinedEntry));
+    }
+
+    #[test]
+    fn reloc_overflow() {
+        let objects = [object(MAIN), object(LIB)];
+
+        // `li` takes a signed 20-bit value.
+        let layout = Layout { base: 0x1000, fixed: vec![("data".to_owned(), 0x80000)] };
+        let err = link_err(&objects, &layout);
+        assert!(matches!(err.kind, ErrorKind::RelocOverflow(0x1000)));
+        let layout = Layout { base: 0x1000, fixed: vec![("data".to_owned(), 0x7FFF0)] };
+        assert!(link(&objects, &layout).is_ok());
+
+        // Branches reach 2^15 instructions away, `jal` 2^19.
+        let far = object("section \"far\"\n global add2, finish\nadd2:\nfinish:\n ret\n");
+        let data = object("section \"data\"\n global value, ptr\nvalue:\nptr:\n d32 0\n");
+        let layout = Layout { base: 0x1000, fixed: vec![("far".to_owned(), 0x1024 + (1 << 17))] };
+        let err = link_err(&[object(MAIN), far.clone(), data.clone()], &layout);
+        assert!(matches!(err.kind, ErrorKind::RelocOverflow(0x101C)));
+        let layout = Layout { base: 0x1000, fixed: vec![("far".to_owned(), 0x1024 + (1 << 21))] };
+        let err = link_err(&[object(MAIN), far, data], &layout);
+        assert!(matches!(err.kind, ErrorKind::RelocOverflow(0x1018)));
+    }
+
+    #[test]
+    fn misaligned_target() {
+        let lib = "
+    global  add2, value, ptr, finish
+    section \"data\", \"rw-\"
+    d8      0
+value:
+ptr:
+add2:
+finish:
+";
+        let err = link_err(&[object(MAIN), object(lib)], &Layout::default());
+        assert!(matches!(err.kind, ErrorKind::MisalignedTarget(0x1018)));
+    }
+
+    #[test]
+    fn section_overlap() {
+        let layout = Layout { base: 0x1000, fixed: vec![("data".to_owned(), 0x1020)] };
+        let err = link_err(&[object(MAIN), object(LIB)], &layout);
+        assert!(matches!(err.kind, ErrorKind::SectionOverlap(ref name) if name == "data"));
+    }
+
+    fn archive(sources: &[&str]) -> Archive {
+        let mut archive = Archive::new();
+        for (i, source) in sources.iter().enumerate() {
+            archive.add(&format!("{}.o", i), object(source));
+        }
+        archive
+    }
+
+    #[test]
+    fn select_archive_members() {
+        let archives = [
+            archive(&[
+                "global f\n extern g\nf:\n call g\n",
+                "global g\n extern k\ng:\n call k\n",
+                "global unused\nunused:\n ret\n",
+            ]),
+            archive(&["global f\nf:\n ret\n", "global k\nk:\n ret\n"]),
+        ];
+        let main = object("extern f, missing\n call f\n call missing\n");
+
+        // Members needed by picked members are picked too; the first archive
+        // defining a symbol wins.
+        let selected = select_members(&[main], &archives);
+        assert_eq!(selected, [(0, 0), (0, 1), (1, 1)]);
+
+        // Symbols defined by the objects are not taken from archives.
+        let main = object("extern f\n global g, k\n call f\ng:\nk:\n ret\n");
+        assert_eq!(select_members(&[main], &archives), [(0, 0)]);
+
+        let objects = [object("extern f\n call f\n sysfn %zero, 0\n")];
+        assert!(select_members(&objects, &[]).is_empty());
+    }
+}
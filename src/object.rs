@@ -0,0 +1,373 @@
+use std::str;
+
+use crate::binfile::{Error, Result, SymbolKind};
+use crate::vm::Perms;
+
+// An object file is a header followed by section, symbol, relocation, file
+// and line records. Names and section data come after the records; their
+// offsets are relative to the start of the file.
+const FILE_HEADER_SIZE: usize = 12 * 4;
+const SECTION_SIZE: usize = 6 * 4;
+const SYMBOL_SIZE: usize = 8 * 4;
+const RELOC_SIZE: usize = 6 * 4;
+const FILE_NAME_SIZE: usize = 2 * 4;
+const LINE_SIZE: usize = 5 * 4;
+
+const FILE_MAGIC: [u8; 4] = [0x80, b'O', b'B', b'J'];
+
+#[derive(Clone, Default, Debug)]
+pub struct Object {
+    pub memory_size: u32,
+    pub entry: Option<(Target, u32)>,
+    pub initial_sp: Option<u32>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocs: Vec<Reloc>,
+    pub files: Vec<String>,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+    // Zero bytes reserved after `data`.
+    pub zero_size: u32,
+    pub perms: Perms,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Place {
+    // Imported from another object.
+    Undefined,
+    Absolute,
+    Section(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub place: Place,
+    // Offset into the section for symbols defined in a section.
+    pub value: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+    // Visible to other objects.
+    pub global: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Absolute,
+    Section(u32),
+    Symbol(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocKind {
+    // The whole word.
+    Abs32,
+    // `imm` of `li`; the value must fit in 20 bits.
+    Li,
+    // `imm` of `lui` gets the upper 20 bits of the value.
+    Lui,
+    // Offset of a conditional branch from the next instruction.
+    Branch,
+    // Offset of `jal` from the next instruction.
+    Jal,
+    // `imm` of an `Rrc` instruction gets the lower 12 bits of the value.
+    Low,
+}
+
+// The word at `offset` of `section` gets the address of `target` plus
+// `addend`.
+#[derive(Clone, Copy, Debug)]
+pub struct Reloc {
+    pub section: u32,
+    pub offset: u32,
+    pub kind: RelocKind,
+    pub target: Target,
+    pub addend: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Line {
+    pub section: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub file: u32,
+    pub line: u32,
+}
+
+pub fn is_object(data: &[u8]) -> bool {
+    data.starts_with(&FILE_MAGIC)
+}
+
+pub(crate) fn read_u32(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+// Returns `count` records of `size` bytes at `offset`.
+pub(crate) fn records(data: &[u8], offset: usize, count: u32, size: usize) -> Result<(&[u8], usize)> {
+    let end = (count as usize)
+        .checked_mul(size)
+        .and_then(|len| offset.checked_add(len))
+        .filter(|&end| end <= data.len())
+        .ok_or(Error::FileTooShort)?;
+    Ok((&data[offset..end], end))
+}
+
+pub(crate) fn slice(data: &[u8], offset: u32, size: u32) -> Result<&[u8]> {
+    let range = (offset as usize)..(offset as usize).wrapping_add(size as usize);
+    data.get(range).ok_or(Error::InvalidOffsetRange { offset, size })
+}
+
+pub(crate) fn string(data: &[u8], offset: u32, size: u32) -> Result<String> {
+    match str::from_utf8(slice(data, offset, size)?) {
+        Ok(s) => Ok(s.to_owned()),
+        Err(_) => Err(Error::InvalidFormat),
+    }
+}
+
+fn target(kind: u32, index: u32, object: &Object) -> Result<Target> {
+    match kind {
+        0 => Ok(Target::Absolute),
+        1 if (index as usize) < object.sections.len() => Ok(Target::Section(index)),
+        2 if (index as usize) < object.symbols.len() => Ok(Target::Symbol(index)),
+        _ => Err(Error::InvalidFormat),
+    }
+}
+
+fn target_parts(target: Target) -> (u32, u32) {
+    match target {
+        Target::Absolute => (0, 0),
+        Target::Section(index) => (1, index),
+        Target::Symbol(index) => (2, index),
+    }
+}
+
+impl Section {
+    pub fn size(&self) -> u32 {
+        (self.data.len() as u32).wrapping_add(self.zero_size)
+    }
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Default::default()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Object> {
+        if data.len() < FILE_HEADER_SIZE || !is_object(data) {
+            return Err(Error::InvalidFormat);
+        }
+        let version = read_u32(data, 1);
+        if version != 1 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut object = Object::new();
+        object.memory_size = read_u32(data, 2);
+        object.initial_sp = Some(read_u32(data, 6)).filter(|&sp| sp != 0);
+
+        let (sections, end) = records(data, FILE_HEADER_SIZE, read_u32(data, 7), SECTION_SIZE)?;
+        let (symbols, end) = records(data, end, read_u32(data, 8), SYMBOL_SIZE)?;
+        let (relocs, end) = records(data, end, read_u32(data, 9), RELOC_SIZE)?;
+        let (files, end) = records(data, end, read_u32(data, 10), FILE_NAME_SIZE)?;
+        let (lines, _) = records(data, end, read_u32(data, 11), LINE_SIZE)?;
+
+        for record in sections.chunks_exact(SECTION_SIZE) {
+            let flags = read_u32(record, 5);
+            object.sections.push(Section {
+                name: string(data, read_u32(record, 0), read_u32(record, 1))?,
+                data: slice(data, read_u32(record, 2), read_u32(record, 3))?.to_vec(),
+                zero_size: read_u32(record, 4),
+                perms: Perms::from_bits(flags).ok_or(Error::InvalidPerms(flags))?,
+            });
+        }
+
+        for record in symbols.chunks_exact(SYMBOL_SIZE) {
+            let place = match (read_u32(record, 2), read_u32(record, 3)) {
+                (0, _) => Place::Undefined,
+                (1, _) => Place::Absolute,
+                (2, index) if (index as usize) < object.sections.len() => Place::Section(index),
+                _ => return Err(Error::InvalidFormat),
+            };
+            let kind = match read_u32(record, 6) {
+                0 => SymbolKind::Code,
+                1 => SymbolKind::Data,
+                2 => SymbolKind::Constant,
+                _ => return Err(Error::InvalidFormat),
+            };
+            object.symbols.push(Symbol {
+                name: string(data, read_u32(record, 0), read_u32(record, 1))?,
+                place,
+                value: read_u32(record, 4),
+                size: read_u32(record, 5),
+                kind,
+                global: read_u32(record, 7) != 0,
+            });
+        }
+
+        for record in relocs.chunks_exact(RELOC_SIZE) {
+            let section = read_u32(record, 0);
+            let offset = read_u32(record, 1);
+            let size = match object.sections.get(section as usize) {
+                Some(section) => section.data.len(),
+                None => return Err(Error::InvalidFormat),
+            };
+            if (offset as usize).checked_add(4).is_none_or(|end| end > size) {
+                return Err(Error::InvalidFormat);
+            }
+            let kind = match read_u32(record, 2) {
+                0 => RelocKind::Abs32,
+                1 => RelocKind::Li,
+                2 => RelocKind::Lui,
+                3 => RelocKind::Branch,
+                4 => RelocKind::Jal,
+                5 => RelocKind::Low,
+                _ => return Err(Error::InvalidFormat),
+            };
+            let target = target(read_u32(record, 3), read_u32(record, 4), &object)?;
+            object.relocs.push(Reloc { section, offset, kind, target, addend: read_u32(record, 5) });
+        }
+
+        for record in files.chunks_exact(FILE_NAME_SIZE) {
+            object.files.push(string(data, read_u32(record, 0), read_u32(record, 1))?);
+        }
+
+        for record in lines.chunks_exact(LINE_SIZE) {
+            let line = Line {
+                section: read_u32(record, 0),
+                offset: read_u32(record, 1),
+                size: read_u32(record, 2),
+                file: read_u32(record, 3),
+                line: read_u32(record, 4),
+            };
+            if line.section as usize >= object.sections.len() || line.file as usize >= object.files.len() {
+                return Err(Error::InvalidFormat);
+            }
+            object.lines.push(line);
+        }
+
+        object.entry = match read_u32(data, 3) {
+            0 => None,
+            kind => Some((target(kind - 1, read_u32(data, 4), &object)?, read_u32(data, 5))),
+        };
+
+        Ok(object)
+    }
+
+    #[allow(clippy::inconsistent_digit_grouping)]
+    pub fn serialize(&self, buffer: &mut Vec<u8>) -> Result<()> {
+        macro_rules! try_u32 {
+            ($e:expr) => {
+                u32::try_from($e).map_err(|_| Error::FileTooLarge)
+            };
+        }
+
+        let records_size = self.sections.len() * SECTION_SIZE
+            + self.symbols.len() * SYMBOL_SIZE
+            + self.relocs.len() * RELOC_SIZE
+            + self.files.len() * FILE_NAME_SIZE
+            + self.lines.len() * LINE_SIZE;
+        let start = buffer.len();
+        let mut records = Vec::with_capacity(records_size);
+        let mut data = Vec::new();
+        let data_offset = FILE_HEADER_SIZE + records_size;
+
+        // Appends `bytes` to the data area and returns its offset and size.
+        let mut push_data = |bytes: &[u8]| -> Result<[u32; 2]> {
+            let offset = try_u32!(data_offset + data.len())?;
+            let size = try_u32!(bytes.len())?;
+            data.extend_from_slice(bytes);
+            Ok([offset, size])
+        };
+
+        let mut push_record = |values: &[u32]| {
+            for value in values {
+                records.extend_from_slice(&u32::to_le_bytes(*value));
+            }
+        };
+
+        for section in &self.sections {
+            let [name_offset, name_size] = push_data(section.name.as_bytes())?;
+            let [data_offset, data_size] = push_data(&section.data)?;
+            let flags = section.perms.bits();
+            push_record(&[name_offset, name_size, data_offset, data_size, section.zero_size, flags]);
+        }
+
+        for symbol in &self.symbols {
+            let [name_offset, name_size] = push_data(symbol.name.as_bytes())?;
+            let (place, section) = match symbol.place {
+                Place::Undefined => (0, 0),
+                Place::Absolute => (1, 0),
+                Place::Section(index) => (2, index),
+            };
+            let kind = match symbol.kind {
+                SymbolKind::Code     => 0,
+                SymbolKind::Data     => 1,
+                SymbolKind::Constant => 2,
+            };
+            let global = symbol.global as u32;
+            push_record(&[name_offset, name_size, place, section, symbol.value, symbol.size, kind, global]);
+        }
+
+        for reloc in &self.relocs {
+            let kind = match reloc.kind {
+                RelocKind::Abs32  => 0,
+                RelocKind::Li     => 1,
+                RelocKind::Lui    => 2,
+                RelocKind::Branch => 3,
+                RelocKind::Jal    => 4,
+                RelocKind::Low    => 5,
+            };
+            let (target_kind, target_index) = target_parts(reloc.target);
+            push_record(&[reloc.section, reloc.offset, kind, target_kind, target_index, reloc.addend]);
+        }
+
+        for file in &self.files {
+            push_record(&push_data(file.as_bytes())?);
+        }
+
+        for line in &self.lines {
+            push_record(&[line.section, line.offset, line.size, line.file, line.line]);
+        }
+
+        try_u32!(data_offset + data.len())?;
+
+        let (entry_kind, entry_index, entry_addend) = match self.entry {
+            Some((target, addend)) => {
+                let (kind, index) = target_parts(target);
+                (kind + 1, index, addend)
+            }
+            None => (0, 0, 0),
+        };
+
+        let mut header = [0_u8; FILE_HEADER_SIZE];
+        header[0_..4_].copy_from_slice(&FILE_MAGIC);
+        header[4_..8_].copy_from_slice(&u32::to_le_bytes(1));
+        header[8_..12].copy_from_slice(&u32::to_le_bytes(self.memory_size));
+        header[12..16].copy_from_slice(&u32::to_le_bytes(entry_kind));
+        header[16..20].copy_from_slice(&u32::to_le_bytes(entry_index));
+        header[20..24].copy_from_slice(&u32::to_le_bytes(entry_addend));
+        header[24..28].copy_from_slice(&u32::to_le_bytes(self.initial_sp.unwrap_or(0)));
+        header[28..32].copy_from_slice(&u32::to_le_bytes(try_u32!(self.sections.len())?));
+        header[32..36].copy_from_slice(&u32::to_le_bytes(try_u32!(self.symbols.len())?));
+        header[36..40].copy_from_slice(&u32::to_le_bytes(try_u32!(self.relocs.len())?));
+        header[40..44].copy_from_slice(&u32::to_le_bytes(try_u32!(self.files.len())?));
+        header[44..48].copy_from_slice(&u32::to_le_bytes(try_u32!(self.lines.len())?));
+        buffer.extend_from_slice(&header);
+        buffer.extend_from_slice(&records);
+        buffer.extend_from_slice(&data);
+
+        debug_assert_eq!(buffer.len() - start, data_offset + data.len());
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.serialize(&mut buffer)?;
+        Ok(buffer)
+    }
+}
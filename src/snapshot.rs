@@ -0,0 +1,353 @@
+use smallvec::SmallVec;
+
+use crate::binfile::{self, Error, RawSegment, Result};
+use crate::vm::{Machine, MemoryMap, Perms, Region, State, Sysfn};
+
+const FILE_HEADER_SIZE: usize = 25 * 4;
+const SEGMENT_HEADER_SIZE: usize = 3 * 4;
+const REGION_SIZE: usize = 3 * 4;
+
+// Memory is saved in blocks of this size; blocks filled with zeros are skipped.
+const BLOCK_SIZE: usize = 256;
+
+const FILE_MAGIC: [u8; 4] = [0x80, b'S', b'N', b'P'];
+
+pub struct File<'a> {
+    data: &'a [u8],
+    state: State,
+    memory_size: u32,
+    segment_count: u32,
+    device: &'a [u8],
+    memory_map: MemoryMap,
+}
+
+fn load_u32(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+pub fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(&FILE_MAGIC)
+}
+
+impl<'a> File<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Result<File<'a>> {
+        if data.len() < FILE_HEADER_SIZE || !is_snapshot(data) {
+            return Err(Error::InvalidFormat);
+        }
+
+        let version = load_u32(data, 1);
+        if version != 1 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut state = State::with_pc(load_u32(data, 2));
+        for (i, reg) in state.regs.iter_mut().enumerate() {
+            *reg = load_u32(data, 3 + i);
+        }
+        let memory_size   = load_u32(data, 19);
+        let segment_count = load_u32(data, 20);
+        let device_offset = load_u32(data, 21);
+        let device_size   = load_u32(data, 22);
+
+        let range = (device_offset as usize)..(device_offset as usize).wrapping_add(device_size as usize);
+        let device = match data.get(range) {
+            Some(device) => device,
+            None => {
+                return Err(Error::InvalidOffsetRange { offset: device_offset, size: device_size });
+            }
+        };
+
+        let segments_size = (segment_count as usize).checked_mul(SEGMENT_HEADER_SIZE);
+        if segments_size.is_none_or(|size| data.len() - FILE_HEADER_SIZE < size) {
+            return Err(Error::FileTooShort);
+        }
+
+        let regions_offset = FILE_HEADER_SIZE + segments_size.unwrap();
+        let region_count = load_u32(data, 24) as usize;
+        let regions = match region_count.checked_mul(REGION_SIZE) {
+            Some(size) if data.len() - regions_offset >= size => &data[regions_offset..regions_offset + size],
+            _ => return Err(Error::FileTooShort),
+        };
+        let perms = |bits| Perms::from_bits(bits).ok_or(Error::InvalidFormat);
+        let regions: Result<Vec<Region>> = regions
+            .chunks_exact(REGION_SIZE)
+            .map(|region| {
+                Ok(Region {
+                    addr:  load_u32(region, 0),
+                    size:  load_u32(region, 1),
+                    perms: perms(load_u32(region, 2))?,
+                })
+            })
+            .collect();
+        let memory_map = MemoryMap::new(regions?, perms(load_u32(data, 23))?);
+
+        Ok(File { data, state, memory_size, segment_count, device, memory_map })
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn memory_size(&self) -> u32 {
+        self.memory_size
+    }
+
+    pub fn segment_count(&self) -> u32 {
+        self.segment_count
+    }
+
+    pub fn device(&self) -> &'a [u8] {
+        self.device
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn raw_segments(&self) -> impl ExactSizeIterator<Item = RawSegment> + 'a {
+        let headers = &self.data[FILE_HEADER_SIZE..];
+        (0..self.segment_count as usize).map(move |i| {
+            let header = &headers[i * SEGMENT_HEADER_SIZE..];
+            RawSegment {
+                offset: load_u32(header, 0),
+                addr:   load_u32(header, 1),
+                size:   load_u32(header, 2),
+                perms:  Perms::ALL,
+                zero_fill: false,
+            }
+        })
+    }
+
+    pub fn to_memory(&self) -> Result<Vec<u8>> {
+        let mut memory = vec![0_u8; self.memory_size as usize];
+        for segment in self.raw_segments() {
+            let offset = segment.offset as usize;
+            let range = offset..offset.wrapping_add(segment.size as usize);
+            let data = match self.data.get(range) {
+                Some(data) => data,
+                None => {
+                    return Err(Error::InvalidOffsetRange { offset: segment.offset, size: segment.size });
+                }
+            };
+
+            let addr = segment.addr as usize;
+            match memory.get_mut(addr..addr.wrapping_add(data.len())) {
+                Some(dest) => dest.copy_from_slice(data),
+                None => {
+                    return Err(Error::InvalidAddrRange { addr: segment.addr, size: segment.size });
+                }
+            }
+        }
+        Ok(memory)
+    }
+}
+
+// Loads either a snapshot or an executable file; device state from a snapshot
+// is restored into `sysfn`.
+pub fn load(data: &[u8], sysfn: &mut dyn Sysfn) -> Result<Machine> {
+    if !is_snapshot(data) {
+        return binfile::load(data);
+    }
+    let file = File::from_bytes(data)?;
+    if !sysfn.restore(file.device()) {
+        return Err(Error::InvalidFormat);
+    }
+    let mut machine = Machine::with_state(file.state(), file.to_memory()?);
+    machine.set_memory_map(file.memory_map().clone());
+    Ok(machine)
+}
+
+#[allow(clippy::inconsistent_digit_grouping)]
+pub fn serialize(
+    state: &State,
+    memory: &[u8],
+    memory_map: &MemoryMap,
+    device: &[u8],
+    buffer: &mut Vec<u8>,
+) -> Result<()> {
+    macro_rules! try_u32 {
+        ($e:expr) => {
+            u32::try_from($e).map_err(|_| Error::FileTooLarge)
+        };
+    }
+
+    let mut segments: SmallVec<[(usize, usize); 16]> = SmallVec::new();
+    for (i, block) in memory.chunks(BLOCK_SIZE).enumerate() {
+        if block.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let start = i * BLOCK_SIZE;
+        match segments.last_mut() {
+            Some((addr, size)) if *addr + *size == start => *size += block.len(),
+            _ => segments.push((start, block.len())),
+        }
+    }
+
+    let memory_size = try_u32!(memory.len())?;
+    let segment_count = try_u32!(segments.len())?;
+    let regions = memory_map.regions();
+    let region_count = try_u32!(regions.len())?;
+    let headers_size = segments
+        .len()
+        .checked_mul(SEGMENT_HEADER_SIZE)
+        .zip(regions.len().checked_mul(REGION_SIZE))
+        .and_then(|(a, b)| a.checked_add(b)?.checked_add(FILE_HEADER_SIZE));
+    let mut offset = match headers_size {
+        Some(size) => try_u32!(size)?,
+        None => return Err(Error::FileTooLarge),
+    };
+    let data_size: usize = segments.iter().map(|&(_, size)| size).sum();
+    let device_offset = offset.checked_add(try_u32!(data_size)?).ok_or(Error::FileTooLarge)?;
+    let device_size = try_u32!(device.len())?;
+    device_offset.checked_add(device_size).ok_or(Error::FileTooLarge)?;
+
+    {
+        let mut header = [0_u8; FILE_HEADER_SIZE];
+        header[0_..4_].copy_from_slice(&FILE_MAGIC);
+        header[4_..8_].copy_from_slice(&u32::to_le_bytes(1));
+        header[8_..12].copy_from_slice(&u32::to_le_bytes(state.pc));
+        for (i, reg) in state.regs.iter().enumerate() {
+            header[12 + i * 4..16 + i * 4].copy_from_slice(&u32::to_le_bytes(*reg));
+        }
+        header[76..80].copy_from_slice(&u32::to_le_bytes(memory_size));
+        header[80..84].copy_from_slice(&u32::to_le_bytes(segment_count));
+        header[84..88].copy_from_slice(&u32::to_le_bytes(device_offset));
+        header[88..92].copy_from_slice(&u32::to_le_bytes(device_size));
+        header[92..96].copy_from_slice(&u32::to_le_bytes(memory_map.default_perms().bits()));
+        header[96..100].copy_from_slice(&u32::to_le_bytes(region_count));
+        buffer.extend_from_slice(&header);
+    }
+
+    for &(addr, size) in &segments {
+        let mut header = [0_u8; SEGMENT_HEADER_SIZE];
+        header[0_..4_].copy_from_slice(&u32::to_le_bytes(offset));
+        header[4_..8_].copy_from_slice(&u32::to_le_bytes(addr as u32));
+        header[8_..12].copy_from_slice(&u32::to_le_bytes(size as u32));
+        buffer.extend_from_slice(&header);
+        offset += size as u32;
+    }
+
+    for region in regions {
+        let mut record = [0_u8; REGION_SIZE];
+        record[0_..4_].copy_from_slice(&u32::to_le_bytes(region.addr));
+        record[4_..8_].copy_from_slice(&u32::to_le_bytes(region.size));
+        record[8_..12].copy_from_slice(&u32::to_le_bytes(region.perms.bits()));
+        buffer.extend_from_slice(&record);
+    }
+
+    for &(addr, size) in &segments {
+        buffer.extend_from_slice(&memory[addr..addr + size]);
+    }
+    buffer.extend_from_slice(device);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, NoDevice};
+    use crate::vm::StopReason;
+
+    // Counts the values it reads; the count is the device state.
+    struct Counter(u32);
+
+    impl Sysfn for Counter {
+        fn read(&mut self) -> u32 {
+            self.0 += 1;
+            self.0
+        }
+
+        fn write(&mut self, _value: u32) {}
+
+        fn save(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn restore(&mut self, data: &[u8]) -> bool {
+            match data.try_into() {
+                Ok(bytes) => {
+                    self.0 = u32::from_le_bytes(bytes);
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+    }
+
+    // Reads from the device into %a0 and %a1 and exits with their sum.
+    fn machine() -> Machine {
+        testing::load_code(&[
+            0x0000_1383, // sysfn   %a0, 1
+            0x0000_1483, // sysfn   %a1, 1
+            0x0004_33A8, // add     %a0, %a0, %a1
+            0x0000_0383, // sysfn   %a0, 0
+        ])
+    }
+
+    fn save(machine: &Machine, device: &dyn Sysfn) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let state = &machine.state;
+        serialize(state, &machine.memory, machine.memory_map(), &device.save(), &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut machine = machine();
+        let mut device = Counter(10);
+        machine.run_for(1, &mut device);
+        machine.memory[0x1FFF] = 0x5A;
+        let data = save(&machine, &device);
+
+        let mut restored_device = Counter(0);
+        let mut restored = load(&data, &mut restored_device).unwrap();
+        assert_eq!(restored.state.pc, machine.state.pc);
+        assert_eq!(restored.state.regs, machine.state.regs);
+        assert_eq!(restored.memory, machine.memory);
+        assert_eq!(restored_device.0, 11);
+
+        assert!(matches!(machine.run(&mut device), StopReason::Exited(23)));
+        assert!(matches!(restored.run(&mut restored_device), StopReason::Exited(23)));
+    }
+
+    #[test]
+    fn zero_blocks_are_skipped() {
+        let mut machine = Machine::new(vec![0; 16 * BLOCK_SIZE]);
+        machine.memory[3 * BLOCK_SIZE] = 1;
+        machine.memory[4 * BLOCK_SIZE + 1] = 2;
+        machine.memory[9 * BLOCK_SIZE] = 3;
+        let data = save(&machine, &Counter(0));
+
+        let file = File::from_bytes(&data).unwrap();
+        let segments: Vec<_> = file.raw_segments().map(|s| (s.addr as usize, s.size as usize)).collect();
+        assert_eq!(segments, [(3 * BLOCK_SIZE, 2 * BLOCK_SIZE), (9 * BLOCK_SIZE, BLOCK_SIZE)]);
+        assert_eq!(file.to_memory().unwrap(), machine.memory);
+    }
+
+    #[test]
+    fn memory_map_is_kept() {
+        let mut machine = machine();
+        let regions = [
+            Region { addr: 0x1000, size: 0x10, perms: Perms::READ | Perms::EXEC },
+            Region { addr: 0x1800, size: 0x800, perms: Perms::READ },
+        ];
+        machine.set_memory_map(MemoryMap::new(regions, Perms::RW));
+        let data = save(&machine, &Counter(0));
+
+        let restored = load(&data, &mut Counter(0)).unwrap();
+        let map = restored.memory_map();
+        assert_eq!(map.default_perms(), Perms::RW);
+        let regions: Vec<_> = map.regions().iter().map(|r| (r.addr, r.size, r.perms)).collect();
+        assert_eq!(regions, [(0x1000, 0x10, Perms::READ | Perms::EXEC), (0x1800, 0x800, Perms::READ)]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut data = save(&machine(), &Counter(0));
+        assert!(matches!(load(&data, &mut NoDevice), Err(Error::InvalidFormat)));
+        assert!(matches!(File::from_bytes(&data[..FILE_HEADER_SIZE - 1]), Err(Error::InvalidFormat)));
+        data[4] = 3;
+        assert!(matches!(File::from_bytes(&data), Err(Error::UnsupportedVersion(3))));
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+
+use crate::binfile::{self, SymbolKind};
+
+#[derive(Clone, Debug)]
+struct Entry {
+    name: String,
+    addr: u32,
+    size: u32,
+}
+
+// Maps addresses to code and data symbols of an executable file.
+#[derive(Clone, Default, Debug)]
+pub struct SymbolMap {
+    // Sorted by address, then by size.
+    entries: Vec<Entry>,
+}
+
+// Formats an address as `name+0x1C`, or as plain hex if no symbol covers it.
+#[derive(Clone, Copy)]
+pub struct Symbolized<'a> {
+    map: &'a SymbolMap,
+    addr: u32,
+}
+
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        Default::default()
+    }
+
+    pub fn from_symbols(symbols: &[binfile::Symbol]) -> SymbolMap {
+        let mut entries: Vec<_> = symbols
+            .iter()
+            .filter(|symbol| symbol.kind != SymbolKind::Constant)
+            .map(|symbol| Entry { name: symbol.name.to_owned(), addr: symbol.value, size: symbol.size })
+            .collect();
+        entries.sort_by_key(|entry| (entry.addr, entry.size));
+        SymbolMap { entries }
+    }
+
+    pub fn from_file(file: &binfile::File) -> binfile::Result<SymbolMap> {
+        Ok(SymbolMap::from_symbols(&file.symbols()?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Returns the symbol containing `addr` and the offset into it. Symbols of
+    // zero size only match their own address.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let index = self.entries.partition_point(|entry| entry.addr <= addr);
+        let entry = &self.entries[index.checked_sub(1)?];
+        let offset = addr - entry.addr;
+        if offset < entry.size || offset == 0 {
+            Some((&entry.name, offset))
+        } else {
+            None
+        }
+    }
+
+    // Names of the symbols that start exactly at `addr`.
+    pub fn names_at(&self, addr: u32) -> impl Iterator<Item = &str> {
+        let start = self.entries.partition_point(|entry| entry.addr < addr);
+        self.entries[start..]
+            .iter()
+            .take_while(move |entry| entry.addr == addr)
+            .map(|entry| entry.name.as_str())
+    }
+
+    pub fn symbolize(&self, addr: u32) -> Symbolized<'_> {
+        Symbolized { map: self, addr }
+    }
+}
+
+impl fmt::Display for Symbolized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.map.lookup(self.addr) {
+            Some((name, 0)) => f.write_str(name),
+            Some((name, offset)) => write!(f, "{}+0x{:X}", name, offset),
+            None => write!(f, "0x{:X}", self.addr),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+// Fixtures shared by the unit tests.
+
+use crate::asm;
+use crate::vm::{Machine, Sysfn};
+
+// Reads zeros and drops the output.
+pub struct NoDevice;
+
+impl Sysfn for NoDevice {
+    fn read(&mut self) -> u32 {
+        0
+    }
+
+    fn write(&mut self, _value: u32) {}
+}
+
+// Places `code` at 0x1000 in 0x2000 bytes of memory.
+pub fn load_code(code: &[u32]) -> Machine {
+    let mut memory = vec![0; 0x2000];
+    for (i, word) in code.iter().enumerate() {
+        memory[0x1000 + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+    }
+    Machine::new(memory)
+}
+
+pub fn load(source: &str) -> Machine {
+    asm::assemble(source).unwrap().to_machine().unwrap()
+}
@@ -1,12 +1,49 @@
+use std::collections::BTreeSet;
 use std::error;
-use std::fmt;
+use std::fmt::{self, Write};
+use std::io;
 use std::mem::{size_of, size_of_val};
+use std::ops::{BitAnd, BitOr};
 
+use crate::isa::{decode_rc, decode_rrc, decode_rrr, decode_rrrr, reserved_bits, Format};
 use crate::opcode;
 
 pub trait Sysfn {
     fn read(&mut self) -> u32;
     fn write(&mut self, value: u32);
+
+    // Device state stored in snapshots. Returns false if `data` cannot be
+    // restored.
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
+}
+
+// Reads bytes from `input` (!0 at the end of it) and writes the low byte of
+// the value to `output`.
+pub struct Console<R, W> {
+    pub input: R,
+    pub output: W,
+}
+
+impl<R: io::Read, W: io::Write> Sysfn for Console<R, W> {
+    fn read(&mut self) -> u32 {
+        self.output.flush().unwrap();
+
+        let mut buf = [0_u8];
+        match self.input.read_exact(&mut buf) {
+            Ok(()) => buf[0] as u32,
+            Err(_) => !0,
+        }
+    }
+
+    fn write(&mut self, value: u32) {
+        self.output.write_all(&[value as u8]).unwrap();
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -15,6 +52,9 @@ pub enum Error {
     UnknownInst(u32),
     InvalidAddr(u32),
     InvalidPc(u32),
+    ExecProtected(u32),
+    ReadProtected(u32),
+    WriteProtected(u32),
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -39,23 +79,534 @@ impl Default for State {
     }
 }
 
+#[derive(Clone, Debug)]
+pub enum StopReason {
+    Exited(u32),
+    Faulted(Error),
+    // `step` executed its instruction.
+    Stepped,
+    BudgetExhausted,
+    OutOfFuel,
+    Breakpoint(u32),
+    Watchpoint(WatchHit),
+    // The predicate of `run_until` matched.
+    Condition,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemAccess {
+    pub addr: u32,
+    pub width: u32,
+    pub write: bool,
+    pub old: u32,
+    pub new: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub id: usize,
+    pub pc: u32,
+    pub access: MemAccess,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access: &MemAccess) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => !access.write,
+            WatchKind::Write => access.write,
+            WatchKind::Access => true,
+        };
+        let start = access.addr as u64;
+        let end = start + access.width as u64;
+        let watch_start = self.addr as u64;
+        let watch_end = watch_start + self.len as u64;
+        kind_matches && start < watch_end && watch_start < end
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Costs {
+    pub alu: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub load: u64,
+    pub store: u64,
+    pub branch: u64,
+    pub jump: u64,
+    pub sysfn: u64,
+}
+
+impl Costs {
+    pub const UNIFORM: Costs = Costs {
+        alu: 1,
+        mul: 1,
+        div: 1,
+        load: 1,
+        store: 1,
+        branch: 1,
+        jump: 1,
+        sysfn: 1,
+    };
+
+    pub fn of(&self, inst: u32) -> u64 {
+        match inst & 0xFF {
+            opcode::STU8 | opcode::STU16 | opcode::ST => self.store,
+            opcode::LDS8 | opcode::LDU8 | opcode::LDS16 | opcode::LDU16 | opcode::LD => self.load,
+            opcode::BEQ | opcode::BNE | opcode::BLT | opcode::BGE | opcode::BLTU | opcode::BGEU => {
+                self.branch
+            }
+            opcode::JAL | opcode::JALR => self.jump,
+            opcode::MULI | opcode::MUL | opcode::MULW | opcode::MULWU => self.mul,
+            opcode::DIV | opcode::DIVU => self.div,
+            opcode::SYSFN => self.sysfn,
+            _ => self.alu,
+        }
+    }
+}
+
+impl Default for Costs {
+    fn default() -> Self {
+        Costs::UNIFORM
+    }
+}
+
+// Access rights of a memory region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Perms(u8);
+
+impl Perms {
+    pub const NONE:  Perms = Perms(0);
+    pub const READ:  Perms = Perms(1);
+    pub const WRITE: Perms = Perms(2);
+    pub const EXEC:  Perms = Perms(4);
+    pub const RW:    Perms = Perms(3);
+    pub const ALL:   Perms = Perms(7);
+
+    pub fn from_bits(bits: u32) -> Option<Perms> {
+        if bits & !Perms::ALL.bits() == 0 {
+            Some(Perms(bits as u8))
+        } else {
+            None
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn contains(self, other: Perms) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // Parses the `rwx` notation; `-` may stand for a missing right.
+    pub fn parse(s: &[u8]) -> Option<Perms> {
+        let mut perms = Perms::NONE;
+        for &c in s {
+            perms = perms | match c {
+                b'r' => Perms::READ,
+                b'w' => Perms::WRITE,
+                b'x' => Perms::EXEC,
+                b'-' => Perms::NONE,
+                _ => return None,
+            };
+        }
+        Some(perms)
+    }
+}
+
+impl BitOr for Perms {
+    type Output = Perms;
+
+    fn bitor(self, rhs: Perms) -> Perms {
+        Perms(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Perms {
+    type Output = Perms;
+
+    fn bitand(self, rhs: Perms) -> Perms {
+        Perms(self.0 & rhs.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub addr: u32,
+    pub size: u32,
+    pub perms: Perms,
+}
+
+// Access rights of the whole memory: memory outside of the regions gets the
+// default rights.
+#[derive(Clone, Debug)]
+pub struct MemoryMap {
+    // Sorted by address, never overlap.
+    regions: Vec<Region>,
+    default: Perms,
+}
+
+impl MemoryMap {
+    // Everything is readable, writable and executable.
+    pub fn flat() -> MemoryMap {
+        MemoryMap { regions: Vec::new(), default: Perms::ALL }
+    }
+
+    // Later regions override the overlapping parts of earlier ones.
+    pub fn new<I>(regions: I, default: Perms) -> MemoryMap
+    where
+        I: IntoIterator<Item = Region>,
+    {
+        let mut map = MemoryMap { regions: Vec::new(), default };
+        for region in regions {
+            map.insert(region);
+        }
+        map
+    }
+
+    fn insert(&mut self, region: Region) {
+        let start = region.addr as u64;
+        let end = start + region.size as u64;
+        if start == end {
+            return;
+        }
+
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for old in self.regions.drain(..) {
+            let old_start = old.addr as u64;
+            let old_end = old_start + old.size as u64;
+            if old_start < start {
+                let size = (old_end.min(start) - old_start) as u32;
+                regions.push(Region { size, ..old });
+            }
+            if old_end > end {
+                let addr = old_start.max(end);
+                regions.push(Region { addr: addr as u32, size: (old_end - addr) as u32, ..old });
+            }
+        }
+        regions.push(region);
+        regions.sort_by_key(|region| region.addr);
+        self.regions = regions;
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn default_perms(&self) -> Perms {
+        self.default
+    }
+
+    // Rights that all of the bytes `addr..addr + size` have.
+    #[inline(always)]
+    pub fn perms(&self, addr: u32, size: u32) -> Perms {
+        if self.regions.is_empty() {
+            return self.default;
+        }
+        let end = addr as u64 + size as u64;
+        let mut addr = addr as u64;
+        let mut perms = Perms::ALL;
+        while addr < end {
+            let index = self.regions.partition_point(|region| region.addr as u64 <= addr);
+            let region = index.checked_sub(1).map(|index| &self.regions[index]);
+            match region {
+                Some(region) if addr < region.addr as u64 + region.size as u64 => {
+                    perms = perms & region.perms;
+                    addr = region.addr as u64 + region.size as u64;
+                }
+                _ => {
+                    perms = perms & self.default;
+                    addr = self.regions.get(index).map_or(end, |region| region.addr as u64);
+                }
+            }
+        }
+        perms
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap::flat()
+    }
+}
+
+pub struct Machine {
+    pub state: State,
+    pub memory: Vec<u8>,
+    memory_map: MemoryMap,
+    fuel: Option<u64>,
+    costs: Costs,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Option<Watchpoint>>,
+    // The breakpoint the machine has stopped at; it is not hit again when
+    // execution resumes from it.
+    resume_from: Option<u32>,
+}
+
+impl Machine {
+    pub fn new(memory: Vec<u8>) -> Machine {
+        Machine::with_state(State::new(), memory)
+    }
+
+    pub fn with_state(state: State, memory: Vec<u8>) -> Machine {
+        Machine {
+            state,
+            memory,
+            memory_map: MemoryMap::flat(),
+            fuel: None,
+            costs: Costs::UNIFORM,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            resume_from: None,
+        }
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) {
+        self.memory_map = memory_map;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, amount: u64) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_add(amount);
+        }
+    }
+
+    pub fn costs(&self) -> &Costs {
+        &self.costs
+    }
+
+    pub fn set_costs(&mut self, costs: Costs) {
+        self.costs = costs;
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u32) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        if let Some(id) = self.watchpoints.iter().position(Option::is_none) {
+            self.watchpoints[id] = Some(watchpoint);
+            id
+        } else {
+            self.watchpoints.push(Some(watchpoint));
+            self.watchpoints.len() - 1
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.get_mut(id)?.take()
+    }
+
+    pub fn watchpoint(&self, id: usize) -> Option<&Watchpoint> {
+        self.watchpoints.get(id)?.as_ref()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> + '_ {
+        self.watchpoints.iter().enumerate().filter_map(|(id, w)| Some((id, w.as_ref()?)))
+    }
+
+    // Executes one instruction, even if there is a breakpoint at `pc`, and
+    // stops at the breakpoint the instruction leads to.
+    pub fn step(&mut self, sysfn: &mut dyn Sysfn) -> StopReason {
+        self.resume_from = None;
+        if let Some(reason) = self.execute(sysfn) {
+            return reason;
+        }
+        self.hit_breakpoint(None).unwrap_or(StopReason::Stepped)
+    }
+
+    pub fn run_for(&mut self, count: u64, sysfn: &mut dyn Sysfn) -> StopReason {
+        let mut resume_from = self.resume_from.take();
+        for _ in 0..count {
+            if let Some(reason) = self.hit_breakpoint(resume_from.take()) {
+                return reason;
+            }
+            if let Some(reason) = self.execute(sysfn) {
+                return reason;
+            }
+        }
+        StopReason::BudgetExhausted
+    }
+
+    pub fn run_until<P>(&mut self, mut predicate: P, sysfn: &mut dyn Sysfn) -> StopReason
+    where
+        P: FnMut(&State) -> bool,
+    {
+        let mut resume_from = self.resume_from.take();
+        loop {
+            if predicate(&self.state) {
+                return StopReason::Condition;
+            }
+            if let Some(reason) = self.hit_breakpoint(resume_from.take()) {
+                return reason;
+            }
+            if let Some(reason) = self.execute(sysfn) {
+                return reason;
+            }
+        }
+    }
+
+    pub fn run(&mut self, sysfn: &mut dyn Sysfn) -> StopReason {
+        if self.fuel.is_some() || !self.breakpoints.is_empty() || !self.watchpoints.is_empty() {
+            return self.run_until(|_| false, sysfn);
+        }
+        match run_with_map(&mut self.state, &mut self.memory, &self.memory_map, sysfn) {
+            Ok(status) => StopReason::Exited(status),
+            Err(err) => StopReason::Faulted(err),
+        }
+    }
+
+    // Executes one instruction and reports its memory accesses to `on_access`.
+    // Breakpoints are not checked.
+    pub fn step_with<F>(&mut self, sysfn: &mut dyn Sysfn, on_access: F) -> StopReason
+    where
+        F: FnMut(MemAccess),
+    {
+        self.execute_with(sysfn, on_access).unwrap_or(StopReason::Stepped)
+    }
+
+    // Execution resuming from the breakpoint at `resume_from` does not stop
+    // there again.
+    fn hit_breakpoint(&mut self, resume_from: Option<u32>) -> Option<StopReason> {
+        let pc = self.state.pc;
+        if resume_from == Some(pc) || !self.breakpoints.contains(&pc) {
+            return None;
+        }
+        self.resume_from = Some(pc);
+        Some(StopReason::Breakpoint(pc))
+    }
+
+    fn execute(&mut self, sysfn: &mut dyn Sysfn) -> Option<StopReason> {
+        self.execute_with(sysfn, |_| {})
+    }
+
+    #[inline(always)]
+    fn execute_with<F>(&mut self, sysfn: &mut dyn Sysfn, mut on_access: F) -> Option<StopReason>
+    where
+        F: FnMut(MemAccess),
+    {
+        let mut cost = 0;
+        if let Some(fuel) = self.fuel {
+            // An instruction that cannot be fetched faults in `execute` below,
+            // so it costs nothing here.
+            if let Some(inst) = load_u32(&self.memory, self.state.pc) {
+                cost = self.costs.of(inst);
+            }
+            if cost > fuel {
+                return Some(StopReason::OutOfFuel);
+            }
+        }
+
+        let pc = self.state.pc;
+        let watchpoints = &self.watchpoints;
+        let mut hit = None;
+        let result = execute(&mut self.state, &mut self.memory, &self.memory_map, sysfn, |access| {
+            on_access(access);
+            if hit.is_some() {
+                return;
+            }
+            for (id, watchpoint) in watchpoints.iter().enumerate() {
+                if watchpoint.is_some_and(|w| w.matches(&access)) {
+                    hit = Some(WatchHit { id, pc, access });
+                    return;
+                }
+            }
+        });
+        if let Some(fuel) = &mut self.fuel {
+            if result.is_ok() {
+                *fuel -= cost;
+            }
+        }
+
+        match result {
+            Ok(None) => hit.map(StopReason::Watchpoint),
+            Ok(Some(status)) => Some(StopReason::Exited(status)),
+            Err(err) => Some(StopReason::Faulted(err)),
+        }
+    }
+}
+
+// Reads a little-endian word, ignoring alignment and memory protection.
+pub fn load_u32(memory: &[u8], addr: u32) -> Option<u32> {
+    let addr = addr as usize;
+    let bytes = memory.get(addr..addr.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// Runs with all of the memory readable, writable and executable.
 pub fn run(state: &mut State, memory: &mut [u8], sysfn: &mut dyn Sysfn) -> Result<u32, Error> {
-    let mut pc: u32 = state.pc;
+    run_with_map(state, memory, &MemoryMap::flat(), sysfn)
+}
+
+pub fn run_with_map(
+    state: &mut State,
+    memory: &mut [u8],
+    memory_map: &MemoryMap,
+    sysfn: &mut dyn Sysfn,
+) -> Result<u32, Error> {
+    loop {
+        if let Some(status) = execute(state, memory, memory_map, sysfn, |_| {})? {
+            return Ok(status);
+        }
+    }
+}
+
+// Executes a single instruction. On error the state is left untouched, so `pc`
+// points to the faulting instruction.
+#[inline(always)]
+fn execute<F>(
+    state: &mut State,
+    memory: &mut [u8],
+    memory_map: &MemoryMap,
+    sysfn: &mut dyn Sysfn,
+    mut on_access: F,
+) -> Result<Option<u32>, Error>
+where
+    F: FnMut(MemAccess),
+{
+    let pc = state.pc;
     let regs = &mut state.regs;
     let memory_len = memory.len();
     let memory_ptr = memory.as_mut_ptr();
 
-    macro_rules! vm_exit {
-        ($result:expr) => {{
-            state.pc = pc;
-            return $result;
-        }};
-    }
-
     macro_rules! load_int {
         ($int:ty, $addr:expr) => {{
             let addr = $addr as usize;
-            if addr.checked_add(size_of::<$int>() - 1).map_or(false, |upper| upper < memory_len) {
+            if addr.checked_add(size_of::<$int>() - 1).is_some_and(|upper| upper < memory_len) {
                 Some(<$int>::from_le_bytes(unsafe { *(memory_ptr.add(addr) as *const _) }))
             } else {
                 None
@@ -66,7 +617,7 @@ pub fn run(state: &mut State, memory: &mut [u8], sysfn: &mut dyn Sysfn) -> Resul
     macro_rules! store_int {
         ($value:expr, $addr:expr) => {{
             let (value, addr) = ($value, $addr as usize);
-            if addr.checked_add(size_of_val(&value) - 1).map_or(false, |upper| upper < memory_len) {
+            if addr.checked_add(size_of_val(&value) - 1).is_some_and(|upper| upper < memory_len) {
                 unsafe { *(memory_ptr.add(addr) as *mut _) = value.to_le_bytes() };
                 Some(value)
             } else {
@@ -75,203 +626,219 @@ pub fn run(state: &mut State, memory: &mut [u8], sysfn: &mut dyn Sysfn) -> Resul
         }};
     }
 
-    if pc % 4 != 0 {
-        vm_exit!(Err(Error::InvalidPc(pc)));
+    if pc & 3 != 0 {
+        return Err(Error::InvalidPc(pc));
     }
 
-    loop {
-        regs[0] = 0;
-        let inst = match load_int!(u32, pc) {
-            Some(inst) => inst,
-            None => vm_exit!(Err(Error::InvalidPc(pc))),
-        };
-        pc = u32::wrapping_add(pc, 4);
-
-        macro_rules! load_impl {
-            ($int:ty) => {{
-                let (rd, rb, off) = decode_rrc(inst);
-                let addr = u32::wrapping_add(regs[rb], off);
-                if let Some(value) = load_int!($int, addr) {
-                    regs[rd] = value as u32;
-                } else {
-                    vm_exit!(Err(Error::InvalidAddr(addr)));
-                }
-            }};
-        }
-
-        macro_rules! store_impl {
-            ($int:ty) => {{
-                let (rs, rb, off) = decode_rrc(inst);
-                let addr = u32::wrapping_add(regs[rb], off);
-                if store_int!(regs[rs] as $int, addr).is_none() {
-                    vm_exit!(Err(Error::InvalidAddr(addr)));
-                }
-            }};
-        }
+    regs[0] = 0;
+    let inst = match load_int!(u32, pc) {
+        Some(inst) => inst,
+        None => return Err(Error::InvalidPc(pc)),
+    };
+    if !memory_map.perms(pc, 4).contains(Perms::EXEC) {
+        return Err(Error::ExecProtected(pc));
+    }
+    let mut next_pc = u32::wrapping_add(pc, 4);
 
-        macro_rules! branch_impl {
-            ($cond:expr) => {{
-                let (rs1, rs2, off) = decode_rrc(inst);
-                if $cond(regs[rs1], regs[rs2]) {
-                    pc = u32::wrapping_add(pc, off << 2);
+    macro_rules! load_impl {
+        ($int:ty) => {{
+            let (rd, rb, off) = decode_rrc(inst);
+            let addr = u32::wrapping_add(regs[rb], off);
+            if let Some(value) = load_int!($int, addr) {
+                let width = size_of::<$int>() as u32;
+                if !memory_map.perms(addr, width).contains(Perms::READ) {
+                    return Err(Error::ReadProtected(addr));
                 }
-            }};
-        }
-
-        macro_rules! binop_imm_impl {
-            ($op:expr) => {{
-                let (rd, rs, imm) = decode_rrc(inst);
-                regs[rd] = $op(regs[rs], imm) as u32;
-            }};
-        }
-
-        macro_rules! binop_impl {
-            ($op:expr) => {{
-                let (rd, rs1, rs2) = decode_rrr(inst);
-                regs[rd] = $op(regs[rs1], regs[rs2]) as u32;
-            }};
-        }
-
-        match inst & 0xFF {
-            opcode::STU8  => store_impl!(u8),
-            opcode::STU16 => store_impl!(u16),
-            opcode::ST    => store_impl!(u32),
-
-            opcode::LDS8  => load_impl!(i8),
-            opcode::LDU8  => load_impl!(u8),
-            opcode::LDS16 => load_impl!(i16),
-            opcode::LDU16 => load_impl!(u16),
-            opcode::LD    => load_impl!(i32),
-
-            opcode::BEQ   => branch_impl!(|x, y| x == y),
-            opcode::BNE   => branch_impl!(|x, y| x != y),
-            opcode::BLT   => branch_impl!(|x, y| (x as i32) <  (y as i32)),
-            opcode::BGE   => branch_impl!(|x, y| (x as i32) >= (y as i32)),
-            opcode::BLTU  => branch_impl!(|x, y| x <  y),
-            opcode::BGEU  => branch_impl!(|x, y| x >= y),
-
-            opcode::ADDI  => binop_imm_impl!(u32::wrapping_add),
-            opcode::RSUBI => binop_imm_impl!(|x, y| u32::wrapping_sub(y, x)),
-            opcode::MULI  => binop_imm_impl!(u32::wrapping_mul),
-            opcode::ANDI  => binop_imm_impl!(|x, y| x & y),
-            opcode::ORI   => binop_imm_impl!(|x, y| x | y),
-            opcode::XORI  => binop_imm_impl!(|x, y| x ^ y),
-            opcode::SHLI  => binop_imm_impl!(|x, y| x << (y & 0x1F)),
-            opcode::LSHRI => binop_imm_impl!(|x, y| x >> (y & 0x1F)),
-            opcode::ASHRI => binop_imm_impl!(|x, y| (x as i32) >> (y & 0x1F)),
-
-            opcode::ADD   => binop_impl!(u32::wrapping_add),
-            opcode::SUB   => binop_impl!(u32::wrapping_sub),
-            opcode::MUL   => binop_impl!(u32::wrapping_mul),
-            opcode::AND   => binop_impl!(|x, y| x & y),
-            opcode::OR    => binop_impl!(|x, y| x | y),
-            opcode::XOR   => binop_impl!(|x, y| x ^ y),
-            opcode::SHL   => binop_impl!(|x, y| x << (y & 0x1F)),
-            opcode::LSHR  => binop_impl!(|x, y| x >> (y & 0x1F)),
-            opcode::ASHR  => binop_impl!(|x, y| (x as i32) >> (y & 0x1F)),
-
-            opcode::JAL => {
-                let (rd, off) = decode_rc(inst);
-                regs[rd] = pc;
-                pc = u32::wrapping_add(pc, off << 2);
-            }
-            opcode::JALR => {
-                let (rd, rs, off) = decode_rrc(inst);
-                let new_pc = u32::wrapping_add(regs[rs], off) & !3;
-                regs[rd] = pc;
-                pc = new_pc;
+                let raw = truncate(value as u32, width);
+                on_access(MemAccess { addr, width, write: false, old: raw, new: raw });
+                regs[rd] = value as u32;
+            } else {
+                return Err(Error::InvalidAddr(addr));
             }
+        }};
+    }
 
-            opcode::LI => {
-                let (rd, imm) = decode_rc(inst);
-                regs[rd] = imm;
+    macro_rules! store_impl {
+        ($int:ty) => {{
+            let (rs, rb, off) = decode_rrc(inst);
+            let addr = u32::wrapping_add(regs[rb], off);
+            let width = size_of::<$int>() as u32;
+            let old = load_int!($int, addr);
+            if old.is_some() && !memory_map.perms(addr, width).contains(Perms::WRITE) {
+                return Err(Error::WriteProtected(addr));
             }
-            opcode::LUI => {
-                let (rd, imm) = decode_rc(inst);
-                regs[rd] = imm << 12;
+            if let Some(new) = store_int!(regs[rs] as $int, addr) {
+                let old = old.unwrap() as u32;
+                on_access(MemAccess { addr, width, write: true, old, new: new as u32 });
+            } else {
+                return Err(Error::InvalidAddr(addr));
             }
+        }};
+    }
 
-            opcode::MULW => {
-                let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
-                let lhs = regs[rs1] as i32 as u64; // Sign-extension.
-                let rhs = regs[rs2] as i32 as u64; // Sign-extension.
-                let mul = u64::wrapping_mul(lhs, rhs);
-                regs[rd1] = mul as u32;
-                regs[rd2] = (mul >> 32) as u32;
-            }
-            opcode::MULWU => {
-                let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
-                let lhs = regs[rs1] as u64; // Zero-extension.
-                let rhs = regs[rs2] as u64; // Zero-extension.
-                let mul = u64::wrapping_mul(lhs, rhs);
-                regs[rd1] = mul as u32;
-                regs[rd2] = (mul >> 32) as u32;
-            }
-            opcode::DIV => {
-                let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
-                let lhs = regs[rs1] as i32;
-                let rhs = regs[rs2] as i32;
-                let (q, r) = match rhs {
-                    0 => (-1, lhs),
-                    -1 => (i32::wrapping_neg(lhs), 0),
-                    _ => (lhs / rhs, lhs % rhs),
-                };
-                regs[rd1] = q as u32;
-                regs[rd2] = r as u32;
-            }
-            opcode::DIVU => {
-                let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
-                let lhs = regs[rs1];
-                let rhs = regs[rs2];
-                let (q, r) = match rhs {
-                    0 => (!0, lhs),
-                    _ => (lhs / rhs, lhs % rhs),
-                };
-                regs[rd1] = q;
-                regs[rd2] = r;
+    macro_rules! branch_impl {
+        ($cond:expr) => {{
+            let (rs1, rs2, off) = decode_rrc(inst);
+            if $cond(regs[rs1], regs[rs2]) {
+                next_pc = u32::wrapping_add(next_pc, off << 2);
             }
+        }};
+    }
 
-            opcode::SYSFN => {
-                let (r, nr) = decode_rc(inst);
-                match nr {
-                    0 => vm_exit!(Ok(regs[r])),
-                    1 => regs[r] = sysfn.read(),
-                    2 => sysfn.write(regs[r]),
-                    _ => vm_exit!(Err(Error::UnknownSysfn(nr))),
-                }
+    macro_rules! binop_imm_impl {
+        ($op:expr) => {{
+            let (rd, rs, imm) = decode_rrc(inst);
+            regs[rd] = $op(regs[rs], imm) as u32;
+        }};
+    }
+
+    // Words that `Instruction::decode` rejects are not executed either.
+    macro_rules! check_reserved {
+        ($format:ident) => {
+            if inst & reserved_bits(Format::$format) != 0 {
+                return Err(Error::UnknownInst(inst));
             }
+        };
+    }
 
-            _ => vm_exit!(Err(Error::UnknownInst(inst))),
-        }
+    macro_rules! binop_impl {
+        ($op:expr) => {{
+            check_reserved!(Rrr);
+            let (rd, rs1, rs2) = decode_rrr(inst);
+            regs[rd] = $op(regs[rs1], regs[rs2]) as u32;
+        }};
     }
-}
 
-fn decode_rc(inst: u32) -> (usize, u32) {
-    let r1 = (inst >> 8) & 0xF;
-    let imm = (inst as i32) >> 12;
-    (r1 as usize, imm as u32)
-}
+    match inst & 0xFF {
+        opcode::STU8  => store_impl!(u8),
+        opcode::STU16 => store_impl!(u16),
+        opcode::ST    => store_impl!(u32),
 
-fn decode_rrc(inst: u32) -> (usize, usize, u32) {
-    let r1 = (inst >> 8) & 0xF;
-    let r2 = (inst >> 12) & 0xF;
-    let imm = (inst as i32) >> 16;
-    (r1 as usize, r2 as usize, imm as u32)
-}
+        opcode::LDS8  => load_impl!(i8),
+        opcode::LDU8  => load_impl!(u8),
+        opcode::LDS16 => load_impl!(i16),
+        opcode::LDU16 => load_impl!(u16),
+        opcode::LD    => load_impl!(i32),
+
+        opcode::BEQ   => branch_impl!(|x, y| x == y),
+        opcode::BNE   => branch_impl!(|x, y| x != y),
+        opcode::BLT   => branch_impl!(|x, y| (x as i32) <  (y as i32)),
+        opcode::BGE   => branch_impl!(|x, y| (x as i32) >= (y as i32)),
+        opcode::BLTU  => branch_impl!(|x, y| x <  y),
+        opcode::BGEU  => branch_impl!(|x, y| x >= y),
+
+        opcode::ADDI  => binop_imm_impl!(u32::wrapping_add),
+        opcode::RSUBI => binop_imm_impl!(|x, y| u32::wrapping_sub(y, x)),
+        opcode::MULI  => binop_imm_impl!(u32::wrapping_mul),
+        opcode::ANDI  => binop_imm_impl!(|x, y| x & y),
+        opcode::ORI   => binop_imm_impl!(|x, y| x | y),
+        opcode::XORI  => binop_imm_impl!(|x, y| x ^ y),
+        opcode::SHLI  => binop_imm_impl!(|x, y| x << (y & 0x1F)),
+        opcode::LSHRI => binop_imm_impl!(|x, y| x >> (y & 0x1F)),
+        opcode::ASHRI => binop_imm_impl!(|x, y| (x as i32) >> (y & 0x1F)),
 
-fn decode_rrr(inst: u32) -> (usize, usize, usize) {
-    let r1 = (inst >> 8) & 0xF;
-    let r2 = (inst >> 12) & 0xF;
-    let r3 = (inst >> 16) & 0xF;
-    (r1 as usize, r2 as usize, r3 as usize)
+        opcode::ADD   => binop_impl!(u32::wrapping_add),
+        opcode::SUB   => binop_impl!(u32::wrapping_sub),
+        opcode::MUL   => binop_impl!(u32::wrapping_mul),
+        opcode::AND   => binop_impl!(|x, y| x & y),
+        opcode::OR    => binop_impl!(|x, y| x | y),
+        opcode::XOR   => binop_impl!(|x, y| x ^ y),
+        opcode::SHL   => binop_impl!(|x, y| x << (y & 0x1F)),
+        opcode::LSHR  => binop_impl!(|x, y| x >> (y & 0x1F)),
+        opcode::ASHR  => binop_impl!(|x, y| (x as i32) >> (y & 0x1F)),
+
+        opcode::JAL => {
+            let (rd, off) = decode_rc(inst);
+            regs[rd] = next_pc;
+            next_pc = u32::wrapping_add(next_pc, off << 2);
+        }
+        opcode::JALR => {
+            let (rd, rs, off) = decode_rrc(inst);
+            let new_pc = u32::wrapping_add(regs[rs], off) & !3;
+            regs[rd] = next_pc;
+            next_pc = new_pc;
+        }
+
+        opcode::LI => {
+            let (rd, imm) = decode_rc(inst);
+            regs[rd] = imm;
+        }
+        opcode::LUI => {
+            let (rd, imm) = decode_rc(inst);
+            regs[rd] = imm << 12;
+        }
+
+        opcode::MULW => {
+            check_reserved!(Rrrr);
+            let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
+            let lhs = regs[rs1] as i32 as u64; // Sign-extension.
+            let rhs = regs[rs2] as i32 as u64; // Sign-extension.
+            let mul = u64::wrapping_mul(lhs, rhs);
+            regs[rd1] = mul as u32;
+            regs[rd2] = (mul >> 32) as u32;
+        }
+        opcode::MULWU => {
+            check_reserved!(Rrrr);
+            let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
+            let lhs = regs[rs1] as u64; // Zero-extension.
+            let rhs = regs[rs2] as u64; // Zero-extension.
+            let mul = u64::wrapping_mul(lhs, rhs);
+            regs[rd1] = mul as u32;
+            regs[rd2] = (mul >> 32) as u32;
+        }
+        opcode::DIV => {
+            check_reserved!(Rrrr);
+            let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
+            let lhs = regs[rs1] as i32;
+            let rhs = regs[rs2] as i32;
+            let (q, r) = match rhs {
+                0 => (-1, lhs),
+                -1 => (i32::wrapping_neg(lhs), 0),
+                _ => (lhs / rhs, lhs % rhs),
+            };
+            regs[rd1] = q as u32;
+            regs[rd2] = r as u32;
+        }
+        opcode::DIVU => {
+            check_reserved!(Rrrr);
+            let (rd1, rd2, rs1, rs2) = decode_rrrr(inst);
+            let lhs = regs[rs1];
+            let rhs = regs[rs2];
+            let (q, r) = match rhs {
+                0 => (!0, lhs),
+                _ => (lhs / rhs, lhs % rhs),
+            };
+            regs[rd1] = q;
+            regs[rd2] = r;
+        }
+
+        opcode::SYSFN => {
+            let (r, nr) = decode_rc(inst);
+            match nr {
+                0 => {
+                    state.pc = next_pc;
+                    return Ok(Some(regs[r]));
+                }
+                1 => regs[r] = sysfn.read(),
+                2 => sysfn.write(regs[r]),
+                _ => return Err(Error::UnknownSysfn(nr)),
+            }
+        }
+
+        _ => return Err(Error::UnknownInst(inst)),
+    }
+
+    regs[0] = 0;
+    state.pc = next_pc;
+    Ok(None)
 }
 
-fn decode_rrrr(inst: u32) -> (usize, usize, usize, usize) {
-    let r1 = (inst >> 8) & 0xF;
-    let r2 = (inst >> 12) & 0xF;
-    let r3 = (inst >> 16) & 0xF;
-    let r4 = (inst >> 20) & 0xF;
-    (r1 as usize, r2 as usize, r3 as usize, r4 as usize)
+fn truncate(value: u32, width: u32) -> u32 {
+    match width {
+        1 => value & 0xFF,
+        2 => value & 0xFFFF,
+        _ => value,
+    }
 }
 
 impl fmt::Display for State {
@@ -317,8 +884,285 @@ impl fmt::Display for Error {
                     write!(f, "invalid program counter 0x{:X}", pc)
                 }
             }
+            ExecProtected(pc) => {
+                write!(f, "non-executable program counter 0x{:X}", pc)
+            }
+            ReadProtected(addr) => {
+                write!(f, "read from protected address 0x{:X}", addr)
+            }
+            WriteProtected(addr) => {
+                write!(f, "write to read-only address 0x{:X}", addr)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [(Perms::READ, 'r'), (Perms::WRITE, 'w'), (Perms::EXEC, 'x')];
+        for (perm, c) in flags {
+            f.write_char(if self.contains(perm) { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use StopReason::*;
+        match self {
+            Exited(status) => write!(f, "exited with status {}", status),
+            Faulted(err) => err.fmt(f),
+            Stepped => f.write_str("stepped"),
+            BudgetExhausted => f.write_str("instruction budget exhausted"),
+            OutOfFuel => f.write_str("out of fuel"),
+            Breakpoint(pc) => write!(f, "breakpoint at 0x{:X}", pc),
+            Condition => f.write_str("condition reached"),
+            Watchpoint(hit) => {
+                write!(f, "watchpoint {} at 0x{:X}: ", hit.id, hit.pc)?;
+                if hit.access.write {
+                    write!(
+                        f,
+                        "[0x{:X}] 0x{:X} -> 0x{:X}",
+                        hit.access.addr, hit.access.old, hit.access.new,
+                    )
+                } else {
+                    write!(f, "[0x{:X}] read 0x{:X}", hit.access.addr, hit.access.new)
+                }
+            }
         }
     }
 }
 
 impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{load, load_code, NoDevice};
+
+    // Counts %a0 up to 3 and exits with it.
+    const COUNT: &[u32] = &[
+        0x0000_0388, // 0x1000: addi    %a0, %zero, 0
+        0x0001_3388, // 0x1004: addi    %a0, %a0, 1
+        0x0003_0488, // 0x1008: addi    %a1, %zero, 3
+        0xFFFD_43A3, // 0x100C: bne     %a0, %a1, 0x1004
+        0x0000_0383, // 0x1010: sysfn   %a0, 0
+    ];
+
+    #[test]
+    fn step_and_run_for() {
+        let mut machine = load_code(COUNT);
+        assert!(matches!(machine.step(&mut NoDevice), StopReason::Stepped));
+        assert_eq!(machine.state.pc, 0x1004);
+        assert!(matches!(machine.run_for(2, &mut NoDevice), StopReason::BudgetExhausted));
+        assert_eq!(machine.state.pc, 0x100C);
+        assert!(matches!(machine.run_for(100, &mut NoDevice), StopReason::Exited(3)));
+    }
+
+    #[test]
+    fn run_until_condition() {
+        let mut machine = load_code(COUNT);
+        let reason = machine.run_until(|state| state.regs[3] == 2, &mut NoDevice);
+        assert!(matches!(reason, StopReason::Condition));
+        assert_eq!(machine.state.pc, 0x1008);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(3)));
+    }
+
+    #[test]
+    fn fault_leaves_pc_at_instruction() {
+        let mut machine = load_code(&[
+            0x0001_0388, // 0x1000: addi    %a0, %zero, 1
+            0x0000_00FF, // 0x1004: an unknown opcode
+        ]);
+        match machine.run(&mut NoDevice) {
+            StopReason::Faulted(Error::UnknownInst(0xFF)) => {}
+            reason => panic!("unexpected stop: {}", reason),
+        }
+        assert_eq!(machine.state.pc, 0x1004);
+        assert_eq!(machine.state.regs[3], 1);
+    }
+
+    #[test]
+    fn fuel() {
+        let mut machine = load_code(COUNT);
+        machine.set_fuel(Some(2));
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::OutOfFuel));
+        assert_eq!(machine.state.pc, 0x1008);
+        assert_eq!(machine.fuel(), Some(0));
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::OutOfFuel));
+        assert_eq!(machine.state.pc, 0x1008);
+
+        machine.add_fuel(100);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(3)));
+        // The program executes 11 instructions: 3 per iteration and 2 outside
+        // the loop.
+        assert_eq!(machine.fuel(), Some(102 - 11));
+    }
+
+    #[test]
+    fn fuel_stops_before_expensive_instruction() {
+        let mut machine = load_code(COUNT);
+        machine.set_costs(Costs { branch: 10, ..Costs::UNIFORM });
+        machine.set_fuel(Some(12));
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::OutOfFuel));
+        assert_eq!(machine.state.pc, 0x100C);
+        assert_eq!(machine.fuel(), Some(9));
+
+        machine.set_fuel(None);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(3)));
+    }
+
+    #[test]
+    fn failed_instruction_costs_nothing() {
+        let mut machine = load_code(&[0xFF]);
+        machine.set_fuel(Some(5));
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Faulted(_)));
+        assert_eq!(machine.fuel(), Some(5));
+    }
+
+    #[test]
+    fn breakpoint_at_entry() {
+        let mut machine = load_code(COUNT);
+        machine.add_breakpoint(0x1000);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Breakpoint(0x1000)));
+        assert_eq!(machine.state.regs[3], 0);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(3)));
+    }
+
+    #[test]
+    fn breakpoint_in_loop() {
+        let mut machine = load_code(COUNT);
+        machine.add_breakpoint(0x100C);
+        for count in 1..=3 {
+            assert!(matches!(machine.run(&mut NoDevice), StopReason::Breakpoint(0x100C)));
+            assert_eq!(machine.state.regs[3], count);
+        }
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(3)));
+
+        let mut machine = load_code(COUNT);
+        machine.add_breakpoint(0x1008);
+        let reason = machine.run_until(|state| state.pc == 0x1010, &mut NoDevice);
+        assert!(matches!(reason, StopReason::Breakpoint(0x1008)));
+        assert!(machine.remove_breakpoint(0x1008));
+        let reason = machine.run_until(|state| state.pc == 0x1010, &mut NoDevice);
+        assert!(matches!(reason, StopReason::Condition));
+    }
+
+    #[test]
+    fn step_over_breakpoint() {
+        let mut machine = load_code(COUNT);
+        machine.add_breakpoint(0x1004);
+        assert!(matches!(machine.step(&mut NoDevice), StopReason::Breakpoint(0x1004)));
+        assert!(matches!(machine.step(&mut NoDevice), StopReason::Stepped));
+        assert_eq!(machine.state.pc, 0x1008);
+    }
+
+    // Stores 7 to 0x100 and exits with the value loaded back.
+    const STORE_LOAD: &[u32] = &[
+        0x0007_0388, // 0x1000: addi    %a0, %zero, 7
+        0x0100_0386, // 0x1004: st      %a0, %zero, 0x100
+        0x0100_049A, // 0x1008: ld      %a1, %zero, 0x100
+        0x0000_0483, // 0x100C: sysfn   %a1, 0
+    ];
+
+    #[test]
+    fn write_watchpoint() {
+        let mut machine = load_code(STORE_LOAD);
+        let id = machine.add_watchpoint(Watchpoint { addr: 0x100, len: 4, kind: WatchKind::Write });
+        let StopReason::Watchpoint(hit) = machine.run(&mut NoDevice) else {
+            panic!("watchpoint not hit");
+        };
+        assert_eq!((hit.id, hit.pc), (id, 0x1004));
+        assert!(hit.access.write);
+        assert_eq!((hit.access.addr, hit.access.old, hit.access.new), (0x100, 0, 7));
+        // The watched instruction has completed.
+        assert_eq!(machine.state.pc, 0x1008);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(7)));
+    }
+
+    #[test]
+    fn read_watchpoint() {
+        let mut machine = load_code(STORE_LOAD);
+        machine.add_watchpoint(Watchpoint { addr: 0x100, len: 4, kind: WatchKind::Read });
+        let StopReason::Watchpoint(hit) = machine.run(&mut NoDevice) else {
+            panic!("watchpoint not hit");
+        };
+        assert_eq!(hit.pc, 0x1008);
+        assert!(!hit.access.write);
+        assert_eq!(hit.access.new, 7);
+    }
+
+    #[test]
+    fn watchpoint_overlap() {
+        let mut machine = load_code(STORE_LOAD);
+        machine.add_watchpoint(Watchpoint { addr: 0x104, len: 4, kind: WatchKind::Access });
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(7)));
+
+        let mut machine = load_code(STORE_LOAD);
+        machine.add_watchpoint(Watchpoint { addr: 0x103, len: 1, kind: WatchKind::Access });
+        let StopReason::Watchpoint(hit) = machine.run(&mut NoDevice) else {
+            panic!("watchpoint not hit");
+        };
+        assert_eq!(hit.pc, 0x1004);
+    }
+
+    #[test]
+    fn remove_watchpoint() {
+        let mut machine = load_code(STORE_LOAD);
+        let first = machine.add_watchpoint(Watchpoint { addr: 0x100, len: 4, kind: WatchKind::Write });
+        let second = machine.add_watchpoint(Watchpoint { addr: 0x200, len: 4, kind: WatchKind::Write });
+        assert!(machine.remove_watchpoint(first).is_some());
+        assert!(machine.remove_watchpoint(first).is_none());
+        assert_eq!(machine.watchpoints().map(|(id, _)| id).collect::<Vec<_>>(), [second]);
+        assert!(matches!(machine.run(&mut NoDevice), StopReason::Exited(7)));
+        // Ids of removed watchpoints are reused.
+        assert_eq!(machine.add_watchpoint(Watchpoint { addr: 0, len: 1, kind: WatchKind::Read }), first);
+    }
+
+    #[test]
+    fn memory_protection() {
+        let source = "
+    mem     0x2000
+    seg     0x1000, \"r-x\"
+    ld      %a0, %zero, value   ; 0x1000
+    st      %a0, %zero, value   ; 0x1004
+
+    seg     0x100, \"r\"
+value:
+    d32     5
+";
+        let mut machine = load(source);
+        match machine.run(&mut NoDevice) {
+            StopReason::Faulted(Error::WriteProtected(0x100)) => {}
+            reason => panic!("unexpected stop: {}", reason),
+        }
+        assert_eq!(machine.state.pc, 0x1004);
+        assert_eq!(machine.state.regs[3], 5);
+
+        // Memory outside of the segments is not executable.
+        machine.state.pc = 0x1800;
+        match machine.run(&mut NoDevice) {
+            StopReason::Faulted(Error::ExecProtected(0x1800)) => {}
+            reason => panic!("unexpected stop: {}", reason),
+        }
+    }
+
+    #[test]
+    fn memory_map_regions() {
+        let regions = [
+            Region { addr: 0x1000, size: 0x1000, perms: Perms::READ },
+            Region { addr: 0x1800, size: 0x100, perms: Perms::ALL },
+        ];
+        let map = MemoryMap::new(regions, Perms::NONE);
+        let regions: Vec<_> = map.regions().iter().map(|r| (r.addr, r.size, r.perms)).collect();
+        assert_eq!(
+            regions,
+            [(0x1000, 0x800, Perms::READ), (0x1800, 0x100, Perms::ALL), (0x1900, 0x700, Perms::READ)],
+        );
+        assert_eq!(map.perms(0x17FE, 4), Perms::READ);
+        assert_eq!(map.perms(0x1800, 4), Perms::ALL);
+        assert_eq!(map.perms(0xFFE, 4), Perms::NONE);
+        assert_eq!(MemoryMap::flat().perms(0, 4), Perms::ALL);
+    }
+}